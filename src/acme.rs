@@ -0,0 +1,612 @@
+//! Ordering and renewal of TLS certificates using ACME.
+//!
+//! A certificate is ordered for every service host as soon as it appears, and renewed
+//! when it is about to expire. The acme lib is blocking, so each order runs on a
+//! thread of its own. Failed orders are retried with an exponential backoff per host.
+//!
+//! Hosts are validated with http-01 challenges. Wildcard certificates can only be
+//! validated with dns-01, which publishes a TXT record served by the `dns` module.
+use crate::persist::Persist;
+use crate::service::Services;
+use crate::tls::TlsCert;
+use crate::util::current_time_millis;
+use crate::{AcmeConfig, LoadBalancer, LolbError, LolbResult};
+use acme_lib::{create_p384_key, Account, Certificate, Directory, DirectoryUrl};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio_timer::Interval;
+
+/// Renew certificates when they have less than this many days left.
+const RENEW_DAYS: i64 = 30;
+
+/// Path prefix of http-01 challenges.
+pub(crate) const PATH_ACME_CHALLENGE: &str = "/.well-known/acme-challenge/";
+
+/// Name prefix of dns-01 challenge TXT records.
+pub(crate) const DNS_ACME_CHALLENGE: &str = "_acme-challenge.";
+
+/// Millis between polls of the ACME api when waiting for validations and certificates.
+const POLL_MILLIS: u64 = 5000;
+
+/// Backoff before ordering again for a host after a failed order. Doubled for every
+/// failed order in a row.
+const ORDER_BACKOFF_START: Duration = Duration::from_secs(5 * 60);
+/// Max backoff between orders for a host.
+const ORDER_BACKOFF_MAX: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Make ACME challenges available to the ACME server.
+pub trait ChallengeSink: Send + Sync {
+    /// Serve `proof` on `/.well-known/acme-challenge/<token>`.
+    fn publish(&self, token: &str, proof: &str);
+    /// Stop serving the token.
+    fn unpublish(&self, token: &str);
+    /// Serve a TXT record `value` for `name`, which is `_acme-challenge.example.com`.
+    /// There can be several values for the same name.
+    fn publish_dns(&self, name: &str, value: &str);
+    /// Stop serving the TXT record value.
+    fn unpublish_dns(&self, name: &str, value: &str);
+}
+
+/// Challenges served by the load balancer itself, before any routing of requests,
+/// and by the embedded dns server.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChallengeStore {
+    http: Arc<Mutex<HashMap<String, String>>>,
+    dns: Arc<Mutex<HashMap<String, Vec<String>>>>,
+}
+
+impl ChallengeStore {
+    /// The proof for a token, if it is published.
+    pub fn proof(&self, token: &str) -> Option<String> {
+        self.http.lock().unwrap().get(token).cloned()
+    }
+    /// The proof if the path is a http-01 challenge with a published token.
+    pub fn http_proof(&self, path: &str) -> Option<String> {
+        challenge_token(path).and_then(|token| self.proof(token))
+    }
+    /// The TXT record values published for a name.
+    pub fn txt(&self, name: &str) -> Vec<String> {
+        let name = name.to_ascii_lowercase();
+        self.dns
+            .lock()
+            .unwrap()
+            .get(&name)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl ChallengeSink for ChallengeStore {
+    fn publish(&self, token: &str, proof: &str) {
+        self.http
+            .lock()
+            .unwrap()
+            .insert(token.to_string(), proof.to_string());
+    }
+    fn unpublish(&self, token: &str) {
+        self.http.lock().unwrap().remove(token);
+    }
+    fn publish_dns(&self, name: &str, value: &str) {
+        self.dns
+            .lock()
+            .unwrap()
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(value.to_string());
+    }
+    fn unpublish_dns(&self, name: &str, value: &str) {
+        let mut lock = self.dns.lock().unwrap();
+        let name = name.to_ascii_lowercase();
+        if let Some(values) = lock.get_mut(&name) {
+            values.retain(|v| v != value);
+            if values.is_empty() {
+                lock.remove(&name);
+            }
+        }
+    }
+}
+
+/// The token if the path is a http-01 challenge.
+pub(crate) fn challenge_token(path: &str) -> Option<&str> {
+    path.strip_prefix(PATH_ACME_CHALLENGE)
+}
+
+/// The ACME account of the load balancer. It is created on first use, since that talks
+/// to the ACME server and blocks.
+pub(crate) struct AcmeAccount<P: Persist> {
+    config: AcmeConfig,
+    persist: P,
+    account: Mutex<Option<Account<P>>>,
+}
+
+impl<P: Persist> AcmeAccount<P> {
+    pub fn new(config: AcmeConfig, persist: P) -> Self {
+        AcmeAccount {
+            config,
+            persist,
+            account: Mutex::new(None),
+        }
+    }
+
+    /// The account, created with the ACME server if this is the first use. This blocks.
+    pub fn account(&self) -> LolbResult<Account<P>> {
+        let mut lock = self.account.lock().unwrap();
+        if let Some(account) = &*lock {
+            return Ok(account.clone());
+        }
+        let url = DirectoryUrl::Other(&self.config.url);
+        let directory = Directory::from_url(self.persist.clone(), url)?;
+        let account = directory.account(&self.config.contact)?;
+        *lock = Some(account.clone());
+        Ok(account)
+    }
+}
+
+/// Periodically check for certificates to order or renew. This future runs forever.
+pub async fn renew_certificates<P>(lb: Arc<Mutex<LoadBalancer<P>>>, check: Duration)
+where
+    P: Persist,
+    P: 'static,
+{
+    let mut interval = Interval::new_interval(check);
+    while interval.next().await.is_some() {
+        // failed orders are retried on a check after their backoff.
+        order_needed(&lb);
+    }
+}
+
+/// An order of a certificate. Blocks until the order is done.
+pub(crate) type Order = Box<dyn FnOnce() -> LolbResult<TlsCert> + Send>;
+
+/// Where certificates are ordered for, and delivered to once the orders are done. This
+/// is the load balancer, which orders using its ACME account.
+pub(crate) trait CertOrders: Send + Sync + 'static {
+    /// Start orders for the hosts needing a certificate.
+    fn start_orders(&self, now: u64) -> Vec<(String, Order)>;
+    /// Finish the order for the host with the ordered certificate, or the failure.
+    fn finish_order(&self, host: &str, cert: LolbResult<TlsCert>, now: u64);
+}
+
+impl<P: Persist + 'static> CertOrders for Mutex<LoadBalancer<P>> {
+    fn start_orders(&self, now: u64) -> Vec<(String, Order)> {
+        let mut lock = self.lock().unwrap();
+        let lock = &mut *lock;
+        let hosts = lock.acme_orders.start_needed(&lock.services, now);
+        hosts
+            .into_iter()
+            .map(|host| {
+                let account = lock.account.clone();
+                let challenges = lock.challenges.clone();
+                let name = host.clone();
+                let order: Order = Box::new(move || {
+                    let account = account.account()?;
+                    order_cert(&account, &name, &*challenges).and_then(|c| TlsCert::from_acme(&c))
+                });
+                (host, order)
+            })
+            .collect()
+    }
+
+    fn finish_order(&self, host: &str, cert: LolbResult<TlsCert>, now: u64) {
+        let mut lock = self.lock().unwrap();
+        let lock = &mut *lock;
+        lock.acme_orders.finish(&mut lock.services, host, cert, now);
+    }
+}
+
+/// Certificate orders in progress, and the backoff of hosts whose orders failed.
+#[derive(Debug, Default)]
+pub(crate) struct Orders {
+    /// Hosts with an order in progress. There is only ever one order per host.
+    pending: HashSet<String>,
+    /// Number of failed orders in a row per host, and the unix time millis when
+    /// the next order can start.
+    failed: HashMap<String, (u32, u64)>,
+}
+
+impl Orders {
+    /// Start orders for the hosts needing a certificate that have no order in progress,
+    /// and that aren't backing off after a failed order. Wildcard domains are ordered
+    /// as `*.example.com`.
+    pub fn start_needed(&mut self, services: &Services, now: u64) -> Vec<String> {
+        let renew_at = now + RENEW_DAYS as u64 * 24 * 60 * 60 * 1000;
+        services
+            .hosts_needing_cert(renew_at)
+            .into_iter()
+            .filter(|host| self.start(host, now))
+            .collect()
+    }
+
+    fn start(&mut self, host: &str, now: u64) -> bool {
+        if let Some((_, next)) = self.failed.get(host) {
+            if now < *next {
+                return false;
+            }
+        }
+        self.pending.insert(host.to_string())
+    }
+
+    /// Finish the order for the host. The certificate is set in the services, and if
+    /// that fails, the host backs off from ordering again.
+    pub fn finish(
+        &mut self,
+        services: &mut Services,
+        host: &str,
+        cert: LolbResult<TlsCert>,
+        now: u64,
+    ) {
+        self.pending.remove(host);
+
+        let res = cert.and_then(|cert| {
+            if services.set_cert(host, cert) {
+                Ok(())
+            } else {
+                Err(LolbError::Message("Host is not serviced"))
+            }
+        });
+
+        match res {
+            Ok(_) => {
+                info!("Got certificate for: {}", host);
+                self.failed.remove(host);
+            }
+            Err(e) => {
+                let count = self.failed.get(host).map(|(c, _)| *c).unwrap_or(0) + 1;
+                let backoff = (ORDER_BACKOFF_START.as_millis() as u64)
+                    .saturating_mul(2_u64.saturating_pow(count - 1))
+                    .min(ORDER_BACKOFF_MAX.as_millis() as u64);
+                warn!(
+                    "Failed to order certificate for {} (retry in {}s): {}",
+                    host,
+                    backoff / 1000,
+                    e
+                );
+                self.failed.insert(host.to_string(), (count, now + backoff));
+            }
+        }
+    }
+
+    /// Tells if any order is in progress.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+}
+
+/// Finishes an order when dropped. An order that panics is finished as failed.
+struct PendingOrder<'a, L: CertOrders> {
+    lb: &'a L,
+    host: &'a str,
+    cert: Option<LolbResult<TlsCert>>,
+}
+
+impl<'a, L: CertOrders> Drop for PendingOrder<'a, L> {
+    fn drop(&mut self) {
+        let cert = self
+            .cert
+            .take()
+            .unwrap_or(Err(LolbError::Message("Certificate order panicked")));
+        self.lb.finish_order(self.host, cert, current_time_millis());
+    }
+}
+
+/// Start ordering certificates for hosts that have none, or that are about to expire.
+pub(crate) fn order_needed<L: CertOrders>(lb: &Arc<L>) {
+    for (host, order) in lb.start_orders(current_time_millis()) {
+        let lb = lb.clone();
+        thread::spawn(move || {
+            let mut pending = PendingOrder {
+                lb: &*lb,
+                host: &host,
+                cert: None,
+            };
+            info!("Ordering certificate for: {}", host);
+            pending.cert = Some(order());
+        });
+    }
+}
+
+/// Order a certificate for the host. A previously issued certificate in persistence is
+/// used if it isn't about to expire. This blocks until the order is done.
+///
+/// A wildcard host, `*.example.com`, is validated using dns-01, others using http-01.
+fn order_cert<P: Persist>(
+    account: &Account<P>,
+    host: &str,
+    challenges: &dyn ChallengeSink,
+) -> LolbResult<Certificate> {
+    if let Some(cert) = account.certificate(host)? {
+        if cert.valid_days_left() > RENEW_DAYS {
+            return Ok(cert);
+        }
+    }
+
+    let wildcard = host.starts_with("*.");
+    let mut order = account.new_order(host, &[])?;
+
+    let csr = loop {
+        if let Some(csr) = order.confirm_validations() {
+            break csr;
+        }
+
+        for auth in order.authorizations()? {
+            if !auth.need_challenge() {
+                continue;
+            }
+            let res = if wildcard {
+                // the authorization is for the domain without the `*.`
+                let name = format!("{}{}", DNS_ACME_CHALLENGE, auth.domain_name());
+                let chall = auth.dns_challenge();
+                let proof = chall.dns_proof();
+                challenges.publish_dns(&name, &proof);
+                let res = chall.validate(POLL_MILLIS);
+                challenges.unpublish_dns(&name, &proof);
+                res
+            } else {
+                let chall = auth.http_challenge();
+                let token = chall.http_token().to_string();
+                challenges.publish(&token, &chall.http_proof());
+                let res = chall.validate(POLL_MILLIS);
+                challenges.unpublish(&token);
+                res
+            };
+            res?;
+        }
+
+        order.refresh()?;
+    };
+
+    let cert = csr.finalize_pkey(create_p384_key(), POLL_MILLIS)?;
+
+    // this also saves the certificate to persistence.
+    Ok(cert.download_and_save_cert()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::persist::MemoryPersist;
+    use crate::serv_auth::Preauthed;
+    use crate::service::ServiceAuth;
+    use crate::{BalanceStrategy, DomainConfig};
+    use std::sync::Weak;
+
+    fn services(hosts: &[&str]) -> Services {
+        let mut services = Services::new(&[DomainConfig {
+            domain: "example.com".into(),
+            auth: ServiceAuth::PresharedKey("secret".into()),
+            wildcard: false,
+        }]);
+        for host in hosts {
+            let p = Preauthed::new(
+                "example.com",
+                host,
+                "/",
+                BalanceStrategy::default(),
+                false,
+                None,
+            );
+            services.add_preauthed(p, Weak::new()).unwrap();
+        }
+        services
+    }
+
+    const MINUTE: u64 = 60 * 1000;
+
+    /// Records the challenges published and unpublished.
+    #[derive(Default)]
+    struct SpySink(Mutex<Vec<String>>);
+
+    impl SpySink {
+        fn take(&self) -> Vec<String> {
+            self.0.lock().unwrap().drain(..).collect()
+        }
+        fn push(&self, event: String) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    impl ChallengeSink for SpySink {
+        fn publish(&self, token: &str, proof: &str) {
+            self.push(format!("publish {} {}", token, proof));
+        }
+        fn unpublish(&self, token: &str) {
+            self.push(format!("unpublish {}", token));
+        }
+        fn publish_dns(&self, name: &str, value: &str) {
+            self.push(format!("publish_dns {} {}", name, value));
+        }
+        fn unpublish_dns(&self, name: &str, value: &str) {
+            self.push(format!("unpublish_dns {} {}", name, value));
+        }
+    }
+
+    /// Order certificates from Pebble, https://github.com/letsencrypt/pebble, with the
+    /// directory url in `PEBBLE_URL`. Nothing answers the challenges, so Pebble must run
+    /// with `PEBBLE_VA_ALWAYS_VALID=1`, and its certificate must be trusted.
+    #[test]
+    #[ignore]
+    fn test_order_cert_pebble() {
+        let url = std::env::var("PEBBLE_URL").expect("PEBBLE_URL not set");
+        let config = AcmeConfig {
+            contact: "admin@example.com".into(),
+            url,
+        };
+        let account = AcmeAccount::new(config, MemoryPersist::new());
+        let account = account.account().unwrap();
+        let spy = SpySink::default();
+
+        let cert = order_cert(&account, "a.example.com", &spy).unwrap();
+        assert!(cert.valid_days_left() > RENEW_DAYS);
+        // the challenge is published while it is validated.
+        let events = spy.take();
+        assert_eq!(events.len(), 2, "{:?}", events);
+        let token = events[0].split(' ').nth(1).unwrap();
+        assert!(events[0].starts_with("publish "));
+        assert_eq!(events[1], format!("unpublish {}", token));
+
+        // the saved certificate is used while it isn't about to expire.
+        order_cert(&account, "a.example.com", &spy).unwrap();
+        assert!(spy.take().is_empty());
+
+        // wildcards are validated using dns-01 of the domain.
+        order_cert(&account, "*.example.com", &spy).unwrap();
+        let events = spy.take();
+        assert_eq!(events.len(), 2, "{:?}", events);
+        let name = "_acme-challenge.example.com";
+        assert!(events[0].starts_with(&format!("publish_dns {} ", name)));
+        assert_eq!(events[1], format!("un{}", events[0]));
+    }
+
+    #[test]
+    fn test_order_backoff() {
+        let mut services = services(&["a.example.com"]);
+        let mut orders = Orders::default();
+
+        let mut now = 0;
+        let mut retries = vec![];
+        for _ in 0..11 {
+            assert_eq!(orders.start_needed(&services, now), vec!["a.example.com"]);
+            // only one order at a time.
+            assert!(orders.start_needed(&services, now).is_empty());
+            let failed = Err(LolbError::Message("Order failed"));
+            orders.finish(&mut services, "a.example.com", failed, now);
+            assert!(!orders.is_pending());
+
+            let (_, next) = orders.failed["a.example.com"];
+            assert!(orders.start_needed(&services, next - 1).is_empty());
+            retries.push((next - now) / MINUTE);
+            now = next;
+        }
+        assert_eq!(
+            retries,
+            vec![5, 10, 20, 40, 80, 160, 320, 640, 1280, 1440, 1440]
+        );
+
+        // a successful order resets the backoff.
+        orders.start_needed(&services, now);
+        let cert = TlsCert::self_signed("a.example.com", Some(now + 100 * 24 * 60 * MINUTE));
+        orders.finish(&mut services, "a.example.com", Ok(cert), now);
+        assert!(orders.failed.is_empty());
+        assert!(orders.start_needed(&services, now).is_empty());
+    }
+
+    #[test]
+    fn test_order_not_serviced() {
+        let mut services = services(&["a.example.com"]);
+        let mut orders = Orders::default();
+        orders.start_needed(&services, 0);
+        // the host went away while ordering.
+        services.apply(&[]);
+        let cert = TlsCert::self_signed("a.example.com", None);
+        orders.finish(&mut services, "a.example.com", Ok(cert), 0);
+        assert_eq!(orders.failed["a.example.com"], (1, 5 * MINUTE));
+    }
+
+    /// Orders certificates without any ACME server. Orders for `a.example.com` succeed,
+    /// `b.example.com` fail and others panic.
+    struct StubOrders(Mutex<(Services, Orders)>);
+
+    impl CertOrders for StubOrders {
+        fn start_orders(&self, now: u64) -> Vec<(String, Order)> {
+            let mut lock = self.0.lock().unwrap();
+            let (services, orders) = &mut *lock;
+            let hosts = orders.start_needed(services, now);
+            hosts
+                .into_iter()
+                .map(|host| {
+                    let order: Order = match host.as_str() {
+                        "a.example.com" => {
+                            Box::new(|| Ok(TlsCert::self_signed("a.example.com", None)))
+                        }
+                        "b.example.com" => Box::new(|| Err(LolbError::Message("Order failed"))),
+                        _ => Box::new(|| panic!("Order panicked")),
+                    };
+                    (host, order)
+                })
+                .collect()
+        }
+
+        fn finish_order(&self, host: &str, cert: LolbResult<TlsCert>, now: u64) {
+            let mut lock = self.0.lock().unwrap();
+            let (services, orders) = &mut *lock;
+            orders.finish(services, host, cert, now);
+        }
+    }
+
+    #[test]
+    fn test_order_needed() {
+        let hosts = ["a.example.com", "b.example.com", "c.example.com"];
+        let stub = Arc::new(StubOrders(Mutex::new((
+            services(&hosts),
+            Orders::default(),
+        ))));
+
+        order_needed(&stub);
+        for _ in 0..500 {
+            if !stub.0.lock().unwrap().1.is_pending() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let lock = stub.0.lock().unwrap();
+        let (services, orders) = &*lock;
+        assert!(!orders.is_pending());
+        assert!(services.cert_for("a.example.com").is_some());
+        // the failed and the panicked orders back off.
+        assert_eq!(
+            services.hosts_needing_cert(0),
+            vec!["b.example.com", "c.example.com"]
+        );
+        assert_eq!(orders.failed.len(), 2);
+        drop(lock);
+
+        order_needed(&stub);
+        assert!(!stub.0.lock().unwrap().1.is_pending());
+    }
+
+    #[test]
+    fn test_challenge_token() {
+        assert_eq!(
+            challenge_token("/.well-known/acme-challenge/abc123"),
+            Some("abc123")
+        );
+        assert_eq!(challenge_token("/.well-known/other/abc123"), None);
+        assert_eq!(challenge_token("/"), None);
+    }
+
+    #[test]
+    fn test_store() {
+        let store = ChallengeStore::default();
+        let sink: Arc<dyn ChallengeSink> = Arc::new(store.clone());
+        sink.publish("abc", "abc.proof");
+        assert_eq!(store.proof("abc"), Some("abc.proof".to_string()));
+        assert_eq!(store.proof("def"), None);
+        assert_eq!(
+            store.http_proof("/.well-known/acme-challenge/abc"),
+            Some("abc.proof".to_string())
+        );
+        // unknown tokens are not challenges of ours.
+        assert_eq!(store.http_proof("/.well-known/acme-challenge/def"), None);
+        assert_eq!(store.http_proof("/abc"), None);
+        sink.unpublish("abc");
+        assert_eq!(store.proof("abc"), None);
+        assert_eq!(store.http_proof("/.well-known/acme-challenge/abc"), None);
+    }
+
+    #[test]
+    fn test_store_dns() {
+        let store = ChallengeStore::default();
+        let name = "_acme-challenge.example.com";
+        store.publish_dns(name, "one");
+        store.publish_dns("_ACME-challenge.example.com", "two");
+        assert_eq!(store.txt(name), vec!["one", "two"]);
+        store.unpublish_dns(name, "one");
+        assert_eq!(store.txt(name), vec!["two"]);
+        store.unpublish_dns(name, "two");
+        assert!(store.txt(name).is_empty());
+        assert!(store.dns.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Strategy for picking one of the live connections servicing a route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum BalanceStrategy {
+    /// Take turns between the connections.
+    #[default]
+    RoundRobin,
+    /// Pick a connection at random.
+    Random,
+    /// Pick the connection with the least number of requests in flight.
+    LeastInFlight,
+    /// Pick two connections at random and use the one with least requests in flight.
+    PowerOfTwo,
+}
+
+impl BalanceStrategy {
+    /// Create a new balancer instance for this strategy.
+    pub(crate) fn balancer(self) -> Box<dyn Balancer> {
+        match self {
+            BalanceStrategy::RoundRobin => Box::new(RoundRobin::default()),
+            BalanceStrategy::Random => Box::new(Random),
+            BalanceStrategy::LeastInFlight => Box::new(LeastInFlight),
+            BalanceStrategy::PowerOfTwo => Box::new(PowerOfTwo),
+        }
+    }
+}
+
+/// A balancer picks which connection to use for the next request on a route.
+///
+/// The balancer is given the number of requests currently in flight for each
+/// live connection and returns the index of the chosen one.
+pub(crate) trait Balancer: fmt::Debug + Send {
+    fn pick(&mut self, in_flight: &[usize]) -> Option<usize>;
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RoundRobin {
+    next: usize,
+}
+
+impl Balancer for RoundRobin {
+    fn pick(&mut self, in_flight: &[usize]) -> Option<usize> {
+        if in_flight.is_empty() {
+            return None;
+        }
+        // connections come and go, so the index must wrap on the current length.
+        let idx = self.next % in_flight.len();
+        self.next = (idx + 1) % in_flight.len();
+        Some(idx)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Random;
+
+impl Balancer for Random {
+    fn pick(&mut self, in_flight: &[usize]) -> Option<usize> {
+        if in_flight.is_empty() {
+            return None;
+        }
+        Some(rand::thread_rng().gen_range(0, in_flight.len()))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct LeastInFlight;
+
+impl Balancer for LeastInFlight {
+    fn pick(&mut self, in_flight: &[usize]) -> Option<usize> {
+        in_flight
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, n)| **n)
+            .map(|(idx, _)| idx)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct PowerOfTwo;
+
+impl Balancer for PowerOfTwo {
+    fn pick(&mut self, in_flight: &[usize]) -> Option<usize> {
+        match in_flight.len() {
+            0 => None,
+            1 => Some(0),
+            len => {
+                let mut rng = rand::thread_rng();
+                let a = rng.gen_range(0, len);
+                // second pick is among the remaining ones to never compare with itself.
+                let b = (a + rng.gen_range(1, len)) % len;
+                if in_flight[b] < in_flight[a] {
+                    Some(b)
+                } else {
+                    Some(a)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn distribution(strategy: BalanceStrategy, in_flight: &[usize], rounds: usize) -> Vec<usize> {
+        let mut balancer = strategy.balancer();
+        let mut counts = vec![0; in_flight.len()];
+        for _ in 0..rounds {
+            let idx = balancer.pick(in_flight).unwrap();
+            counts[idx] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_empty() {
+        for s in &[
+            BalanceStrategy::RoundRobin,
+            BalanceStrategy::Random,
+            BalanceStrategy::LeastInFlight,
+            BalanceStrategy::PowerOfTwo,
+        ] {
+            assert_eq!(s.balancer().pick(&[]), None);
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let counts = distribution(BalanceStrategy::RoundRobin, &[0, 0, 0, 0], 400);
+        assert_eq!(counts, vec![100, 100, 100, 100]);
+    }
+
+    #[test]
+    fn test_round_robin_shrinking() {
+        let mut balancer = RoundRobin::default();
+        assert_eq!(balancer.pick(&[0, 0, 0]), Some(0));
+        assert_eq!(balancer.pick(&[0, 0, 0]), Some(1));
+        assert_eq!(balancer.pick(&[0, 0, 0]), Some(2));
+        // one connection died
+        assert_eq!(balancer.pick(&[0, 0]), Some(0));
+        assert_eq!(balancer.pick(&[0, 0]), Some(1));
+    }
+
+    #[test]
+    fn test_random() {
+        let counts = distribution(BalanceStrategy::Random, &[0, 0, 0, 0], 4000);
+        // every connection should get a fair share.
+        assert!(counts.iter().all(|c| *c > 500), "{:?}", counts);
+    }
+
+    #[test]
+    fn test_least_in_flight() {
+        let counts = distribution(BalanceStrategy::LeastInFlight, &[3, 1, 0, 2], 100);
+        assert_eq!(counts, vec![0, 0, 100, 0]);
+    }
+
+    #[test]
+    fn test_power_of_two() {
+        let counts = distribution(BalanceStrategy::PowerOfTwo, &[5, 5, 0, 5], 3000);
+        // the idle connection is picked whenever it is one of the two candidates.
+        assert!(counts[2] > 1000, "{:?}", counts);
+        // the most loaded is still used when it meets an equally loaded one.
+        assert!(counts.iter().all(|c| *c > 0), "{:?}", counts);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::chunked::{ChunkedDecoder, ChunkedEncoder};
+use crate::chunked::{is_allowed_trailer, ChunkedDecoder, ChunkedEncoder};
 use crate::limit::{LimitRead, LimitWrite};
 use crate::peek::Peekable;
 use crate::Socket;
@@ -12,7 +12,7 @@ where
 {
     Http2(h2::RecvStream),
     Http11Plain(LimitRead<&'a mut Peekable<S>>),
-    Http11Chunked(ChunkedDecoder<&'a mut Peekable<S>>),
+    Http11Chunked(ChunkedDecoder<'a, S>),
 }
 
 impl<'a, S: Socket> RecvBody<'a, S> {
@@ -24,12 +24,63 @@ impl<'a, S: Socket> RecvBody<'a, S> {
         }
     }
 
+    /// Trailer fields sent after the body. Only call this once `data()` returned `None`.
+    pub async fn trailers(&mut self) -> LolbResult<Option<http::HeaderMap>> {
+        match self {
+            RecvBody::Http2(r) => Ok(r.trailers().await?),
+            RecvBody::Http11Plain(_) => Ok(None),
+            RecvBody::Http11Chunked(r) => Ok(r.trailers()),
+        }
+    }
+
     pub fn release_capacity(&mut self, amount: usize) -> LolbResult<()> {
         match self {
             RecvBody::Http2(r) => Ok(r.release_capacity().release_capacity(amount)?),
             _ => Ok(()),
         }
     }
+
+    /// Whether the entire body is read.
+    pub fn is_end(&self) -> bool {
+        match self {
+            RecvBody::Http2(r) => r.is_end_stream(),
+            RecvBody::Http11Plain(r) => r.is_end(),
+            RecvBody::Http11Chunked(r) => r.is_end(),
+        }
+    }
+
+    /// Read and discard the rest of the body. Gives `false` if there is more than `max`
+    /// bytes, in which case the body is left partly read.
+    pub async fn drain(&mut self, max: usize) -> LolbResult<bool> {
+        let mut total = 0;
+        while let Some(data) = self.data().await {
+            let len = data?.len();
+            self.release_capacity(len)?;
+            total += len;
+            if total > max {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// The trailers without fields that are not allowed in a trailer, or `None` if no
+/// field is left.
+pub(crate) fn allowed_trailers(trailers: http::HeaderMap) -> Option<http::HeaderMap> {
+    let mut allowed = http::HeaderMap::new();
+    for (name, value) in trailers.iter() {
+        if is_allowed_trailer(name) {
+            allowed.append(name.clone(), value.clone());
+        } else {
+            debug!("Drop forbidden trailer: {}", name);
+        }
+    }
+    if allowed.is_empty() {
+        None
+    } else {
+        Some(allowed)
+    }
 }
 
 async fn read_chunk<S: AsyncRead + Unpin>(s: &mut S) -> Option<LolbResult<Bytes>> {
@@ -79,6 +130,8 @@ where
     NoBody,
     Limited(LimitWrite<&'a mut S>),
     Chunked(ChunkedEncoder<&'a mut S>),
+    /// Body that ends when the connection closes, for http/1.0 clients.
+    Unlimited(&'a mut S),
 }
 
 impl<'a, S: Socket> Http11Body<'a, S> {
@@ -92,17 +145,34 @@ impl<'a, S: Socket> Http11Body<'a, S> {
             Chunked(w) => {
                 w.send_chunk(chunk).await?;
             }
+            Unlimited(w) => {
+                w.write_all(&chunk[..]).await?;
+            }
         }
         Ok(())
     }
-    pub(crate) async fn send_finish(&mut self) -> LolbResult<()> {
+    /// Finish the body. Trailers can only be sent when the body is chunked, and are
+    /// otherwise dropped.
+    pub(crate) async fn send_finish(
+        &mut self,
+        trailers: Option<&http::HeaderMap>,
+    ) -> LolbResult<()> {
         use Http11Body::*;
         match self {
-            NoBody => {}
-            Limited(_) => {}
             Chunked(w) => {
-                w.send_finish().await?;
+                w.send_finish(trailers).await?;
+                return Ok(());
             }
+            Limited(w) => {
+                // a short body would be taken as the start of the next response.
+                if !w.is_end() {
+                    return Err(LolbError::Message("Body shorter than content-length"));
+                }
+            }
+            NoBody | Unlimited(_) => {}
+        }
+        if trailers.is_some() {
+            debug!("Drop trailers of body that isn't chunked");
         }
         Ok(())
     }
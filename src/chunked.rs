@@ -1,22 +1,132 @@
-use crate::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use crate::{LolbError, LolbResult};
-use bytes::Bytes;
+//! Transfer-encoding chunked, RFC 7230 section 4.1.
+//!
+//! ```text
+//! chunked-body   = *chunk last-chunk trailer-part CRLF
+//! chunk          = chunk-size [ chunk-ext ] CRLF chunk-data CRLF
+//! last-chunk     = 1*("0") [ chunk-ext ] CRLF
+//! chunk-ext      = *( BWS ";" BWS chunk-ext-name [ BWS "=" BWS chunk-ext-val ] )
+//! trailer-part   = *( header-field CRLF )
+//! ```
+//!
+//! Chunk extensions are validated, but dropped, since http2 has nothing to carry them.
+//! Trailer fields are kept apart from the body, and fields that must not be sent as
+//! trailers, like `content-length`, are dropped.
+use crate::peek::Peekable;
+use crate::{AsyncReadExt, AsyncWrite, AsyncWriteExt, Socket};
+use crate::{LolbError, LolbResult, StatusKind};
+use bytes::{Bytes, BytesMut};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
 use std::io;
 
-/// Decode AsyncRead as transfer-encoding chunked.
-pub(crate) struct ChunkedDecoder<S: AsyncRead> {
-    socket: S,
-    amount_left: usize,
+/// Amount we max read from the underlying chunked stream. To not use up too much memory.
+const MAX_READ_SIZE: usize = 16_384;
+
+/// Max length of a chunk size line, including extensions, or of a trailer field.
+const MAX_LINE_LEN: usize = 4096;
+
+/// Max size of all trailer fields together.
+const MAX_TRAILER_SIZE: usize = 16_384;
+
+/// Max size of a single chunk.
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Max size of the entire decoded body.
+const MAX_BODY_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Fields that are not allowed in trailers, since they are needed before the body is
+/// processed. RFC 7230 section 4.1.2.
+const FORBIDDEN_TRAILERS: &[&str] = &[
+    // framing
+    "transfer-encoding",
+    "content-length",
+    "trailer",
+    // routing and connection
+    "host",
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "upgrade",
+    "te",
+    // request modifiers
+    "cache-control",
+    "expect",
+    "max-forwards",
+    "pragma",
+    "range",
+    "if-match",
+    "if-none-match",
+    "if-modified-since",
+    "if-unmodified-since",
+    "if-range",
+    // authentication
+    "authorization",
+    "proxy-authorization",
+    "proxy-authenticate",
+    "www-authenticate",
+    "set-cookie",
+    "cookie",
+    // response control
+    "age",
+    "date",
+    "expires",
+    "location",
+    "retry-after",
+    "vary",
+    "warning",
+    // payload processing
+    "content-encoding",
+    "content-type",
+    "content-range",
+];
+
+/// Tells if the field is allowed in a trailer.
+pub(crate) fn is_allowed_trailer(name: &HeaderName) -> bool {
+    !FORBIDDEN_TRAILERS.contains(&name.as_str())
 }
 
-/// Amount we max read from the underlying chunked stream. To not use up too much memory.
-const MAX_READ_SIZE: usize = 1024 * 1024;
+const BAD_CHUNK_SIZE: LolbError = LolbError::Status(StatusKind::BadRequest, "Bad chunk size");
+const BAD_CHUNK_END: LolbError =
+    LolbError::Status(StatusKind::BadRequest, "Chunk data not followed by CRLF");
+const BAD_LINE: LolbError = LolbError::Status(StatusKind::BadRequest, "Bad line in chunked body");
+const BAD_TRAILER: LolbError = LolbError::Status(StatusKind::BadRequest, "Bad trailer field");
+const TRAILER_TOO_BIG: LolbError = LolbError::Status(StatusKind::BadRequest, "Trailer too big");
+const CHUNK_TOO_BIG: LolbError = LolbError::Status(StatusKind::PayloadTooLarge, "Chunk too big");
+const BODY_TOO_BIG: LolbError =
+    LolbError::Status(StatusKind::PayloadTooLarge, "Chunked body too big");
 
-impl<S: AsyncRead + Unpin> ChunkedDecoder<S> {
-    pub fn new(socket: S) -> Self {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Next is a chunk size line.
+    Size,
+    /// Reading chunk data with this many bytes left.
+    Data(usize),
+    /// Next is the CRLF after chunk data.
+    DataEnd,
+    /// The last chunk and trailer are read.
+    Done,
+}
+
+/// Decode a socket as transfer-encoding chunked.
+///
+/// Lines are peeked, and only what belongs to the chunked body is consumed, which means
+/// the socket is positioned at the next request when `data()` returns `None`.
+pub(crate) struct ChunkedDecoder<'a, S: Socket> {
+    socket: &'a mut Peekable<S>,
+    state: State,
+    trailers: Option<HeaderMap>,
+    /// Sum of the chunk sizes so far.
+    body_size: usize,
+    max_body_size: usize,
+}
+
+impl<'a, S: Socket> ChunkedDecoder<'a, S> {
+    pub fn new(socket: &'a mut Peekable<S>) -> Self {
         ChunkedDecoder {
             socket,
-            amount_left: 0,
+            state: State::Size,
+            trailers: None,
+            body_size: 0,
+            max_body_size: MAX_BODY_SIZE,
         }
     }
 
@@ -28,85 +138,220 @@ impl<S: AsyncRead + Unpin> ChunkedDecoder<S> {
         }
     }
 
+    /// Tells if the entire chunked body, including trailer, has been read.
+    pub fn is_end(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Take the trailer fields. They are available once `data()` returned `None`.
+    pub fn trailers(&mut self) -> Option<HeaderMap> {
+        self.trailers.take()
+    }
+
     async fn data_res(&mut self) -> LolbResult<Option<Bytes>> {
-        if self.amount_left == 0 {
-            let chunk_size = self.read_chunk_size().await?;
-            if chunk_size == 0 {
-                return Ok(None);
+        loop {
+            match self.state {
+                State::Size => {
+                    let line = self.read_line(MAX_LINE_LEN).await?;
+                    let chunk_size = parse_chunk_size(&line).ok_or(BAD_CHUNK_SIZE)?;
+                    // limits are checked before reading any of the chunk data.
+                    if chunk_size > MAX_CHUNK_SIZE {
+                        return Err(CHUNK_TOO_BIG);
+                    }
+                    self.body_size += chunk_size;
+                    if self.body_size > self.max_body_size {
+                        return Err(BODY_TOO_BIG);
+                    }
+                    if chunk_size == 0 {
+                        let trailers = self.read_trailers().await?;
+                        if !trailers.is_empty() {
+                            self.trailers = Some(trailers);
+                        }
+                        self.state = State::Done;
+                    } else {
+                        self.state = State::Data(chunk_size);
+                    }
+                }
+                State::Data(left) => {
+                    let to_read = left.min(MAX_READ_SIZE);
+                    let mut buf = BytesMut::with_capacity(to_read);
+                    buf.resize(to_read, 0);
+                    let read = self.socket.read(&mut buf[..]).await?;
+                    if read == 0 {
+                        return Err(unexpected_eof());
+                    }
+                    buf.truncate(read);
+                    self.state = if read == left {
+                        State::DataEnd
+                    } else {
+                        State::Data(left - read)
+                    };
+                    return Ok(Some(buf.freeze()));
+                }
+                State::DataEnd => {
+                    let mut crlf = [0_u8; 2];
+                    self.socket.read_exact(&mut crlf).await?;
+                    if &crlf != b"\r\n" {
+                        return Err(BAD_CHUNK_END);
+                    }
+                    self.state = State::Size;
+                }
+                State::Done => return Ok(None),
             }
-            self.amount_left = chunk_size;
         }
-        let to_read = self.amount_left.min(MAX_READ_SIZE);
-        self.amount_left -= to_read;
-        let mut buf = Vec::with_capacity(to_read);
-        self.read_buf(to_read, &mut buf).await?;
-        self.skip_until_lf().await?; // skip trailing \r\n
-        Ok(Some(buf.into()))
     }
 
-    // 3\r\nhel\r\nb\r\nlo world!!!\r\n0\r\n\r\n
-    async fn read_chunk_size(&mut self) -> LolbResult<usize> {
-        let mut buf = Vec::with_capacity(16);
-
-        // read until we get a non-numeric character. this could be
-        // either \r or maybe a ; if we are using "extensions"
+    /// Read trailer fields until the empty line ending the chunked body.
+    async fn read_trailers(&mut self) -> LolbResult<HeaderMap> {
+        let mut trailers = HeaderMap::new();
+        let mut total = 0;
         loop {
-            self.read_buf(1, &mut buf).await?;
-            let c: char = buf[buf.len() - 1].into();
-            // keep reading until we get ; or \r
-            if !c.is_numeric() {
-                break;
+            let line = self.read_line(MAX_LINE_LEN).await?;
+            if line.is_empty() {
+                return Ok(trailers);
+            }
+            total += line.len();
+            if total > MAX_TRAILER_SIZE {
+                return Err(TRAILER_TOO_BIG);
+            }
+            let (name, value) = parse_trailer(&line).ok_or(BAD_TRAILER)?;
+            if is_allowed_trailer(&name) {
+                trailers.append(name, value);
+            } else {
+                debug!("Drop forbidden trailer: {}", name);
             }
         }
+    }
 
-        self.skip_until_lf().await?;
-
-        // no length, no number to parse.
-        if buf.is_empty() {
-            return Ok(0);
+    /// Read a line ending with CRLF. The CRLF is not included.
+    async fn read_line(&mut self, limit: usize) -> LolbResult<Vec<u8>> {
+        // +2 for the CRLF
+        let mut buf = vec![0_u8; limit + 2];
+        let amount = self.socket.peek(&mut buf, &|b| b.contains(&b'\n')).await?;
+        let lf = match buf[..amount].iter().position(|c| *c == b'\n') {
+            Some(lf) => lf,
+            None if amount < buf.len() => return Err(unexpected_eof()),
+            None => return Err(BAD_LINE),
+        };
+        self.socket.consume(lf + 1);
+        let mut line = buf;
+        line.truncate(lf);
+        // a bare LF is not a line ending, and CR only allowed before LF.
+        if line.pop() != Some(b'\r') || line.contains(&b'\r') {
+            return Err(BAD_LINE);
         }
+        Ok(line)
+    }
+}
 
-        // parse the read numbers as a chunk size.
-        let chunk_size = String::from_utf8(buf)
-            .ok()
-            .and_then(|c| usize::from_str_radix(c.trim(), 16).ok())
-            .ok_or_else(|| {
-                io::Error::new(io::ErrorKind::InvalidInput, "Not a number in chunk size")
-            })?;
+fn unexpected_eof() -> LolbError {
+    LolbError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Stream ended in chunked body",
+    ))
+}
 
-        Ok(chunk_size)
+/// Parse a chunk size line, without CRLF, into the size. Extensions are validated
+/// and dropped.
+fn parse_chunk_size(line: &[u8]) -> Option<usize> {
+    let hex_len = line.iter().take_while(|c| c.is_ascii_hexdigit()).count();
+    if hex_len == 0 {
+        return None;
     }
 
-    // skip until we get a \n
-    async fn skip_until_lf(&mut self) -> LolbResult<()> {
-        // skip until we get a \n
-        let mut one = [0_u8; 1];
-        loop {
-            self.read_amount(&mut one[..]).await?;
-            if one[0] == b'\n' {
-                break;
-            }
+    let mut size: usize = 0;
+    for c in &line[0..hex_len] {
+        let digit = (*c as char).to_digit(16)? as usize;
+        size = size.checked_mul(16)?.checked_add(digit)?;
+    }
+
+    // whitespace is only allowed around the `;` and `=` of extensions.
+    let mut rest = &line[hex_len..];
+    loop {
+        if rest.is_empty() {
+            return Some(size);
+        }
+        rest = skip_bws(rest);
+        if rest.first() != Some(&b';') {
+            return None;
+        }
+        rest = skip_bws(&rest[1..]);
+        rest = skip_token(rest)?;
+        let after = skip_bws(rest);
+        if after.first() == Some(&b'=') {
+            rest = skip_bws(&after[1..]);
+            rest = if rest.first() == Some(&b'"') {
+                skip_quoted_string(rest)?
+            } else {
+                skip_token(rest)?
+            };
         }
-        Ok(())
     }
+}
 
-    async fn read_buf(&mut self, amount: usize, buf: &mut Vec<u8>) -> LolbResult<()> {
-        buf.reserve(amount);
-        let len = buf.len();
-        buf.resize(len + amount, 0);
-        self.read_amount(&mut buf[len..len + amount]).await?;
-        Ok(())
+/// Parse a `name: value` trailer field. Obsolete line folding is not allowed.
+fn parse_trailer(line: &[u8]) -> Option<(HeaderName, HeaderValue)> {
+    let colon = line.iter().position(|c| *c == b':')?;
+    let name = &line[0..colon];
+    // this also rejects whitespace before the colon and obs-fold.
+    if name.is_empty() || !name.iter().all(|c| is_tchar(*c)) {
+        return None;
     }
+    let value = trim_ows(&line[colon + 1..]);
+    let name = HeaderName::from_bytes(name).ok()?;
+    let value = HeaderValue::from_bytes(value).ok()?;
+    Some((name, value))
+}
 
-    async fn read_amount(&mut self, buf: &mut [u8]) -> LolbResult<()> {
-        let read = self.socket.read_exact(buf).await?;
-        if read != buf.len() {
-            return Err(LolbError::Io(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Read amount less than expected {} < {}", read, buf.len()),
-            )));
+fn is_tchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+fn skip_bws(s: &[u8]) -> &[u8] {
+    let n = s.iter().take_while(|c| **c == b' ' || **c == b'\t').count();
+    &s[n..]
+}
+
+fn trim_ows(s: &[u8]) -> &[u8] {
+    let s = skip_bws(s);
+    let n = s
+        .iter()
+        .rev()
+        .take_while(|c| **c == b' ' || **c == b'\t')
+        .count();
+    &s[0..s.len() - n]
+}
+
+fn skip_token(s: &[u8]) -> Option<&[u8]> {
+    let n = s.iter().take_while(|c| is_tchar(**c)).count();
+    if n == 0 {
+        None
+    } else {
+        Some(&s[n..])
+    }
+}
+
+/// Skip a quoted string, starting with `"`.
+fn skip_quoted_string(s: &[u8]) -> Option<&[u8]> {
+    let mut i = 1;
+    while i < s.len() {
+        match s[i] {
+            b'"' => return Some(&s[i + 1..]),
+            // quoted-pair
+            b'\\' if i + 1 < s.len() && is_qtext_or_pair(s[i + 1], true) => i += 2,
+            c if is_qtext_or_pair(c, false) => i += 1,
+            _ => return None,
         }
-        Ok(())
+    }
+    None
+}
+
+fn is_qtext_or_pair(c: u8, pair: bool) -> bool {
+    match c {
+        b'\t' | b' ' => true,
+        b'"' | b'\\' => pair,
+        0x21..=0x7e | 0x80..=0xff => true,
+        _ => false,
     }
 }
 
@@ -115,16 +360,424 @@ pub(crate) struct ChunkedEncoder<S: AsyncWrite + Unpin>(pub S);
 
 impl<S: AsyncWrite + Unpin> ChunkedEncoder<S> {
     pub async fn send_chunk(&mut self, buf: Bytes) -> LolbResult<()> {
-        let header = format!("{}\r\n", buf.len()).into_bytes();
+        // an empty chunk would be taken as the last chunk.
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let header = format!("{:x}\r\n", buf.len()).into_bytes();
         self.0.write_all(&header[..]).await?;
         self.0.write_all(&buf[..]).await?;
         const CRLF: &[u8] = b"\r\n";
         self.0.write_all(CRLF).await?;
         Ok(())
     }
-    pub async fn send_finish(&mut self) -> LolbResult<()> {
-        const END: &[u8] = b"0\r\n\r\n";
-        self.0.write_all(END).await?;
+
+    /// Send the last chunk with the trailer fields that are allowed in a trailer.
+    pub async fn send_finish(&mut self, trailers: Option<&HeaderMap>) -> LolbResult<()> {
+        let mut end = b"0\r\n".to_vec();
+        for (name, value) in trailers.into_iter().flatten() {
+            if !is_allowed_trailer(name) {
+                debug!("Drop forbidden trailer: {}", name);
+                continue;
+            }
+            end.extend_from_slice(name.as_str().as_bytes());
+            end.extend_from_slice(b": ");
+            end.extend_from_slice(value.as_bytes());
+            end.extend_from_slice(b"\r\n");
+        }
+        end.extend_from_slice(b"\r\n");
+        self.0.write_all(&end[..]).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::AsyncRead;
+    use futures_executor::block_on;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::io::{Read, Write};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Reader giving out the input in random small pieces.
+    struct Trickle {
+        input: Vec<u8>,
+        pos: usize,
+        rng: StdRng,
+    }
+
+    impl Trickle {
+        fn new(input: &[u8], seed: u64) -> Self {
+            Trickle {
+                input: input.to_vec(),
+                pos: 0,
+                rng: StdRng::seed_from_u64(seed),
+            }
+        }
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let left = self.input.len() - self.pos;
+            let max = left.min(buf.len()).min(self.rng.gen_range(1, 64));
+            buf[0..max].copy_from_slice(&self.input[self.pos..self.pos + max]);
+            self.pos += max;
+            Ok(max)
+        }
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Read::read(self.get_mut(), buf))
+        }
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Write::write(self.get_mut(), buf))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Socket for Trickle {}
+
+    /// Decode the entire input. Gives the body, the trailers and what is left unread.
+    fn decode(input: &[u8]) -> LolbResult<(Vec<u8>, Option<HeaderMap>, Vec<u8>)> {
+        decode_with(Trickle::new(input, 42))
+    }
+
+    fn decode_with(reader: Trickle) -> LolbResult<(Vec<u8>, Option<HeaderMap>, Vec<u8>)> {
+        let mut socket = Peekable::new(reader);
+        let mut dec = ChunkedDecoder::new(&mut socket);
+        let mut body = vec![];
+        block_on(async {
+            while let Some(data) = dec.data().await {
+                body.extend_from_slice(&data?[..]);
+            }
+            Ok::<_, LolbError>(())
+        })?;
+        assert!(dec.is_end());
+        let trailers = dec.trailers();
+        // what is left includes anything peeked past the end of the body.
+        let mut rest = vec![];
+        block_on(AsyncReadExt::read_to_end(&mut socket, &mut rest))?;
+        Ok((body, trailers, rest))
+    }
+
+    fn is_bad_request(res: LolbResult<(Vec<u8>, Option<HeaderMap>, Vec<u8>)>) -> bool {
+        is_status(res, http::StatusCode::BAD_REQUEST)
+    }
+
+    fn is_status(
+        res: LolbResult<(Vec<u8>, Option<HeaderMap>, Vec<u8>)>,
+        status: http::StatusCode,
+    ) -> bool {
+        match res {
+            Err(e) => e.status() == Some(status),
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_hex_sizes() {
+        let input = b"3\r\nhel\r\nb\r\nlo world!!!\r\nA\r\n0123456789\r\n\
+            1F\r\n0123456789012345678901234567890\r\n000a\r\n0123456789\r\n0\r\n\r\nNEXT";
+        let (body, trailers, rest) = decode(input).unwrap();
+        let expected = [
+            "hello world!!!",
+            "0123456789",
+            "0123456789012345678901234567890",
+            "0123456789",
+        ];
+        assert_eq!(String::from_utf8(body).unwrap(), expected.concat());
+        assert!(trailers.is_none());
+        // the decoder must not read into the next request.
+        assert_eq!(&rest[..], b"NEXT");
+    }
+
+    #[test]
+    fn test_large_chunk() {
+        let data = vec![b'x'; 100_000];
+        let mut input = format!("{:x}\r\n", data.len()).into_bytes();
+        input.extend_from_slice(&data);
+        input.extend_from_slice(b"\r\n0\r\n\r\n");
+        let (body, _, _) = decode(&input).unwrap();
+        assert_eq!(body, data);
+    }
+
+    #[test]
+    fn test_extensions() {
+        let input = b"5;name=value;flag ; q = \"a;b\\\"c\"\r\nhello\r\n0;last\r\n\r\n";
+        let (body, _, _) = decode(input).unwrap();
+        assert_eq!(&body[..], b"hello");
+    }
+
+    #[test]
+    fn test_bad_size_lines() {
+        for line in &[
+            "",
+            "x",
+            "0x5",
+            "-1",
+            "+1",
+            " 5",
+            "5 x",
+            "5;",
+            "5;=v",
+            "5;n=",
+            "5;n=\"open",
+            "5;n=\"a\"b",
+            "5;n@=v",
+            "5,6",
+            "5\t",
+            "5;flag ",
+            "10000000000000000000",
+        ] {
+            assert_eq!(parse_chunk_size(line.as_bytes()), None, "{:?}", line);
+            let input = format!("{}\r\nhello\r\n0\r\n\r\n", line);
+            assert!(is_bad_request(decode(input.as_bytes())), "{:?}", line);
+        }
+        assert_eq!(parse_chunk_size(b"ffffffff"), Some(0xffff_ffff));
+        assert_eq!(parse_chunk_size(b"0;a\t=\t\"\t\""), Some(0));
+    }
+
+    #[test]
+    fn test_bad_line_endings() {
+        for input in &[
+            &b"5\nhello\r\n0\r\n\r\n"[..],
+            b"5\r\r\nhello\r\n0\r\n\r\n",
+            b"5\r\nhello\r\n0\n\r\n",
+            b"5\r\nhello\r\n0\r\n\n",
+            b"5\r\nhelloXX0\r\n\r\n",
+            b"5\r\nhello\n0\r\n\r\n",
+            b"5\r\nhello world\r\n0\r\n\r\n",
+        ] {
+            assert!(is_bad_request(decode(input)), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_eof() {
+        for input in &[
+            &b""[..],
+            b"5\r\nhel",
+            b"5\r\nhello",
+            b"5\r\nhello\r\n",
+            b"5\r\nhello\r\n0\r\n",
+            b"5\r\nhello\r\n0\r\nx-a: b\r\n",
+        ] {
+            match decode(input) {
+                Err(LolbError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+                r => panic!("{:?}: {:?}", input, r.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_trailers() {
+        let input = b"5\r\nhello\r\n0\r\nX-Checksum: abc\r\ngrpc-status:  0 \r\n\
+            Content-Length: 5\r\nExpires: never\r\nx-checksum:def\r\n\r\nNEXT";
+        let (body, trailers, rest) = decode(input).unwrap();
+        assert_eq!(&body[..], b"hello");
+        assert_eq!(&rest[..], b"NEXT");
+        let trailers = trailers.unwrap();
+        assert_eq!(trailers.len(), 3);
+        let checksums: Vec<_> = trailers.get_all("x-checksum").iter().collect();
+        assert_eq!(checksums, vec!["abc", "def"]);
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+        assert!(trailers.get("content-length").is_none());
+        assert!(trailers.get("expires").is_none());
+    }
+
+    #[test]
+    fn test_bad_trailers() {
+        for trailer in &[
+            "no-colon",
+            ": no-name",
+            "x-space : b",
+            " x-fold: b",
+            "x-a: b\r\n\tfolded",
+            "x-a: b\r\n folded",
+            "x(a): b",
+            "x-a: b\u{7f}",
+        ] {
+            let input = format!("0\r\n{}\r\n\r\n", trailer);
+            assert!(is_bad_request(decode(input.as_bytes())), "{:?}", trailer);
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let long_ext = format!("5;x={}\r\nhello\r\n0\r\n\r\n", "a".repeat(MAX_LINE_LEN));
+        assert!(is_bad_request(decode(long_ext.as_bytes())));
+
+        let mut input = "0\r\n".to_string();
+        for i in 0..(MAX_TRAILER_SIZE / 100 + 1) {
+            input.push_str(&format!("x-{:04}: {}\r\n", i, "a".repeat(100)));
+        }
+        input.push_str("\r\n");
+        assert!(is_bad_request(decode(input.as_bytes())));
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let too_large = http::StatusCode::PAYLOAD_TOO_LARGE;
+        // a too big chunk is rejected before any of its data is read.
+        let input = format!("{:x}\r\nhello", MAX_CHUNK_SIZE + 1);
+        assert!(is_status(decode(input.as_bytes()), too_large));
+        assert!(is_status(decode(b"ffffffff\r\n"), too_large));
+
+        let body = |max_body_size: usize| {
+            let input = b"5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n";
+            let mut socket = Peekable::new(Trickle::new(input, 42));
+            let mut dec = ChunkedDecoder::new(&mut socket);
+            dec.max_body_size = max_body_size;
+            block_on(async {
+                let mut body = vec![];
+                while let Some(data) = dec.data().await {
+                    body.extend_from_slice(&data?[..]);
+                }
+                Ok::<_, LolbError>(body)
+            })
+        };
+        assert_eq!(&body(10).unwrap()[..], b"helloworld");
+        let err = body(9).unwrap_err();
+        assert_eq!(err.status(), Some(too_large));
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut out = vec![];
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        trailers.insert("content-length", HeaderValue::from_static("42"));
+        block_on(async {
+            let mut enc = ChunkedEncoder(&mut out);
+            enc.send_chunk(Bytes::from(vec![b'x'; 26])).await.unwrap();
+            // must not end the body.
+            enc.send_chunk(Bytes::new()).await.unwrap();
+            enc.send_chunk(Bytes::from_static(b"hello")).await.unwrap();
+            enc.send_finish(Some(&trailers)).await.unwrap();
+        });
+        let expected = format!(
+            "1a\r\n{}\r\n5\r\nhello\r\n0\r\ngrpc-status: 0\r\n\r\n",
+            "x".repeat(26)
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    fn random_trailers(rng: &mut StdRng) -> HeaderMap {
+        let mut trailers = HeaderMap::new();
+        for i in 0..rng.gen_range(0, 4) {
+            let value: String = (0..rng.gen_range(0, 20))
+                .map(|_| rng.gen_range(0x20_u8, 0x7f) as char)
+                .collect();
+            let value = HeaderValue::from_str(value.trim()).unwrap();
+            trailers.append(
+                format!("x-t{}", i % 2).parse::<HeaderName>().unwrap(),
+                value,
+            );
+        }
+        trailers
+    }
+
+    #[test]
+    fn test_fuzz_round_trip() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for seed in 0..200 {
+            let mut body = vec![];
+            let mut out = vec![];
+            let trailers = random_trailers(&mut rng);
+            block_on(async {
+                let mut enc = ChunkedEncoder(&mut out);
+                for _ in 0..rng.gen_range(0, 10) {
+                    let chunk: Vec<u8> = (0..rng.gen_range(0, 3000)).map(|_| rng.gen()).collect();
+                    body.extend_from_slice(&chunk);
+                    enc.send_chunk(chunk.into()).await.unwrap();
+                }
+                enc.send_finish(Some(&trailers)).await.unwrap();
+            });
+            let (decoded, dec_trailers, rest) = decode_with(Trickle::new(&out, seed)).unwrap();
+            assert_eq!(decoded, body);
+            assert_eq!(dec_trailers.unwrap_or_default(), trailers);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_fuzz_garbage() {
+        const ALPHABET: &[u8] = b"0123456789abcdefABCDEFxX;=\"\\ \t\r\n:-";
+        let mut rng = StdRng::seed_from_u64(11);
+        for seed in 0..2000 {
+            let mut input = b"5;a=b\r\nhello\r\n1a\r\nabcdefghijklmnopqrstuvwxyz\r\n\
+                0\r\nx-a: b\r\n\r\n"
+                .to_vec();
+            // mutate a few bytes, sometimes to random bytes, sometimes to syntax.
+            for _ in 0..rng.gen_range(1, 4) {
+                let pos = rng.gen_range(0, input.len());
+                input[pos] = if rng.gen() {
+                    rng.gen()
+                } else {
+                    ALPHABET[rng.gen_range(0, ALPHABET.len())]
+                };
+            }
+            if rng.gen_range(0, 4) == 0 {
+                input.truncate(rng.gen_range(0, input.len()));
+            }
+            // anything goes, but no panics, and it must come to an end.
+            if let Ok((body, _, _)) = decode_with(Trickle::new(&input, seed)) {
+                assert!(body.len() <= input.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_size_line() {
+        const ALPHABET: &[u8] = b"0123456789abcdefABCDEFgxX;=\"\\ \t,-";
+        let mut rng = StdRng::seed_from_u64(13);
+        for _ in 0..10_000 {
+            let line: Vec<u8> = (0..rng.gen_range(0, 24))
+                .map(|_| ALPHABET[rng.gen_range(0, ALPHABET.len())])
+                .collect();
+            if let Some(size) = parse_chunk_size(&line) {
+                let hex: String = line
+                    .iter()
+                    .take_while(|c| c.is_ascii_hexdigit())
+                    .map(|c| *c as char)
+                    .collect();
+                assert_eq!(usize::from_str_radix(&hex, 16).ok(), Some(size));
+                let rest = &line[hex.len()..];
+                assert!(
+                    rest.is_empty() || skip_bws(rest)[0] == b';',
+                    "{:?}",
+                    String::from_utf8_lossy(&line)
+                );
+            }
+        }
+    }
+}
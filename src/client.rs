@@ -0,0 +1,317 @@
+//! Client library for services registering with a load balancer.
+//!
+//! A service first authenticates by posting a `Preauthed` json to the load balancer
+//! over http2. The load balancer responds with a one-off reconnect key, which the
+//! service sends as `lolb<8 bytes>` preamble on a new connection. On that connection
+//! the roles are reversed: the load balancer is the http2 client and the service the
+//! http2 server receiving the proxied requests.
+//!
+//! ```no_run
+//! # async fn serve() -> lolb::LolbResult<()> {
+//! use lolb::client::{ServiceClient, ServiceConfig};
+//!
+//! let config = ServiceConfig::new(
+//!     "127.0.0.1:80".parse().unwrap(),
+//!     "secret",
+//!     "example.com",
+//!     "myservice.example.com",
+//!     "/",
+//! );
+//! let mut client = ServiceClient::new(config);
+//! loop {
+//!     let (req, mut send_resp) = client.accept().await?;
+//!     // respond to req using send_resp
+//! }
+//! # }
+//! ```
+use crate::serv_auth::{AuthResponse, Preauthed};
+use crate::service::PREAUTH_PREFIX;
+use crate::{AsyncWriteExt, BalanceStrategy, HashKey, LolbError, LolbResult, StatusKind};
+use crate::{HEADER_AUTH, PATH_NODE_REGISTER};
+use bytes::{Bytes, BytesMut};
+use futures_util::future::{select, Either};
+use futures_util::pin_mut;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_net::tcp::TcpStream;
+
+/// Backoff before the first reconnect attempt. Doubled for every failed attempt.
+const BACKOFF_START: Duration = Duration::from_millis(100);
+/// Max backoff between reconnect attempts.
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Configuration of a service registering with a load balancer.
+#[derive(Debug, Clone)]
+pub struct ServiceConfig {
+    /// Address of the load balancer.
+    pub lolb_addr: SocketAddr,
+    /// Preshared secret for the domain.
+    pub secret: String,
+    /// Domain serviced by the load balancer. Something like `example.com`.
+    pub domain: String,
+    /// Host of the service. Something like `myservice.example.com`.
+    pub host: String,
+    /// Route prefix serviced. I.e. `/` or `/something`.
+    pub prefix: String,
+    /// How the load balancer is to balance between connections of the route.
+    pub balance: BalanceStrategy,
+    /// Whether clients are to be pinned to the same connection using a cookie.
+    pub sticky: bool,
+    /// Route using consistent hashing of this part of the request.
+    pub hash: Option<HashKey>,
+}
+
+impl ServiceConfig {
+    pub fn new(
+        lolb_addr: SocketAddr,
+        secret: &str,
+        domain: &str,
+        host: &str,
+        prefix: &str,
+    ) -> Self {
+        ServiceConfig {
+            lolb_addr,
+            secret: secret.to_string(),
+            domain: domain.to_string(),
+            host: host.to_string(),
+            prefix: prefix.to_string(),
+            balance: BalanceStrategy::default(),
+            sticky: false,
+            hash: None,
+        }
+    }
+
+    fn preauthed(&self) -> Preauthed {
+        Preauthed::new(
+            &self.domain,
+            &self.host,
+            &self.prefix,
+            self.balance,
+            self.sticky,
+            self.hash.clone(),
+        )
+    }
+}
+
+/// Connection from a service to a load balancer.
+pub struct ServiceClient {
+    config: ServiceConfig,
+    /// The current connection, if connected.
+    conn: Option<h2::server::Connection<TcpStream, Bytes>>,
+    /// Current backoff between reconnect attempts.
+    backoff: Duration,
+}
+
+impl ServiceClient {
+    pub fn new(config: ServiceConfig) -> Self {
+        ServiceClient {
+            config,
+            conn: None,
+            backoff: BACKOFF_START,
+        }
+    }
+
+    /// Accept the next request proxied from the load balancer.
+    ///
+    /// This is the accept loop of the http2 server and must be polled for any request to
+    /// make progress. When the connection drops, the service transparently authenticates
+    /// and reconnects with a backoff. Only a rejected auth is returned as an error.
+    pub async fn accept(
+        &mut self,
+    ) -> LolbResult<(
+        http::Request<h2::RecvStream>,
+        h2::server::SendResponse<Bytes>,
+    )> {
+        loop {
+            if self.conn.is_none() {
+                match connect(&self.config).await {
+                    Ok(conn) => {
+                        self.conn = Some(conn);
+                        self.backoff = BACKOFF_START;
+                    }
+                    // a rejected auth will not go better by retrying.
+                    Err(e @ LolbError::Status(StatusKind::Unauthorized, _)) => return Err(e),
+                    Err(e) => {
+                        debug!("Failed to connect to load balancer: {}", e);
+                        self.wait_backoff().await;
+                        continue;
+                    }
+                }
+            }
+
+            let conn = self.conn.as_mut().unwrap();
+            match conn.accept().await {
+                Some(Ok(r)) => return Ok(r),
+                Some(Err(e)) => debug!("Load balancer connection failed: {}", e),
+                None => debug!("Load balancer connection closed"),
+            }
+
+            // reconnect
+            self.conn = None;
+            self.wait_backoff().await;
+        }
+    }
+
+    async fn wait_backoff(&mut self) {
+        tokio_timer::delay_for(self.backoff).await;
+        self.backoff = next_backoff(self.backoff);
+    }
+}
+
+/// The backoff after a failed attempt with the given backoff.
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(BACKOFF_MAX)
+}
+
+/// Authenticate and make the connection for proxied requests.
+async fn connect(config: &ServiceConfig) -> LolbResult<h2::server::Connection<TcpStream, Bytes>> {
+    let auth = authenticate(config).await?;
+
+    let mut tcp = TcpStream::connect(config.lolb_addr).await?;
+
+    // preamble telling the load balancer this is a preauthed service connection.
+    let mut preamble = Vec::with_capacity(12);
+    preamble.extend_from_slice(PREAUTH_PREFIX);
+    preamble.extend_from_slice(&auth.key.to_be_bytes());
+    tcp.write_all(&preamble[..]).await?;
+
+    // the load balancer is the client on this connection.
+    Ok(h2::server::handshake(tcp).await?)
+}
+
+/// Post the preauth to the load balancer to get a reconnect key.
+async fn authenticate(config: &ServiceConfig) -> LolbResult<AuthResponse> {
+    let tcp = TcpStream::connect(config.lolb_addr).await?;
+    let (send_req, conn) = h2::client::handshake(tcp).await?;
+
+    let json = serde_json::to_vec(&config.preauthed()).expect("Failed to json serialize Preauthed");
+    let uri = format!("http://{}{}", config.host, PATH_NODE_REGISTER);
+    let req = http::Request::builder()
+        .method("POST")
+        .uri(uri.as_str())
+        .header(HEADER_AUTH, config.secret.as_str())
+        .header("content-type", "application/json")
+        .body(())?;
+
+    let auth = async move {
+        let mut h2 = send_req.ready().await?;
+        let (response, mut send_body) = h2.send_request(req, false)?;
+        send_body.send_data(json.into(), true)?;
+
+        let res = response.await?;
+        let status = res.status();
+        let (_, mut body) = res.into_parts();
+
+        let mut bytes = BytesMut::new();
+        while let Some(data) = body.data().await {
+            let data = data?;
+            body.release_capacity().release_capacity(data.len())?;
+            bytes.extend_from_slice(&data[..]);
+        }
+
+        if status == http::StatusCode::UNAUTHORIZED {
+            return Err(LolbError::Status(StatusKind::Unauthorized, "Auth rejected"));
+        } else if !status.is_success() {
+            return Err(LolbError::Owned(format!("Auth failed: {}", status)));
+        }
+
+        serde_json::from_slice::<AuthResponse>(&bytes[..])
+            .map_err(|e| LolbError::Owned(format!("Bad auth response: {}", e)))
+    };
+
+    // the connection must be driven while the request is in progress.
+    pin_mut!(auth);
+    pin_mut!(conn);
+    match select(auth, conn).await {
+        Either::Left((res, _)) => res,
+        Either::Right((Err(e), _)) => Err(e.into()),
+        Either::Right((Ok(_), _)) => Err(LolbError::Message("Connection closed during auth")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{h2_request, start_lb, start_service, test_config, test_lb};
+    use crate::test::{wait_live, TestLb};
+    use std::sync::{Arc, Mutex};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_timer::Timeout;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = BACKOFF_START;
+        let mut schedule = vec![];
+        for _ in 0..12 {
+            schedule.push(backoff.as_millis());
+            backoff = next_backoff(backoff);
+        }
+        assert_eq!(
+            schedule,
+            vec![100, 200, 400, 800, 1600, 3200, 6400, 12800, 25600, 30000, 30000, 30000]
+        );
+    }
+
+    /// The id of the service connection requests to `a.example.com` are routed to.
+    fn routed_id(lb: &TestLb) -> u64 {
+        let req = http::Request::get("http://a.example.com/")
+            .body(())
+            .unwrap();
+        lb.lock().unwrap().services.route(&req).unwrap().conn.id()
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let mut rt = Runtime::new().unwrap();
+        let lb = Arc::new(Mutex::new(test_lb()));
+        let (ids, statuses) = rt
+            .block_on(Timeout::new(
+                async move {
+                    let addr = start_lb(lb.clone()).await;
+                    start_service(addr, "a.example.com");
+                    let mut ids = vec![];
+                    let mut statuses = vec![];
+                    for _ in 0..2 {
+                        wait_live(&lb, "a.example.com").await;
+                        ids.push(routed_id(&lb));
+                        let req = http::Request::get("http://a.example.com/")
+                            .body(())
+                            .unwrap();
+                        statuses.push(h2_request(addr, req, &[]).await.0.status());
+
+                        // removing the domain closes the service connection, and the
+                        // service reconnects when it's back.
+                        let mut lock = lb.lock().unwrap();
+                        let mut removed = test_config();
+                        removed.domains[0].domain = "other.com".into();
+                        lock.apply_config(removed).unwrap();
+                        lock.apply_config(test_config()).unwrap();
+                    }
+                    (ids, statuses)
+                },
+                Duration::from_secs(10),
+            ))
+            .expect("Timed out");
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(statuses, vec![http::StatusCode::NO_CONTENT; 2]);
+    }
+
+    #[test]
+    fn test_auth_rejected() {
+        let mut rt = Runtime::new().unwrap();
+        let lb = Arc::new(Mutex::new(test_lb()));
+        let err = rt
+            .block_on(Timeout::new(
+                async move {
+                    let addr = start_lb(lb).await;
+                    let config =
+                        ServiceConfig::new(addr, "wrong", "example.com", "a.example.com", "/");
+                    let mut client = ServiceClient::new(config);
+                    client.accept().await.err().unwrap()
+                },
+                Duration::from_secs(10),
+            ))
+            .expect("Timed out");
+        assert_eq!(err.status(), Some(http::StatusCode::UNAUTHORIZED));
+    }
+}
@@ -1,4 +1,234 @@
+use crate::service::ServiceAuth;
+use crate::{LolbError, LolbResult};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
+/// Load balancer configuration.
+///
+/// There are no listen addresses. The embedder binds the sockets, and hands the incoming
+/// connections to `accept_incoming` using a `ConnectionProvider`.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub struct Config {}
+pub struct Config {
+    /// Domains serviced by the load balancer.
+    pub domains: Vec<DomainConfig>,
+    /// Timeouts for handling connections.
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// ACME settings for TLS certificates.
+    pub acme: AcmeConfig,
+    /// Embedded DNS server. Needed for wildcard domains.
+    #[serde(default)]
+    pub dns: Option<DnsConfig>,
+}
+
+/// A domain serviced by the load balancer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainConfig {
+    /// The dns name of the domain serviced. Something like `example.com`.
+    pub domain: String,
+    /// Auth to use when adding service connections to this domain.
+    pub auth: ServiceAuth,
+    /// Use one wildcard certificate, `*.example.com`, for the hosts under the domain.
+    /// The ACME DNS-01 challenge is answered by the embedded DNS server, which
+    /// must be the authority of the domain.
+    #[serde(default)]
+    pub wildcard: bool,
+}
+
+/// Timeouts, in seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timeouts {
+    /// Time to wait for a service to respond to a request.
+    #[serde(default = "default_service_response")]
+    pub service_response: u64,
+    /// Time to wait for the next request on an idle http11 connection.
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: u64,
+}
+
+/// ACME account settings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AcmeConfig {
+    /// Contact email for the ACME account.
+    pub contact: String,
+    /// ACME directory url. Defaults to Let's Encrypt production.
+    #[serde(default = "default_acme_url")]
+    pub url: String,
+}
+
+/// Embedded DNS server settings. The embedder binds the sockets of the server, and
+/// passes them to `DnsServer::serve_udp` and `DnsServer::serve_tcp`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DnsConfig {
+    /// The public addresses of the load balancer. These are the A and AAAA records of
+    /// the service hosts.
+    #[serde(default)]
+    pub addrs: Vec<IpAddr>,
+    /// Addresses of names outside the serviced domains. The key is a name, or a
+    /// regex between slashes like `/.*\.local/`. The value is an ip, `env(VAR)` or a
+    /// shell command between backticks.
+    #[serde(default)]
+    pub address: BTreeMap<String, String>,
+    /// Upstream DNS server, i.e. `8.8.8.8:53`, for names outside the serviced domains
+    /// that are not in `address`.
+    #[serde(default)]
+    pub upstream: Option<SocketAddr>,
+}
+
+fn default_service_response() -> u64 {
+    30
+}
+
+fn default_keep_alive() -> u64 {
+    60
+}
+
+fn default_acme_url() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_string()
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            service_response: default_service_response(),
+            keep_alive: default_keep_alive(),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn service_response(&self) -> Duration {
+        Duration::from_secs(self.service_response)
+    }
+
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_secs(self.keep_alive)
+    }
+}
+
+impl Config {
+    /// Parse config from a TOML string.
+    pub fn from_toml(s: &str) -> LolbResult<Config> {
+        let config: Config =
+            toml::from_str(s).map_err(|e| LolbError::Owned(format!("Bad config: {}", e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse config from a JSON string.
+    pub fn from_json(s: &str) -> LolbResult<Config> {
+        let config: Config =
+            serde_json::from_str(s).map_err(|e| LolbError::Owned(format!("Bad config: {}", e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the config makes sense.
+    pub fn validate(&self) -> LolbResult<()> {
+        if self.domains.is_empty() {
+            return Err(LolbError::Message("Bad config: No domains"));
+        }
+        let mut seen = HashSet::new();
+        for d in &self.domains {
+            if d.domain.is_empty() || d.domain.starts_with('.') || d.domain.ends_with('.') {
+                return Err(LolbError::Owned(format!(
+                    "Bad config: Invalid domain: {}",
+                    d.domain
+                )));
+            }
+            if !seen.insert(d.domain.to_ascii_lowercase()) {
+                return Err(LolbError::Owned(format!(
+                    "Bad config: Duplicate domain: {}",
+                    d.domain
+                )));
+            }
+            if !d.auth.is_configured() {
+                return Err(LolbError::Owned(format!(
+                    "Bad config: Empty auth for domain: {}",
+                    d.domain
+                )));
+            }
+        }
+        if self.timeouts.service_response == 0 {
+            return Err(LolbError::Message(
+                "Bad config: Zero service response timeout",
+            ));
+        }
+        if self.timeouts.keep_alive == 0 {
+            return Err(LolbError::Message("Bad config: Zero keep-alive timeout"));
+        }
+        if self.dns.is_none() {
+            if let Some(d) = self.domains.iter().find(|d| d.wildcard) {
+                return Err(LolbError::Owned(format!(
+                    "Bad config: Wildcard domain without dns: {}",
+                    d.domain
+                )));
+            }
+        }
+        if !self.acme.contact.contains('@') {
+            return Err(LolbError::Owned(format!(
+                "Bad config: ACME contact is not an email: {}",
+                self.acme.contact
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TOML: &str = r#"
+[[domains]]
+domain = "example.com"
+auth = { PresharedKey = "secret" }
+
+[acme]
+contact = "admin@example.com"
+"#;
+
+    #[test]
+    fn test_from_toml() {
+        let config = Config::from_toml(TOML).unwrap();
+        assert_eq!(config.domains[0].domain, "example.com");
+        assert_eq!(
+            config.domains[0].auth,
+            ServiceAuth::PresharedKey("secret".into())
+        );
+        assert_eq!(config.timeouts, Timeouts::default());
+        assert_eq!(config.acme.url, default_acme_url());
+        assert!(!config.domains[0].wildcard);
+        assert_eq!(config.dns, None);
+    }
+
+    #[test]
+    fn test_from_json() {
+        let config = Config::from_toml(TOML).unwrap();
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(Config::from_json(&json).unwrap(), config);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = Config::from_toml(TOML).unwrap();
+
+        let mut dupe = config.clone();
+        dupe.domains.push(dupe.domains[0].clone());
+        assert!(dupe.validate().is_err());
+
+        let mut no_auth = config.clone();
+        no_auth.domains[0].auth = ServiceAuth::PresharedKey("".into());
+        assert!(no_auth.validate().is_err());
+
+        let mut no_dns = config.clone();
+        no_dns.domains[0].wildcard = true;
+        assert!(no_dns.validate().is_err());
+
+        let mut contact = config;
+        contact.acme.contact = "nope".into();
+        assert!(contact.validate().is_err());
+    }
+}
@@ -2,6 +2,7 @@ use crate::peek::Peekable;
 use crate::{AsyncRead, AsyncWrite, LolbResult};
 use std::future::Future;
 use std::io::{Read, Write};
+use std::net::SocketAddr;
 
 pub trait Socket: Read + Write + AsyncRead + AsyncWrite + Unpin {}
 
@@ -21,6 +22,8 @@ where
 {
     /// The underlying socket.
     socket: Peekable<S>,
+    /// Address of the connecting peer.
+    peer_addr: SocketAddr,
     /// Whether we are treating the connection as http11 or http2. This is in the
     /// ALPN negotiated when using TLS.
     http_version: HttpVersion,
@@ -29,9 +32,15 @@ where
 }
 
 impl<S: Socket> Connection<S> {
-    pub fn new(socket: S, http_version: HttpVersion, is_secure: bool) -> Self {
+    pub fn new(
+        socket: S,
+        peer_addr: SocketAddr,
+        http_version: HttpVersion,
+        is_secure: bool,
+    ) -> Self {
         Connection {
             socket: Peekable::new(socket),
+            peer_addr,
             http_version,
             is_secure,
         }
@@ -41,6 +50,15 @@ impl<S: Socket> Connection<S> {
         &mut self.socket
     }
 
+    /// Take the underlying socket, including anything peeked.
+    pub fn into_socket(self) -> Peekable<S> {
+        self.socket
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
     pub fn http_version(&self) -> HttpVersion {
         self.http_version
     }
@@ -50,6 +68,10 @@ impl<S: Socket> Connection<S> {
     }
 }
 
+/// Request extension with the address of the peer that sent the request.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PeerAddr(pub SocketAddr);
+
 /// The version of http connection.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HttpVersion {
@@ -60,3 +82,139 @@ pub enum HttpVersion {
     /// HTTP/2
     Http2,
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use tokio_net::tcp::{TcpListener, TcpStream};
+    use tokio_sync::Mutex;
+
+    /// In memory client connection.
+    pub(crate) struct Duplex {
+        pub input: io::Cursor<Vec<u8>>,
+        pub output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Write::write(&mut self.output, buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Duplex {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Read::read(self.get_mut(), buf))
+        }
+    }
+
+    impl AsyncWrite for Duplex {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Write::write(self.get_mut(), buf))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Socket for Duplex {}
+
+    pub(crate) fn duplex(input: &[u8]) -> Duplex {
+        Duplex {
+            input: io::Cursor::new(input.to_vec()),
+            output: vec![],
+        }
+    }
+
+    /// Tcp connection for tests. Only the async io is used.
+    pub(crate) struct TestTcp(pub TcpStream);
+
+    impl Read for TestTcp {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for TestTcp {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for TestTcp {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for TestTcp {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+        }
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_flush(cx)
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+        }
+    }
+
+    impl Socket for TestTcp {}
+
+    pub(crate) type TestAccept =
+        Pin<Box<dyn Future<Output = LolbResult<Connection<TestTcp>>> + Send>>;
+
+    /// Provides the plain tcp connections of a listener.
+    pub(crate) struct TcpProvider(Arc<Mutex<TcpListener>>);
+
+    impl TcpProvider {
+        pub fn new(listener: TcpListener) -> Self {
+            TcpProvider(Arc::new(Mutex::new(listener)))
+        }
+    }
+
+    impl ConnectionProvider<TestTcp, TestAccept> for TcpProvider {
+        fn accept(&mut self) -> TestAccept {
+            let listener = self.0.clone();
+            Box::pin(async move {
+                let (tcp, peer_addr) = listener.lock().await.accept().await?;
+                let conn = Connection::new(TestTcp(tcp), peer_addr, HttpVersion::Unknown, false);
+                Ok(conn)
+            })
+        }
+    }
+}
@@ -0,0 +1,419 @@
+use super::packet::{
+    BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, MAX_EDNS_SIZE,
+};
+use super::{read_frame, write_frame};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_net::tcp::TcpStream;
+use tokio_net::udp::UdpSocket;
+use tokio_timer::Timeout;
+
+/// Time to wait for the upstream server to respond.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Max number of cached responses.
+const MAX_CACHE_ENTRIES: usize = 10_000;
+
+/// Max time, in seconds, to cache a response whatever the TTL says.
+const MAX_CACHE_TTL: u32 = 86_400;
+
+/// Forwards queries to an upstream DNS server. Responses are cached for as long as
+/// their TTLs say.
+pub(crate) struct Forwarder {
+    upstream: SocketAddr,
+    cache: Mutex<HashMap<(String, QueryType), Cached>>,
+}
+
+struct Cached {
+    response: DnsPacket,
+    at: Instant,
+    expires: Instant,
+}
+
+impl Forwarder {
+    pub fn new(upstream: SocketAddr) -> Self {
+        Forwarder {
+            upstream,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Look up the question, from the cache if possible.
+    pub async fn lookup(&self, question: &DnsQuestion) -> io::Result<DnsPacket> {
+        if let Some(response) = self.cached(question) {
+            return Ok(response);
+        }
+        let response = self.forward(question).await?;
+        self.cache(question, &response);
+        Ok(response)
+    }
+
+    /// The cached response, with the TTLs counted down by the time spent in the cache.
+    fn cached(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        let key = (question.name.clone(), question.qtype);
+        let now = Instant::now();
+        let mut lock = self.cache.lock().unwrap();
+
+        let expired = lock.get(&key).map(|c| c.expires <= now)?;
+        if expired {
+            lock.remove(&key);
+            return None;
+        }
+
+        let cached = &lock[&key];
+        let elapsed = (now - cached.at).as_secs() as u32;
+        let mut response = cached.response.clone();
+        for rec in response
+            .answers
+            .iter_mut()
+            .chain(response.authorities.iter_mut())
+        {
+            if let Some(ttl) = rec.ttl() {
+                rec.set_ttl(ttl.saturating_sub(elapsed));
+            }
+        }
+        Some(response)
+    }
+
+    fn cache(&self, question: &DnsQuestion, response: &DnsPacket) {
+        let ttl = match cache_ttl(response) {
+            Some(x) if x > 0 => x,
+            _ => return,
+        };
+        let now = Instant::now();
+        let mut lock = self.cache.lock().unwrap();
+
+        if lock.len() >= MAX_CACHE_ENTRIES {
+            lock.retain(|_, c| c.expires > now);
+            if lock.len() >= MAX_CACHE_ENTRIES {
+                debug!("DNS cache is full");
+                return;
+            }
+        }
+
+        lock.insert(
+            (question.name.clone(), question.qtype),
+            Cached {
+                response: response.clone(),
+                at: now,
+                expires: now + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+
+    /// Send the question to the upstream server. Truncated responses are retried
+    /// over TCP.
+    async fn forward(&self, question: &DnsQuestion) -> io::Result<DnsPacket> {
+        // a random id makes it hard to spoof a response.
+        let id = rand::random::<u16>();
+
+        let mut request = DnsPacket::new();
+        request.header.id = id;
+        request.header.recursion_desired = true;
+        request.questions.push(question.clone());
+        request.resources.push(DnsRecord::OPT {
+            udp_size: MAX_EDNS_SIZE as u16,
+            flags: 0,
+            options: vec![],
+        });
+        let mut buffer = BytePacketBuffer::new();
+        request.write(&mut buffer)?;
+        let request = buffer.into_vec();
+
+        let exchange = async {
+            let response = self.forward_udp(id, question, &request).await?;
+            if response.header.truncated_message {
+                self.forward_tcp(id, question, &request).await
+            } else {
+                Ok(response)
+            }
+        };
+
+        let mut response = match Timeout::new(exchange, FORWARD_TIMEOUT).await {
+            Ok(x) => x?,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Upstream DNS server timeout",
+                ))
+            }
+        };
+
+        // records we can't write back are dropped, and the EDNS0 record is about the
+        // upstream transport.
+        let keep = |r: &DnsRecord| !matches!(r, DnsRecord::UNKNOWN { .. } | DnsRecord::OPT { .. });
+        response.answers.retain(keep);
+        response.authorities.retain(keep);
+        response.resources.retain(keep);
+
+        Ok(response)
+    }
+
+    async fn forward_udp(
+        &self,
+        id: u16,
+        question: &DnsQuestion,
+        request: &[u8],
+    ) -> io::Result<DnsPacket> {
+        // a new socket for every query gives a random source port.
+        let bind: SocketAddr = if self.upstream.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0, 0, 0, 0, 0, 0, 0, 0], 0).into()
+        };
+        let mut socket = UdpSocket::bind(bind).await?;
+        socket.send_to(request, self.upstream).await?;
+
+        loop {
+            let mut buf = vec![0; MAX_EDNS_SIZE];
+            let (len, src) = socket.recv_from(&mut buf).await?;
+            buf.truncate(len);
+
+            // anything else is stray or spoofed.
+            if src != self.upstream {
+                debug!("Ignoring DNS response from: {}", src);
+                continue;
+            }
+            match DnsPacket::from_buffer(&mut BytePacketBuffer::from_vec(buf)) {
+                Ok(x) if is_response_to(&x, id, question) => return Ok(x),
+                _ => debug!("Ignoring bad DNS response from: {}", src),
+            }
+        }
+    }
+
+    async fn forward_tcp(
+        &self,
+        id: u16,
+        question: &DnsQuestion,
+        request: &[u8],
+    ) -> io::Result<DnsPacket> {
+        let mut stream = TcpStream::connect(self.upstream).await?;
+        write_frame(&mut stream, request).await?;
+
+        let buf = read_frame(&mut stream)
+            .await?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "No DNS response"))?;
+
+        let response = DnsPacket::from_buffer(&mut BytePacketBuffer::from_vec(buf))?;
+        if !is_response_to(&response, id, question) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "DNS response to other query",
+            ));
+        }
+        Ok(response)
+    }
+}
+
+fn is_response_to(response: &DnsPacket, id: u16, question: &DnsQuestion) -> bool {
+    response.header.response
+        && response.header.id == id
+        && response.questions.first() == Some(question)
+}
+
+/// Seconds a response can be cached. The lowest TTL of the answers, or for a negative
+/// response, the SOA minimum (RFC 2308). Failures are not cached.
+fn cache_ttl(response: &DnsPacket) -> Option<u32> {
+    match response.header.rescode {
+        ResultCode::NOERROR | ResultCode::NXDOMAIN => {}
+        _ => return None,
+    }
+    if response.header.truncated_message {
+        return None;
+    }
+
+    let ttl = if response.answers.is_empty() {
+        response.authorities.iter().find_map(|r| match r {
+            DnsRecord::SOA { ttl, minimum, .. } => Some((*ttl).min(*minimum)),
+            _ => None,
+        })?
+    } else {
+        response.answers.iter().filter_map(|r| r.ttl()).min()?
+    };
+
+    Some(ttl.min(MAX_CACHE_TTL))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::sync::{Arc, Mutex as StdMutex};
+    use std::thread;
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Queries seen by the stub upstream as (source port, id).
+    type Seen = Arc<StdMutex<Vec<(u16, u16)>>>;
+
+    /// Upstream server answering `nx.example.org` with NXDOMAIN, `zero.example.org` with
+    /// TTL 0, and anything else with an A record. `stray.example.org` gets a response with
+    /// the wrong id before the right one.
+    fn stub_upstream() -> (SocketAddr, Seen) {
+        let socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let seen: Seen = Arc::new(StdMutex::new(vec![]));
+        let seen2 = seen.clone();
+
+        thread::spawn(move || loop {
+            let mut buf = vec![0; MAX_EDNS_SIZE];
+            let (len, src) = socket.recv_from(&mut buf).unwrap();
+            buf.truncate(len);
+            let request = DnsPacket::from_buffer(&mut BytePacketBuffer::from_vec(buf)).unwrap();
+            seen2.lock().unwrap().push((src.port(), request.header.id));
+
+            let question = request.questions[0].clone();
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.questions.push(question.clone());
+
+            match &question.name[..] {
+                "nx.example.org" => {
+                    response.header.rescode = ResultCode::NXDOMAIN;
+                    response.authorities.push(DnsRecord::SOA {
+                        domain: "example.org".into(),
+                        mname: "ns.example.org".into(),
+                        rname: "hostmaster.example.org".into(),
+                        serial: 1,
+                        refresh: 7200,
+                        retry: 3600,
+                        expire: 1_209_600,
+                        minimum: 60,
+                        ttl: 300,
+                    });
+                }
+                name => response.answers.push(DnsRecord::A {
+                    domain: name.into(),
+                    addr: "10.1.1.1".parse().unwrap(),
+                    ttl: if name == "zero.example.org" { 0 } else { 300 },
+                }),
+            }
+
+            if question.name == "stray.example.org" {
+                let mut stray = response.clone();
+                stray.header.id = stray.header.id.wrapping_add(1);
+                stray.answers[0] = DnsRecord::A {
+                    domain: question.name.clone(),
+                    addr: "10.6.6.6".parse().unwrap(),
+                    ttl: 300,
+                };
+                let mut buffer = BytePacketBuffer::new();
+                stray.write(&mut buffer).unwrap();
+                socket.send_to(&buffer.into_vec(), src).unwrap();
+            }
+
+            let mut buffer = BytePacketBuffer::new();
+            response.write(&mut buffer).unwrap();
+            socket.send_to(&buffer.into_vec(), src).unwrap();
+        });
+
+        (addr, seen)
+    }
+
+    fn question(name: &str) -> DnsQuestion {
+        DnsQuestion::new(name.into(), QueryType::A)
+    }
+
+    #[test]
+    fn test_forward_and_cache() {
+        let (upstream, seen) = stub_upstream();
+        let forwarder = Forwarder::new(upstream);
+        let mut rt = Runtime::new().unwrap();
+
+        for _ in 0..2 {
+            let res = rt.block_on(forwarder.lookup(&question("a.example.org")));
+            let res = res.unwrap();
+            assert_eq!(res.header.rescode, ResultCode::NOERROR);
+            assert_eq!(
+                res.answers,
+                vec![DnsRecord::A {
+                    domain: "a.example.org".into(),
+                    addr: "10.1.1.1".parse().unwrap(),
+                    ttl: 300,
+                }]
+            );
+        }
+        // the second lookup is cached.
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let (upstream, seen) = stub_upstream();
+        let forwarder = Forwarder::new(upstream);
+        let mut rt = Runtime::new().unwrap();
+
+        // ttl 0 is never cached.
+        for _ in 0..2 {
+            rt.block_on(forwarder.lookup(&question("zero.example.org")))
+                .unwrap();
+        }
+        assert_eq!(seen.lock().unwrap().len(), 2);
+
+        // negative responses are cached using the SOA minimum.
+        for _ in 0..2 {
+            let res = rt.block_on(forwarder.lookup(&question("nx.example.org")));
+            assert_eq!(res.unwrap().header.rescode, ResultCode::NXDOMAIN);
+        }
+        assert_eq!(seen.lock().unwrap().len(), 3);
+
+        // expired entries are looked up again.
+        {
+            let mut lock = forwarder.cache.lock().unwrap();
+            let cached = lock.values_mut().next().unwrap();
+            cached.expires = Instant::now();
+        }
+        rt.block_on(forwarder.lookup(&question("nx.example.org")))
+            .unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_ttl_counts_down() {
+        let (upstream, _) = stub_upstream();
+        let forwarder = Forwarder::new(upstream);
+        let mut rt = Runtime::new().unwrap();
+
+        rt.block_on(forwarder.lookup(&question("a.example.org")))
+            .unwrap();
+        {
+            let mut lock = forwarder.cache.lock().unwrap();
+            let cached = lock.values_mut().next().unwrap();
+            cached.at = cached.at.checked_sub(Duration::from_secs(100)).unwrap();
+        }
+        let res = rt.block_on(forwarder.lookup(&question("a.example.org")));
+        assert_eq!(res.unwrap().answers[0].ttl(), Some(200));
+    }
+
+    #[test]
+    fn test_wrong_id_ignored() {
+        let (upstream, _) = stub_upstream();
+        let forwarder = Forwarder::new(upstream);
+        let mut rt = Runtime::new().unwrap();
+
+        let res = rt.block_on(forwarder.lookup(&question("stray.example.org")));
+        match &res.unwrap().answers[0] {
+            DnsRecord::A { addr, .. } => assert_eq!(addr.to_string(), "10.1.1.1"),
+            r => panic!("Unexpected record: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_random_id_and_port() {
+        let (upstream, seen) = stub_upstream();
+        let forwarder = Forwarder::new(upstream);
+        let mut rt = Runtime::new().unwrap();
+
+        for name in &["a.example.org", "b.example.org", "c.example.org"] {
+            rt.block_on(forwarder.lookup(&question(name))).unwrap();
+        }
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 3);
+        assert!(seen.iter().any(|s| s.0 != seen[0].0));
+        assert!(seen.iter().any(|s| s.1 != seen[0].1));
+    }
+}
@@ -0,0 +1,348 @@
+use super::packet::{DnsPacket, DnsRecord, QueryType, ResultCode};
+use crate::{LolbError, LolbResult};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the output of an address command is used before the command is rerun.
+const CMD_CACHE_TIME: Duration = Duration::from_secs(60);
+
+/// How long an address command may run before it is killed.
+const CMD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a running address command is checked for having exited.
+const CMD_POLL: Duration = Duration::from_millis(10);
+
+/// Name to match in the address table.
+enum AddressKey {
+    Raw(String),
+    Regex(Regex),
+}
+
+impl AddressKey {
+    fn new(text: &str) -> std::result::Result<Self, regex::Error> {
+        if text.len() > 1 && text.starts_with('/') && text.ends_with('/') {
+            let s = &text[1..text.len() - 1];
+            Ok(AddressKey::Regex(Regex::new(s)?))
+        } else {
+            Ok(AddressKey::Raw(text.to_owned()))
+        }
+    }
+
+    fn is_match(&self, addr: &str) -> bool {
+        match self {
+            AddressKey::Raw(text) => text == addr,
+            AddressKey::Regex(regex) => regex.is_match(addr),
+        }
+    }
+}
+
+/// Address in the address table.
+enum IpValue {
+    Raw(String),
+    Cmd(CmdValue),
+}
+
+/// Address that is the output of a shell command. The command is run on a thread of its
+/// own when the value is created, and rerun when the output is older than
+/// `CMD_CACHE_TIME`. Meanwhile the previous output is used, which means a query never
+/// waits for a command. The output is empty until the first run is done.
+struct CmdValue {
+    cmd: String,
+    timeout: Duration,
+    cached: Arc<Mutex<CmdOutput>>,
+}
+
+struct CmdOutput {
+    value: String,
+    at: Instant,
+    running: bool,
+}
+
+impl IpValue {
+    fn new(text: &str) -> LolbResult<Self> {
+        if text.len() > 1 && text.starts_with('`') && text.ends_with('`') {
+            let s = &text[1..text.len() - 1];
+            Ok(IpValue::Cmd(CmdValue::new(s, CMD_TIMEOUT)))
+        } else if text.starts_with("env(") && text.ends_with(')') {
+            let env = &text[4..text.len() - 1];
+            let arg = std::env::var(env).unwrap_or_default();
+            if arg.is_empty() {
+                return Err(LolbError::Owned(format!(
+                    "Environment argument is missing or empty: {}",
+                    env
+                )));
+            }
+            debug!("Environment argument load: {} = {}", env, arg);
+            Ok(IpValue::Raw(arg))
+        } else {
+            Ok(IpValue::Raw(text.to_owned()))
+        }
+    }
+
+    fn value(&self) -> String {
+        match self {
+            IpValue::Raw(text) => text.to_owned(),
+            IpValue::Cmd(cmd) => cmd.value(),
+        }
+    }
+}
+
+impl CmdValue {
+    fn new(cmd: &str, timeout: Duration) -> Self {
+        let value = CmdValue {
+            cmd: cmd.to_owned(),
+            timeout,
+            cached: Arc::new(Mutex::new(CmdOutput {
+                value: "".to_owned(),
+                at: Instant::now(),
+                running: true,
+            })),
+        };
+        value.spawn_run();
+        value
+    }
+
+    fn value(&self) -> String {
+        let mut lock = self.cached.lock().unwrap();
+        if !lock.running && lock.at.elapsed() >= CMD_CACHE_TIME {
+            lock.running = true;
+            self.spawn_run();
+        }
+        lock.value.clone()
+    }
+
+    /// Run the command on a thread of its own, and update the output when done. The
+    /// caller sets `running`.
+    fn spawn_run(&self) {
+        let cmd = self.cmd.clone();
+        let timeout = self.timeout;
+        let cached = self.cached.clone();
+        thread::spawn(move || {
+            let value = run_cmd(&cmd, timeout);
+            let mut lock = cached.lock().unwrap();
+            lock.value = value;
+            lock.at = Instant::now();
+            lock.running = false;
+        });
+    }
+}
+
+/// Run a shell command for its output. A failed command, or one that doesn't exit
+/// within the timeout, is logged and gives an empty output.
+fn run_cmd(cmd: &str, timeout: Duration) -> String {
+    let mut child = match Command::new("bash")
+        .arg("-c")
+        .arg(cmd)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Address command failed to run: {}", e);
+            return "".to_owned();
+        }
+    };
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if started.elapsed() >= timeout => {
+                warn!("Address command timed out: {}", cmd);
+                child.kill().ok();
+                child.wait().ok();
+                return "".to_owned();
+            }
+            Ok(None) => thread::sleep(CMD_POLL),
+            Err(e) => {
+                warn!("Address command failed: {}", e);
+                return "".to_owned();
+            }
+        }
+    }
+    let output = match child.wait_with_output() {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Address command failed to run: {}", e);
+            return "".to_owned();
+        }
+    };
+    if output.status.success() {
+        String::from_utf8(output.stdout).unwrap_or_default()
+    } else {
+        warn!(
+            "Address command failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        "".to_owned()
+    }
+}
+
+/// Table of addresses for names outside the serviced domains.
+pub(crate) struct AddressMatcher {
+    inner: Vec<(AddressKey, IpValue)>,
+}
+
+impl AddressMatcher {
+    pub fn new(address: &BTreeMap<String, String>) -> LolbResult<Self> {
+        let mut vec = Vec::new();
+        for (addr_text, ip_text) in address {
+            let key = AddressKey::new(addr_text)
+                .map_err(|e| LolbError::Owned(format!("Bad dns address regex: {}", e)))?;
+            vec.push((key, IpValue::new(ip_text)?));
+        }
+        Ok(Self { inner: vec })
+    }
+
+    fn matches(&self, qname: &str) -> Option<String> {
+        self.inner
+            .iter()
+            .find(|(address_key, _)| address_key.is_match(qname))
+            .map(|(_, ip_value)| ip_value.value())
+    }
+}
+
+/// Answer from the address table, or `None` if no name in the table matches.
+pub(crate) fn lookup_regexp_hack(
+    qname: &str,
+    qtype: QueryType,
+    matcher: &AddressMatcher,
+) -> Option<DnsPacket> {
+    let value = matcher.matches(qname)?;
+
+    let mut dns_packet = DnsPacket::new();
+    dns_packet.header.rescode = ResultCode::NOERROR;
+
+    if qtype != QueryType::A && qtype != QueryType::AAAA {
+        debug!("Unsupported query type: {:?}", qtype);
+        dns_packet.header.rescode = ResultCode::NOTIMP;
+        return Some(dns_packet);
+    }
+
+    let addr: IpAddr = match value.trim().parse() {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Parse ip address error: {:?}", e);
+            dns_packet.header.rescode = ResultCode::SERVFAIL;
+            return Some(dns_packet);
+        }
+    };
+
+    // an address of the other family is an empty answer, the name exists.
+    match (qtype, addr) {
+        (QueryType::A, IpAddr::V4(addr)) => dns_packet.answers.push(DnsRecord::A {
+            domain: qname.to_owned(),
+            addr,
+            ttl: 0,
+        }),
+        (QueryType::AAAA, IpAddr::V6(addr)) => dns_packet.answers.push(DnsRecord::AAAA {
+            domain: qname.to_owned(),
+            addr,
+            ttl: 0,
+        }),
+        _ => {}
+    }
+    Some(dns_packet)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matcher() -> AddressMatcher {
+        let mut address = BTreeMap::new();
+        address.insert("v4.local".to_string(), "10.0.0.1".to_string());
+        address.insert("/^v6\\..*/".to_string(), "::1".to_string());
+        address.insert("cmd.local".to_string(), "`echo 10.0.0.2`".to_string());
+        AddressMatcher::new(&address).unwrap()
+    }
+
+    #[test]
+    fn test_lookup_family() {
+        let matcher = matcher();
+
+        let res = lookup_regexp_hack("v4.local", QueryType::A, &matcher).unwrap();
+        assert_eq!(res.answers.len(), 1);
+        let res = lookup_regexp_hack("v4.local", QueryType::AAAA, &matcher).unwrap();
+        assert_eq!(res.header.rescode, ResultCode::NOERROR);
+        assert!(res.answers.is_empty());
+
+        let res = lookup_regexp_hack("v6.local", QueryType::AAAA, &matcher).unwrap();
+        assert_eq!(
+            res.answers,
+            vec![DnsRecord::AAAA {
+                domain: "v6.local".into(),
+                addr: "::1".parse().unwrap(),
+                ttl: 0,
+            }]
+        );
+        let res = lookup_regexp_hack("v6.local", QueryType::A, &matcher).unwrap();
+        assert!(res.answers.is_empty());
+
+        let res = lookup_regexp_hack("v4.local", QueryType::MX, &matcher).unwrap();
+        assert_eq!(res.header.rescode, ResultCode::NOTIMP);
+
+        assert!(lookup_regexp_hack("other", QueryType::A, &matcher).is_none());
+        assert!(lookup_regexp_hack("other", QueryType::MX, &matcher).is_none());
+    }
+
+    /// Wait for a run of the command to be done.
+    fn wait_done(cmd: &CmdValue) {
+        while cmd.cached.lock().unwrap().running {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn cmd_value(matcher: &AddressMatcher) -> &CmdValue {
+        matcher
+            .inner
+            .iter()
+            .find_map(|(_, v)| match v {
+                IpValue::Cmd(cmd) => Some(cmd),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cmd_cached() {
+        let matcher = matcher();
+        wait_done(cmd_value(&matcher));
+        let res = lookup_regexp_hack("cmd.local", QueryType::A, &matcher).unwrap();
+        assert_eq!(
+            res.answers,
+            vec![DnsRecord::A {
+                domain: "cmd.local".into(),
+                addr: "10.0.0.2".parse().unwrap(),
+                ttl: 0,
+            }]
+        );
+
+        // a stale value is still used while the command reruns.
+        let cmd = cmd_value(&matcher);
+        {
+            let mut lock = cmd.cached.lock().unwrap();
+            lock.at = lock.at.checked_sub(CMD_CACHE_TIME).unwrap();
+            lock.value = "10.0.0.3".into();
+        }
+        assert_eq!(cmd.value(), "10.0.0.3");
+        wait_done(cmd);
+        assert_eq!(cmd.value().trim(), "10.0.0.2");
+    }
+
+    #[test]
+    fn test_cmd_timeout() {
+        let started = Instant::now();
+        let cmd = CmdValue::new("sleep 5; echo 10.0.0.2", Duration::from_millis(100));
+        // creating the value doesn't wait for the command.
+        assert_eq!(cmd.value(), "");
+        wait_done(&cmd);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(cmd.value(), "");
+    }
+}
@@ -0,0 +1,770 @@
+//! Embedded authoritative DNS server.
+//!
+//! The server is authoritative for the serviced domains. Service hosts with at least
+//! one live connection resolve to the addresses of the load balancer, which means a
+//! newly added service is resolvable without any external DNS provider. The domain
+//! itself has the SOA and NS records of the zone, where the NS is the zone itself like
+//! the primary name server of the SOA. The domain, and names with service hosts under
+//! them, exist even without addresses of their own (RFC 8020). Other names under the
+//! domains are `NXDOMAIN`. Negative responses have the SOA of the zone, which tells
+//! resolvers for how long to cache them.
+//!
+//! It also answers `_acme-challenge` TXT queries, which is how ACME validates wildcard
+//! certificates. For this to work the server must be the authority of the domains,
+//! i.e. an NS record of `example.com` points to it.
+//!
+//! Names outside the serviced domains are looked up in the address table of the
+//! `DnsConfig`. Names not in the table are forwarded to the upstream server, if one is
+//! configured, and the responses are cached for as long as their TTLs say.
+//!
+//! Queries are served over both UDP and TCP, on sockets the embedder binds and passes to
+//! `serve_udp` and `serve_tcp`. UDP responses that don't fit are sent truncated, and the
+//! client retries over TCP. Every query, and every TCP connection,
+//! is a task spawned on the executor, which means the server runs on the same runtime
+//! as the rest of the load balancer.
+use crate::acme::DNS_ACME_CHALLENGE;
+use crate::persist::Persist;
+use crate::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::{DnsConfig, Executor, LoadBalancer, LolbResult, TokioExecutor};
+use forward::Forwarder;
+use matcher::{lookup_regexp_hack, AddressMatcher};
+use packet::{
+    BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, MAX_EDNS_SIZE,
+    MAX_UDP_SIZE,
+};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_net::tcp::TcpListener;
+use tokio_net::udp::UdpSocket;
+use tokio_sync::mpsc;
+use tokio_timer::Timeout;
+
+mod forward;
+mod matcher;
+pub mod packet;
+
+/// TTL of service host records. Services come and go, so keep it short.
+const HOST_TTL: u32 = 60;
+
+/// TTL of the SOA record of the zones. Negative responses are cached for the lesser of
+/// this and the minimum of the SOA.
+const SOA_TTL: u32 = 3600;
+
+/// Time to wait for the next query of a TCP connection before closing it.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Max number of UDP queries and TCP connections handled at the same time. Queries
+/// over the limit are dropped, and the client will retry.
+const MAX_IN_FLIGHT: usize = 256;
+
+/// The records served by the DNS server.
+pub trait Zone: Send + Sync {
+    /// Tells if the name is the domain, or a name under the domain, of a serviced domain.
+    fn is_authority(&self, name: &str) -> bool;
+    /// The serviced domain of the name, which is the zone the name is in.
+    fn zone_of(&self, name: &str) -> Option<String>;
+    /// Tells if the name is a service host with at least one live connection.
+    fn is_live_host(&self, name: &str) -> bool;
+    /// Tells if there is a service host with at least one live connection under the name.
+    fn has_live_host_under(&self, name: &str) -> bool;
+    /// TXT record values for the name.
+    fn txt(&self, name: &str) -> Vec<String>;
+}
+
+impl<P: Persist> Zone for Mutex<LoadBalancer<P>> {
+    fn is_authority(&self, name: &str) -> bool {
+        let lock = self.lock().unwrap();
+        lock.services.is_authority(name)
+    }
+    fn zone_of(&self, name: &str) -> Option<String> {
+        let lock = self.lock().unwrap();
+        lock.services.domain_of(name).map(|d| d.to_string())
+    }
+    fn is_live_host(&self, name: &str) -> bool {
+        let lock = self.lock().unwrap();
+        lock.services.is_live_host(name)
+    }
+    fn has_live_host_under(&self, name: &str) -> bool {
+        let lock = self.lock().unwrap();
+        lock.services.has_live_host_under(name)
+    }
+    fn txt(&self, name: &str) -> Vec<String> {
+        let store = self.lock().unwrap().challenge_store.clone();
+        store.txt(name)
+    }
+}
+
+/// DNS server answering queries from a `Zone`.
+pub struct DnsServer<Z> {
+    zone: Arc<Z>,
+    /// Addresses of the load balancer.
+    addrs: Vec<IpAddr>,
+    matcher: AddressMatcher,
+    /// Upstream server for names not in the address table.
+    forwarder: Option<Forwarder>,
+    /// Executor to spawn query handling tasks on.
+    executor: Arc<dyn Executor>,
+    /// Number of queries and connections currently handled.
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Holds one of the `MAX_IN_FLIGHT` slots until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn acquire(count: &Arc<AtomicUsize>) -> Option<InFlight> {
+        let prev = count.fetch_add(1, Ordering::SeqCst);
+        // the slot is released again when dropped, also when over the limit.
+        let slot = InFlight(count.clone());
+        if prev < MAX_IN_FLIGHT {
+            Some(slot)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<Z: Zone> DnsServer<Z> {
+    /// Create a new DNS server. Tasks are spawned on the default tokio executor unless
+    /// another is set using `set_executor`.
+    pub fn new(zone: Arc<Z>, config: &DnsConfig) -> LolbResult<Self> {
+        Ok(DnsServer {
+            zone,
+            addrs: config.addrs.clone(),
+            matcher: AddressMatcher::new(&config.address)?,
+            forwarder: config.upstream.map(Forwarder::new),
+            executor: Arc::new(TokioExecutor),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Set the executor to spawn query handling tasks on.
+    pub fn set_executor<E: Executor + 'static>(&mut self, executor: E) {
+        self.executor = Arc::new(executor);
+    }
+
+    /// Make the response to a request.
+    pub async fn handle(&self, request: &DnsPacket) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = request.header.id;
+        packet.header.recursion_desired = true;
+        packet.header.recursion_available = true;
+        packet.header.response = true;
+
+        // tell EDNS0 clients how large responses we can receive.
+        if request.edns_udp_size().is_some() {
+            packet.resources.push(DnsRecord::OPT {
+                udp_size: MAX_EDNS_SIZE as u16,
+                flags: 0,
+                options: vec![],
+            });
+        }
+
+        if request.questions.is_empty() {
+            packet.header.rescode = ResultCode::FORMERR;
+            return packet;
+        }
+
+        let question = &request.questions[0];
+        debug!("Received query: {:?}", question);
+        packet.questions.push(question.clone());
+
+        if self.zone.is_authority(&question.name) {
+            self.authoritative(question, &mut packet);
+            return packet;
+        }
+
+        let result = match lookup_regexp_hack(&question.name, question.qtype, &self.matcher) {
+            Some(x) => x,
+            None => match &self.forwarder {
+                Some(forwarder) => match forwarder.lookup(question).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        debug!("Failed to forward query: {:?}", e);
+                        packet.header.rescode = ResultCode::SERVFAIL;
+                        return packet;
+                    }
+                },
+                None => {
+                    debug!("No address for query name: {}", question.name);
+                    packet.header.rescode = ResultCode::SERVFAIL;
+                    return packet;
+                }
+            },
+        };
+        packet.header.rescode = result.header.rescode;
+        packet.answers.extend(result.answers);
+        packet.authorities.extend(result.authorities);
+        packet.resources.extend(result.resources);
+
+        packet
+    }
+
+    /// Answer a question for a name in the zone.
+    fn authoritative(&self, question: &DnsQuestion, packet: &mut DnsPacket) {
+        packet.header.authoritative_answer = true;
+        let name = &question.name;
+        let zone = self.zone.zone_of(name);
+        let apex = zone.as_ref().filter(|z| z.eq_ignore_ascii_case(name));
+
+        let negative_ttl = if name.starts_with(DNS_ACME_CHALLENGE) {
+            let txt = self.zone.txt(name);
+            if txt.is_empty() {
+                packet.header.rescode = ResultCode::NXDOMAIN;
+            } else if question.qtype == QueryType::TXT {
+                for value in txt {
+                    packet.answers.push(DnsRecord::TXT {
+                        domain: name.clone(),
+                        data: vec![value.into_bytes()],
+                        ttl: 0,
+                    });
+                }
+            }
+            // the challenge is about to be published, and must not be cached as missing.
+            0
+        } else if let Some(zone) = apex.filter(|_| question.qtype == QueryType::SOA) {
+            packet.answers.push(zone_soa(zone, HOST_TTL));
+            HOST_TTL
+        } else if let Some(zone) = apex.filter(|_| question.qtype == QueryType::NS) {
+            packet.answers.push(DnsRecord::NS {
+                domain: zone.clone(),
+                host: zone.clone(),
+                ttl: SOA_TTL,
+            });
+            HOST_TTL
+        } else if !self.zone.is_live_host(name) {
+            // hosts without live connections don't exist, but the apex and names with
+            // live hosts under them do, without any records of the type.
+            if apex.is_none() && !self.zone.has_live_host_under(name) {
+                packet.header.rescode = ResultCode::NXDOMAIN;
+            }
+            HOST_TTL
+        } else {
+            // other query types get an empty answer, the name exists.
+            for addr in &self.addrs {
+                match (question.qtype, addr) {
+                    (QueryType::A, IpAddr::V4(addr)) => packet.answers.push(DnsRecord::A {
+                        domain: name.clone(),
+                        addr: *addr,
+                        ttl: HOST_TTL,
+                    }),
+                    (QueryType::AAAA, IpAddr::V6(addr)) => packet.answers.push(DnsRecord::AAAA {
+                        domain: name.clone(),
+                        addr: *addr,
+                        ttl: HOST_TTL,
+                    }),
+                    _ => {}
+                }
+            }
+            HOST_TTL
+        };
+
+        // negative responses, NXDOMAIN or no records of the type, have the SOA of the zone
+        // in the authority section to be cached (RFC 2308).
+        if packet.answers.is_empty() {
+            if let Some(zone) = &zone {
+                packet.authorities.push(zone_soa(zone, negative_ttl));
+            }
+        }
+    }
+}
+
+/// The SOA record of a zone. `minimum` is for how long negative responses are cached.
+fn zone_soa(zone: &str, minimum: u32) -> DnsRecord {
+    DnsRecord::SOA {
+        domain: zone.to_string(),
+        mname: zone.to_string(),
+        rname: format!("hostmaster.{}", zone),
+        serial: 1,
+        refresh: 7200,
+        retry: 3600,
+        expire: 1_209_600,
+        minimum,
+        ttl: SOA_TTL.min(minimum),
+    }
+}
+
+impl<Z: Zone> DnsServer<Z> {
+    /// Make the response to a request in wire format. Over UDP, a response larger than
+    /// the client can receive is truncated, and the client retries over TCP. Requests
+    /// that can't be parsed get no response.
+    pub async fn respond(&self, request: Vec<u8>, udp: bool) -> Option<Vec<u8>> {
+        let mut req_buffer = BytePacketBuffer::from_vec(request);
+        let request = match DnsPacket::from_buffer(&mut req_buffer) {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("Failed to parse query packet: {:?}", e);
+                return None;
+            }
+        };
+
+        let mut packet = self.handle(&request).await;
+
+        let mut res_buffer = BytePacketBuffer::new();
+        if let Err(e) = packet.write(&mut res_buffer) {
+            warn!("Failed to encode response packet: {:?}", e);
+            return None;
+        }
+
+        // EDNS0 clients can receive more than the plain 512 bytes.
+        let max_size = request
+            .edns_udp_size()
+            .map(|s| s as usize)
+            .unwrap_or(MAX_UDP_SIZE)
+            .clamp(MAX_UDP_SIZE, MAX_EDNS_SIZE);
+
+        if udp && res_buffer.pos() > max_size {
+            debug!("Truncating response of {} bytes", res_buffer.pos());
+            res_buffer = BytePacketBuffer::new();
+            if let Err(e) = packet.truncated().write(&mut res_buffer) {
+                warn!("Failed to encode truncated response packet: {:?}", e);
+                return None;
+            }
+        }
+
+        Some(res_buffer.into_vec())
+    }
+
+    /// Serve queries of one TCP connection until the client closes it, or it is idle.
+    async fn serve_tcp_conn<S>(&self, mut stream: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            let request = match Timeout::new(read_frame(&mut stream), TCP_IDLE_TIMEOUT).await {
+                Ok(x) => x?,
+                // idle for too long.
+                Err(_) => return Ok(()),
+            };
+            let request = match request {
+                Some(x) => x,
+                None => return Ok(()),
+            };
+            let response = match self.respond(request, false).await {
+                Some(x) => x,
+                None => return Ok(()),
+            };
+            write_frame(&mut stream, &response).await?;
+        }
+    }
+}
+
+impl<Z: Zone + 'static> DnsServer<Z> {
+    /// Serve DNS over UDP. This future runs forever.
+    pub async fn serve_udp(self: Arc<Self>, socket: UdpSocket) -> LolbResult<()> {
+        let (mut recv, mut send) = socket.split();
+
+        // the send half can't be shared, responses are sent from one task.
+        let (tx, mut rx) = mpsc::channel::<(SocketAddr, Vec<u8>)>(MAX_IN_FLIGHT);
+        self.executor.spawn(Box::pin(async move {
+            while let Some((src, response)) = rx.recv().await {
+                if let Err(e) = send.send_to(&response, &src).await {
+                    debug!("Failed to send response buffer: {:?}", e);
+                }
+            }
+        }));
+
+        loop {
+            let mut request = vec![0; MAX_EDNS_SIZE];
+            let (len, src) = match recv.recv_from(&mut request).await {
+                Ok(x) => x,
+                Err(e) => {
+                    debug!("Failed to read from UDP socket: {:?}", e);
+                    continue;
+                }
+            };
+            request.truncate(len);
+
+            let in_flight = match InFlight::acquire(&self.in_flight) {
+                Some(x) => x,
+                None => {
+                    debug!("Dropping UDP query, too many in flight");
+                    continue;
+                }
+            };
+
+            let server = self.clone();
+            let mut tx = tx.clone();
+            self.executor.spawn(Box::pin(async move {
+                let _in_flight = in_flight;
+                if let Some(response) = server.respond(request, true).await {
+                    // fails only if the sending task is gone.
+                    tx.send((src, response)).await.ok();
+                }
+            }));
+        }
+    }
+
+    /// Serve DNS over TCP. This future runs forever.
+    pub async fn serve_tcp(self: Arc<Self>, mut listener: TcpListener) -> LolbResult<()> {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => {
+                    debug!("Failed to accept TCP connection: {:?}", e);
+                    continue;
+                }
+            };
+
+            let in_flight = match InFlight::acquire(&self.in_flight) {
+                Some(x) => x,
+                None => {
+                    debug!("Dropping TCP connection, too many in flight");
+                    continue;
+                }
+            };
+
+            let server = self.clone();
+            self.executor.spawn(Box::pin(async move {
+                let _in_flight = in_flight;
+                if let Err(e) = server.serve_tcp_conn(stream).await {
+                    debug!("DNS TCP connection failed: {:?}", e);
+                }
+            }));
+        }
+    }
+}
+
+/// Read a message prefixed with its 16 bit length. Gives `None` if the stream ends
+/// before the next message.
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 2];
+    match stream.read_exact(&mut len).await {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        r => r?,
+    };
+    let mut message = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+/// Write a message prefixed with its 16 bit length.
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, message: &[u8]) -> io::Result<()> {
+    stream
+        .write_all(&(message.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(message).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acme::{ChallengeSink, ChallengeStore};
+    use futures_executor::block_on;
+    use std::collections::BTreeMap;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    struct TestZone(ChallengeStore);
+
+    impl Zone for TestZone {
+        fn is_authority(&self, name: &str) -> bool {
+            name == "example.com" || name.ends_with(".example.com")
+        }
+        fn zone_of(&self, name: &str) -> Option<String> {
+            if self.is_authority(name) {
+                Some("example.com".into())
+            } else {
+                None
+            }
+        }
+        fn is_live_host(&self, name: &str) -> bool {
+            name == "live.example.com" || name == "a.b.example.com"
+        }
+        fn has_live_host_under(&self, name: &str) -> bool {
+            name == "example.com" || name == "b.example.com"
+        }
+        fn txt(&self, name: &str) -> Vec<String> {
+            self.0.txt(name)
+        }
+    }
+
+    fn server() -> DnsServer<TestZone> {
+        let store = ChallengeStore::default();
+        store.publish_dns("_acme-challenge.example.com", "proof");
+        // too big for a plain UDP response.
+        for i in 0..BIG_COUNT {
+            store.publish_dns(BIG_NAME, &format!("{:050}", i));
+        }
+        let config = DnsConfig {
+            addrs: vec!["10.0.0.1".parse().unwrap(), "::1".parse().unwrap()],
+            address: BTreeMap::new(),
+            upstream: None,
+        };
+        DnsServer::new(Arc::new(TestZone(store)), &config).unwrap()
+    }
+
+    const BIG_NAME: &str = "_acme-challenge.big.example.com";
+    const BIG_COUNT: usize = 30;
+
+    fn request(name: &str, qtype: QueryType, edns: Option<u16>) -> Vec<u8> {
+        let mut request = DnsPacket::new();
+        request.header.id = 4711;
+        request
+            .questions
+            .push(DnsQuestion::new(name.to_string(), qtype));
+        if let Some(udp_size) = edns {
+            request.resources.push(DnsRecord::OPT {
+                udp_size,
+                flags: 0,
+                options: vec![],
+            });
+        }
+        let mut buffer = BytePacketBuffer::new();
+        request.write(&mut buffer).unwrap();
+        buffer.into_vec()
+    }
+
+    fn parse(bytes: Vec<u8>) -> DnsPacket {
+        DnsPacket::from_buffer(&mut BytePacketBuffer::from_vec(bytes)).unwrap()
+    }
+
+    /// Query the server, going through the wire format both ways.
+    fn query(server: &DnsServer<TestZone>, name: &str, qtype: QueryType) -> DnsPacket {
+        parse(block_on(server.respond(request(name, qtype, None), true)).unwrap())
+    }
+
+    #[test]
+    fn test_acme_challenge() {
+        let server = server();
+        let res = query(&server, "_acme-challenge.example.com", QueryType::TXT);
+        assert_eq!(res.header.id, 4711);
+        assert!(res.header.response);
+        assert!(res.header.authoritative_answer);
+        assert_eq!(res.header.rescode, ResultCode::NOERROR);
+        assert_eq!(
+            res.answers,
+            vec![DnsRecord::TXT {
+                domain: "_acme-challenge.example.com".into(),
+                data: vec![b"proof".to_vec()],
+                ttl: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_no_challenge() {
+        let server = server();
+        let res = query(&server, "_acme-challenge.a.example.com", QueryType::TXT);
+        assert!(res.header.authoritative_answer);
+        assert_eq!(res.header.rescode, ResultCode::NXDOMAIN);
+        assert!(res.answers.is_empty());
+        assert_eq!(res.authorities, vec![zone_soa("example.com", 0)]);
+
+        // the challenge exists, but not with the type.
+        let res = query(&server, "_acme-challenge.example.com", QueryType::A);
+        assert_eq!(res.header.rescode, ResultCode::NOERROR);
+        assert!(res.answers.is_empty());
+        assert_eq!(res.authorities, vec![zone_soa("example.com", 0)]);
+    }
+
+    #[test]
+    fn test_not_authority() {
+        let server = server();
+        let res = query(&server, "_acme-challenge.example.org", QueryType::TXT);
+        assert!(!res.header.authoritative_answer);
+        assert!(res.answers.is_empty());
+    }
+
+    #[test]
+    fn test_live_host() {
+        let server = server();
+        let res = query(&server, "live.example.com", QueryType::A);
+        assert!(res.header.authoritative_answer);
+        assert_eq!(res.header.rescode, ResultCode::NOERROR);
+        assert_eq!(
+            res.answers,
+            vec![DnsRecord::A {
+                domain: "live.example.com".into(),
+                addr: "10.0.0.1".parse().unwrap(),
+                ttl: HOST_TTL,
+            }]
+        );
+
+        let res = query(&server, "LIVE.example.com", QueryType::AAAA);
+        assert_eq!(
+            res.answers,
+            vec![DnsRecord::AAAA {
+                domain: "live.example.com".into(),
+                addr: "::1".parse().unwrap(),
+                ttl: HOST_TTL,
+            }]
+        );
+
+        assert!(res.authorities.is_empty());
+
+        let res = query(&server, "live.example.com", QueryType::MX);
+        assert_eq!(res.header.rescode, ResultCode::NOERROR);
+        assert!(res.answers.is_empty());
+        assert_eq!(res.authorities, vec![zone_soa("example.com", HOST_TTL)]);
+    }
+
+    #[test]
+    fn test_dead_host() {
+        let server = server();
+        let res = query(&server, "dead.example.com", QueryType::A);
+        assert!(res.header.authoritative_answer);
+        assert_eq!(res.header.rescode, ResultCode::NXDOMAIN);
+        assert!(res.answers.is_empty());
+        assert_eq!(
+            res.authorities,
+            vec![DnsRecord::SOA {
+                domain: "example.com".into(),
+                mname: "example.com".into(),
+                rname: "hostmaster.example.com".into(),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1_209_600,
+                minimum: HOST_TTL,
+                ttl: HOST_TTL,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_apex() {
+        let server = server();
+        let res = query(&server, "example.com", QueryType::SOA);
+        assert!(res.header.authoritative_answer);
+        assert_eq!(res.header.rescode, ResultCode::NOERROR);
+        assert_eq!(res.answers, vec![zone_soa("example.com", HOST_TTL)]);
+        assert!(res.authorities.is_empty());
+
+        let res = query(&server, "EXAMPLE.com", QueryType::NS);
+        assert_eq!(res.header.rescode, ResultCode::NOERROR);
+        assert_eq!(
+            res.answers,
+            vec![DnsRecord::NS {
+                domain: "example.com".into(),
+                host: "example.com".into(),
+                ttl: SOA_TTL,
+            }]
+        );
+
+        // no service at the apex, but the name exists.
+        let res = query(&server, "example.com", QueryType::A);
+        assert_eq!(res.header.rescode, ResultCode::NOERROR);
+        assert!(res.answers.is_empty());
+        assert_eq!(res.authorities, vec![zone_soa("example.com", HOST_TTL)]);
+    }
+
+    #[test]
+    fn test_empty_non_terminal() {
+        let server = server();
+        let res = query(&server, "b.example.com", QueryType::A);
+        assert_eq!(res.header.rescode, ResultCode::NOERROR);
+        assert!(res.answers.is_empty());
+        assert_eq!(res.authorities, vec![zone_soa("example.com", HOST_TTL)]);
+
+        // only the apex has SOA and NS records.
+        let res = query(&server, "b.example.com", QueryType::SOA);
+        assert_eq!(res.header.rescode, ResultCode::NOERROR);
+        assert!(res.answers.is_empty());
+        let res = query(&server, "live.example.com", QueryType::NS);
+        assert!(res.answers.is_empty());
+
+        let res = query(&server, "c.b.example.com", QueryType::A);
+        assert_eq!(res.header.rescode, ResultCode::NXDOMAIN);
+    }
+
+    #[test]
+    fn test_edns_no_truncation() {
+        let server = server();
+        let res = block_on(server.respond(request(BIG_NAME, QueryType::TXT, Some(4096)), true));
+        let res = parse(res.unwrap());
+        assert!(!res.header.truncated_message);
+        assert_eq!(res.answers.len(), BIG_COUNT);
+        assert_eq!(res.edns_udp_size(), Some(MAX_EDNS_SIZE as u16));
+    }
+
+    /// In memory TCP connection.
+    struct Duplex {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl AsyncRead for Duplex {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().input).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Duplex {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().output).poll_write(cx, buf)
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_truncate_and_retry_tcp() {
+        let server = server();
+
+        let res = block_on(server.respond(request(BIG_NAME, QueryType::TXT, None), true));
+        let res = res.unwrap();
+        assert!(res.len() <= MAX_UDP_SIZE);
+        let res = parse(res);
+        assert!(res.header.truncated_message);
+        assert_eq!(res.questions.len(), 1);
+        assert!(res.answers.is_empty());
+
+        // retry over tcp, twice on the same connection.
+        let mut input = vec![];
+        for _ in 0..2 {
+            let req = request(BIG_NAME, QueryType::TXT, None);
+            block_on(write_frame(&mut input, &req)).unwrap();
+        }
+        let mut conn = Duplex {
+            input: io::Cursor::new(input),
+            output: vec![],
+        };
+        block_on(server.serve_tcp_conn(&mut conn)).unwrap();
+
+        let mut output = &conn.output[..];
+        for _ in 0..2 {
+            let res = block_on(read_frame(&mut output)).unwrap().unwrap();
+            assert!(res.len() > MAX_UDP_SIZE);
+            let res = parse(res);
+            assert!(!res.header.truncated_message);
+            assert_eq!(res.answers.len(), BIG_COUNT);
+        }
+        assert!(block_on(read_frame(&mut output)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let slots: Vec<_> = (0..MAX_IN_FLIGHT)
+            .map(|_| InFlight::acquire(&count).unwrap())
+            .collect();
+        assert!(InFlight::acquire(&count).is_none());
+        assert_eq!(count.load(Ordering::SeqCst), MAX_IN_FLIGHT);
+        drop(slots);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert!(InFlight::acquire(&count).is_some());
+    }
+}
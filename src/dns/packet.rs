@@ -0,0 +1,1266 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Max size of a DNS packet over UDP.
+pub const MAX_UDP_SIZE: usize = 512;
+
+/// Max size of a DNS packet over UDP when the client supports EDNS0 with a larger size.
+pub const MAX_EDNS_SIZE: usize = 4096;
+
+/// Max size of a DNS packet over TCP, limited by the 16 bit length prefix.
+pub const MAX_TCP_SIZE: usize = 65535;
+
+/// Max length of a name in wire format.
+const MAX_NAME_LEN: usize = 255;
+
+/// Max length of a label in a name.
+const MAX_LABEL_LEN: u8 = 63;
+
+/// Max number of compression pointers followed when reading a name.
+const MAX_JUMPS: usize = 5;
+
+/// Buffer for reading and writing packets. Writing grows the buffer up
+/// to `MAX_TCP_SIZE`.
+pub struct BytePacketBuffer {
+    pub buf: Vec<u8>,
+    pub pos: usize,
+}
+
+impl Default for BytePacketBuffer {
+    fn default() -> Self {
+        BytePacketBuffer::new()
+    }
+}
+
+impl BytePacketBuffer {
+    /// Empty buffer to write a packet to.
+    pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer::from_vec(Vec::with_capacity(MAX_UDP_SIZE))
+    }
+
+    /// Zeroed buffer of `size` bytes to receive a packet into.
+    pub fn with_size(size: usize) -> BytePacketBuffer {
+        BytePacketBuffer::from_vec(vec![0; size])
+    }
+
+    /// Buffer to read a received packet from.
+    pub fn from_vec(buf: Vec<u8>) -> BytePacketBuffer {
+        BytePacketBuffer { buf, pos: 0 }
+    }
+
+    /// The bytes up to the current position, which is the written packet.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.buf.truncate(self.pos);
+        self.buf
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    fn step(&mut self, steps: usize) -> Result<()> {
+        self.pos += steps;
+
+        Ok(())
+    }
+
+    fn seek(&mut self, pos: usize) -> Result<()> {
+        self.pos = pos;
+
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<u8> {
+        if self.pos >= self.buf.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        }
+        let res = self.buf[self.pos];
+        self.pos += 1;
+
+        Ok(res)
+    }
+
+    fn get(&mut self, pos: usize) -> Result<u8> {
+        if pos >= self.buf.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        }
+        Ok(self.buf[pos])
+    }
+
+    pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+        if start + len > self.buf.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        }
+        Ok(&self.buf[start..start + len])
+    }
+
+    fn read_u16(&mut self) -> Result<u16> {
+        let res = ((self.read()? as u16) << 8) | (self.read()? as u16);
+
+        Ok(res)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let res = ((self.read()? as u32) << 24)
+            | ((self.read()? as u32) << 16)
+            | ((self.read()? as u32) << 8)
+            | (self.read()? as u32);
+
+        Ok(res)
+    }
+
+    fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
+        let mut pos = self.pos();
+        let mut jumped = false;
+        let mut jumps = 0;
+        // length of the name in wire format, including the terminating empty label.
+        let mut name_len = 1;
+
+        let mut delim = "";
+        loop {
+            let len = self.get(pos)?;
+
+            // A two byte sequence, where the two highest bits of the first byte is
+            // set, represents a offset relative to the start of the buffer. We
+            // handle this by jumping to the offset, setting a flag to indicate
+            // that we shouldn't update the shared buffer position once done.
+            if (len & 0xC0) == 0xC0 {
+                // When a jump is performed, we only modify the shared buffer
+                // position once, and avoid making the change later on.
+                if !jumped {
+                    self.seek(pos + 2)?;
+                }
+
+                let b2 = self.get(pos + 1)? as u16;
+                let offset = ((((len & 0x3F) as u16) << 8) | b2) as usize;
+
+                // only pointing back to earlier names means there can be no loops, and
+                // the jumps are limited to not spend time on silly packets.
+                jumps += 1;
+                if offset >= pos || jumps > MAX_JUMPS {
+                    return Err(Error::new(ErrorKind::InvalidInput, "Bad name pointer"));
+                }
+
+                pos = offset;
+                jumped = true;
+                continue;
+            }
+
+            if len > MAX_LABEL_LEN {
+                return Err(Error::new(ErrorKind::InvalidInput, "Too long label"));
+            }
+
+            pos += 1;
+
+            // Names are terminated by an empty label of length 0
+            if len == 0 {
+                break;
+            }
+
+            name_len += len as usize + 1;
+            if name_len > MAX_NAME_LEN {
+                return Err(Error::new(ErrorKind::InvalidInput, "Too long name"));
+            }
+
+            outstr.push_str(delim);
+
+            let str_buffer = self.get_range(pos, len as usize)?;
+            outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+
+            delim = ".";
+
+            pos += len as usize;
+        }
+
+        if !jumped {
+            self.seek(pos)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a length prefixed character string.
+    fn read_character_string(&mut self) -> Result<Vec<u8>> {
+        let len = self.read()? as usize;
+        let s = self.get_range(self.pos, len)?.to_vec();
+        self.step(len)?;
+        Ok(s)
+    }
+
+    fn write(&mut self, val: u8) -> Result<()> {
+        if self.pos >= MAX_TCP_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        }
+        if self.pos >= self.buf.len() {
+            self.buf.resize(self.pos + 1, 0);
+        }
+        self.buf[self.pos] = val;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn write_u8(&mut self, val: u8) -> Result<()> {
+        self.write(val)?;
+
+        Ok(())
+    }
+
+    fn write_u16(&mut self, val: u16) -> Result<()> {
+        self.write((val >> 8) as u8)?;
+        self.write((val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    fn write_u32(&mut self, val: u32) -> Result<()> {
+        self.write(((val >> 24) & 0xFF) as u8)?;
+        self.write(((val >> 16) & 0xFF) as u8)?;
+        self.write(((val >> 8) & 0xFF) as u8)?;
+        self.write((val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    fn write_qname(&mut self, qname: &str) -> Result<()> {
+        // the root name, "", is only the terminating empty label.
+        let split_str = qname.split('.').filter(|l| !l.is_empty());
+
+        for label in split_str {
+            let len = label.len();
+            if len > 63 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Single label exceeds 63 characters of length",
+                ));
+            }
+
+            self.write_u8(len as u8)?;
+            for b in label.as_bytes() {
+                self.write_u8(*b)?;
+            }
+        }
+
+        self.write_u8(0)?;
+
+        Ok(())
+    }
+
+    /// Write a length prefixed character string.
+    fn write_character_string(&mut self, s: &[u8]) -> Result<()> {
+        if s.len() > 255 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Character string exceeds 255 bytes of length",
+            ));
+        }
+        self.write_u8(s.len() as u8)?;
+        for b in s {
+            self.write_u8(*b)?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        self.buf[pos] = val;
+
+        Ok(())
+    }
+
+    fn set_u16(&mut self, pos: usize, val: u16) -> Result<()> {
+        self.set(pos, (val >> 8) as u8)?;
+        self.set(pos + 1, (val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ResultCode {
+    #[default]
+    NOERROR = 0,
+    FORMERR = 1,
+    SERVFAIL = 2,
+    NXDOMAIN = 3,
+    NOTIMP = 4,
+    REFUSED = 5,
+}
+
+impl ResultCode {
+    pub fn from_num(num: u8) -> ResultCode {
+        match num {
+            1 => ResultCode::FORMERR,
+            2 => ResultCode::SERVFAIL,
+            3 => ResultCode::NXDOMAIN,
+            4 => ResultCode::NOTIMP,
+            5 => ResultCode::REFUSED,
+            _ => ResultCode::NOERROR,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DnsHeader {
+    pub id: u16, // 16 bits
+
+    pub recursion_desired: bool,    // 1 bit
+    pub truncated_message: bool,    // 1 bit
+    pub authoritative_answer: bool, // 1 bit
+    pub opcode: u8,                 // 4 bits
+    pub response: bool,             // 1 bit
+
+    pub rescode: ResultCode,       // 4 bits
+    pub checking_disabled: bool,   // 1 bit
+    pub authed_data: bool,         // 1 bit
+    pub z: bool,                   // 1 bit
+    pub recursion_available: bool, // 1 bit
+
+    pub questions: u16,             // 16 bits
+    pub answers: u16,               // 16 bits
+    pub authoritative_entries: u16, // 16 bits
+    pub resource_entries: u16,      // 16 bits
+}
+
+impl DnsHeader {
+    pub fn new() -> DnsHeader {
+        DnsHeader {
+            ..Default::default()
+        }
+    }
+
+    pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        self.id = buffer.read_u16()?;
+
+        let flags = buffer.read_u16()?;
+        let a = (flags >> 8) as u8;
+        let b = (flags & 0xFF) as u8;
+        self.recursion_desired = (a & 1) > 0;
+        self.truncated_message = (a & (1 << 1)) > 0;
+        self.authoritative_answer = (a & (1 << 2)) > 0;
+        self.opcode = (a >> 3) & 0x0F;
+        self.response = (a & (1 << 7)) > 0;
+
+        self.rescode = ResultCode::from_num(b & 0x0F);
+        self.checking_disabled = (b & (1 << 4)) > 0;
+        self.authed_data = (b & (1 << 5)) > 0;
+        self.z = (b & (1 << 6)) > 0;
+        self.recursion_available = (b & (1 << 7)) > 0;
+
+        self.questions = buffer.read_u16()?;
+        self.answers = buffer.read_u16()?;
+        self.authoritative_entries = buffer.read_u16()?;
+        self.resource_entries = buffer.read_u16()?;
+
+        // Return the constant header size
+        Ok(())
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.write_u16(self.id)?;
+
+        buffer.write_u8(
+            (self.recursion_desired as u8)
+                | ((self.truncated_message as u8) << 1)
+                | ((self.authoritative_answer as u8) << 2)
+                | (self.opcode << 3)
+                | ((self.response as u8) << 7),
+        )?;
+
+        buffer.write_u8(
+            (self.rescode as u8)
+                | ((self.checking_disabled as u8) << 4)
+                | ((self.authed_data as u8) << 5)
+                | ((self.z as u8) << 6)
+                | ((self.recursion_available as u8) << 7),
+        )?;
+
+        buffer.write_u16(self.questions)?;
+        buffer.write_u16(self.answers)?;
+        buffer.write_u16(self.authoritative_entries)?;
+        buffer.write_u16(self.resource_entries)?;
+
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+pub enum QueryType {
+    UNKNOWN(u16),
+    A,     // 1
+    NS,    // 2
+    CNAME, // 5
+    SOA,   // 6
+    MX,    // 15
+    TXT,   // 16
+    AAAA,  // 28
+    SRV,   // 33
+    OPT,   // 41
+    CAA,   // 257
+}
+
+impl QueryType {
+    pub fn to_num(self) -> u16 {
+        match self {
+            QueryType::UNKNOWN(x) => x,
+            QueryType::A => 1,
+            QueryType::NS => 2,
+            QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::MX => 15,
+            QueryType::TXT => 16,
+            QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::OPT => 41,
+            QueryType::CAA => 257,
+        }
+    }
+
+    pub fn from_num(num: u16) -> QueryType {
+        match num {
+            1 => QueryType::A,
+            2 => QueryType::NS,
+            5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            15 => QueryType::MX,
+            16 => QueryType::TXT,
+            28 => QueryType::AAAA,
+            33 => QueryType::SRV,
+            41 => QueryType::OPT,
+            257 => QueryType::CAA,
+            _ => QueryType::UNKNOWN(num),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsQuestion {
+    pub name: String,
+    pub qtype: QueryType,
+}
+
+impl DnsQuestion {
+    pub fn new(name: String, qtype: QueryType) -> DnsQuestion {
+        DnsQuestion { name, qtype }
+    }
+
+    pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.read_qname(&mut self.name)?;
+        self.qtype = QueryType::from_num(buffer.read_u16()?); // qtype
+        let _ = buffer.read_u16()?; // class
+
+        Ok(())
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.write_qname(&self.name)?;
+
+        let typenum = self.qtype.to_num();
+        buffer.write_u16(typenum)?;
+        buffer.write_u16(1)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DnsRecord {
+    UNKNOWN {
+        domain: String,
+        qtype: u16,
+        data_len: u16,
+        ttl: u32,
+    }, // 0
+    A {
+        domain: String,
+        addr: Ipv4Addr,
+        ttl: u32,
+    }, // 1
+    NS {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 2
+    CNAME {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 5
+    SOA {
+        domain: String,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    MX {
+        domain: String,
+        priority: u16,
+        host: String,
+        ttl: u32,
+    }, // 15
+    TXT {
+        domain: String,
+        /// Each string is at most 255 bytes.
+        data: Vec<Vec<u8>>,
+        ttl: u32,
+    }, // 16
+    AAAA {
+        domain: String,
+        addr: Ipv6Addr,
+        ttl: u32,
+    }, // 28
+    SRV {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: String,
+        ttl: u32,
+    }, // 33
+    /// EDNS0 pseudo record. Only valid in the additional section.
+    OPT {
+        /// Max UDP payload size of the sender. This is the class of the record.
+        udp_size: u16,
+        /// Extended rcode, version and flags. This is the ttl of the record.
+        flags: u32,
+        /// Options, as is.
+        options: Vec<u8>,
+    }, // 41
+    CAA {
+        domain: String,
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+        ttl: u32,
+    }, // 257
+}
+
+impl DnsRecord {
+    /// The TTL of the record. `None` for the OPT pseudo record.
+    pub fn ttl(&self) -> Option<u32> {
+        match *self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::CAA { ttl, .. } => Some(ttl),
+            DnsRecord::OPT { .. } => None,
+        }
+    }
+
+    /// Set the TTL of the record. Does nothing for the OPT pseudo record.
+    pub fn set_ttl(&mut self, value: u32) {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::CAA { ttl, .. } => *ttl = value,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord> {
+        let mut domain = String::new();
+        buffer.read_qname(&mut domain)?;
+
+        let qtype_num = buffer.read_u16()?;
+        let qtype = QueryType::from_num(qtype_num);
+        let class = buffer.read_u16()?;
+        let ttl = buffer.read_u32()?;
+        let data_len = buffer.read_u16()?;
+
+        match qtype {
+            QueryType::A => {
+                let raw_addr = buffer.read_u32()?;
+                let addr = Ipv4Addr::new(
+                    ((raw_addr >> 24) & 0xFF) as u8,
+                    ((raw_addr >> 16) & 0xFF) as u8,
+                    ((raw_addr >> 8) & 0xFF) as u8,
+                    (raw_addr & 0xFF) as u8,
+                );
+
+                Ok(DnsRecord::A { domain, addr, ttl })
+            }
+            QueryType::AAAA => {
+                let raw_addr1 = buffer.read_u32()?;
+                let raw_addr2 = buffer.read_u32()?;
+                let raw_addr3 = buffer.read_u32()?;
+                let raw_addr4 = buffer.read_u32()?;
+                let addr = Ipv6Addr::new(
+                    ((raw_addr1 >> 16) & 0xFFFF) as u16,
+                    (raw_addr1 & 0xFFFF) as u16,
+                    ((raw_addr2 >> 16) & 0xFFFF) as u16,
+                    (raw_addr2 & 0xFFFF) as u16,
+                    ((raw_addr3 >> 16) & 0xFFFF) as u16,
+                    (raw_addr3 & 0xFFFF) as u16,
+                    ((raw_addr4 >> 16) & 0xFFFF) as u16,
+                    (raw_addr4 & 0xFFFF) as u16,
+                );
+
+                Ok(DnsRecord::AAAA { domain, addr, ttl })
+            }
+            QueryType::NS => {
+                let mut ns = String::new();
+                buffer.read_qname(&mut ns)?;
+
+                Ok(DnsRecord::NS {
+                    domain,
+                    host: ns,
+                    ttl,
+                })
+            }
+            QueryType::CNAME => {
+                let mut cname = String::new();
+                buffer.read_qname(&mut cname)?;
+
+                Ok(DnsRecord::CNAME {
+                    domain,
+                    host: cname,
+                    ttl,
+                })
+            }
+            QueryType::MX => {
+                let priority = buffer.read_u16()?;
+                let mut mx = String::new();
+                buffer.read_qname(&mut mx)?;
+
+                Ok(DnsRecord::MX {
+                    domain,
+                    priority,
+                    host: mx,
+                    ttl,
+                })
+            }
+            QueryType::TXT => {
+                let end = buffer.pos() + data_len as usize;
+                let mut data = vec![];
+                while buffer.pos() < end {
+                    data.push(buffer.read_character_string()?);
+                }
+
+                Ok(DnsRecord::TXT { domain, data, ttl })
+            }
+            QueryType::SOA => {
+                let mut mname = String::new();
+                buffer.read_qname(&mut mname)?;
+                let mut rname = String::new();
+                buffer.read_qname(&mut rname)?;
+
+                Ok(DnsRecord::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl,
+                })
+            }
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Ok(DnsRecord::SRV {
+                    domain,
+                    priority,
+                    weight,
+                    port,
+                    host,
+                    ttl,
+                })
+            }
+            QueryType::OPT => {
+                let options = buffer.get_range(buffer.pos(), data_len as usize)?.to_vec();
+                buffer.step(data_len as usize)?;
+
+                Ok(DnsRecord::OPT {
+                    udp_size: class,
+                    flags: ttl,
+                    options,
+                })
+            }
+            QueryType::CAA => {
+                let start = buffer.pos();
+                let flags = buffer.read()?;
+                let tag = buffer.read_character_string()?;
+                let tag = String::from_utf8_lossy(&tag).to_string();
+                let value_len = (data_len as usize)
+                    .checked_sub(buffer.pos() - start)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Bad CAA record"))?;
+                let value = buffer.get_range(buffer.pos(), value_len)?.to_vec();
+                buffer.step(value_len)?;
+
+                Ok(DnsRecord::CAA {
+                    domain,
+                    flags,
+                    tag,
+                    value,
+                    ttl,
+                })
+            }
+            QueryType::UNKNOWN(_) => {
+                buffer.step(data_len as usize)?;
+
+                Ok(DnsRecord::UNKNOWN {
+                    domain,
+                    qtype: qtype_num,
+                    data_len,
+                    ttl,
+                })
+            }
+        }
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
+        let start_pos = buffer.pos();
+
+        match *self {
+            DnsRecord::A {
+                ref domain,
+                ref addr,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::A.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4)?;
+
+                let octets = addr.octets();
+                buffer.write_u8(octets[0])?;
+                buffer.write_u8(octets[1])?;
+                buffer.write_u8(octets[2])?;
+                buffer.write_u8(octets[3])?;
+            }
+            DnsRecord::NS {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NS.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::CNAME {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::CNAME.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::MX {
+                ref domain,
+                priority,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::MX.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::TXT {
+                ref domain,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for s in data {
+                    buffer.write_character_string(s)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::AAAA {
+                ref domain,
+                ref addr,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::AAAA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(16)?;
+
+                for octet in &addr.segments() {
+                    buffer.write_u16(*octet)?;
+                }
+            }
+            DnsRecord::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SRV.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::OPT {
+                udp_size,
+                flags,
+                ref options,
+            } => {
+                buffer.write_qname("")?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(udp_size)?;
+                buffer.write_u32(flags)?;
+                buffer.write_u16(options.len() as u16)?;
+
+                for b in options {
+                    buffer.write_u8(*b)?;
+                }
+            }
+            DnsRecord::CAA {
+                ref domain,
+                flags,
+                ref tag,
+                ref value,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::CAA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u8(flags)?;
+                buffer.write_character_string(tag.as_bytes())?;
+                for b in value {
+                    buffer.write_u8(*b)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::UNKNOWN { .. } => {
+                debug!("Skipping record: {:?}", self);
+            }
+        }
+
+        Ok(buffer.pos() - start_pos)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DnsPacket {
+    pub header: DnsHeader,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+    pub resources: Vec<DnsRecord>,
+}
+
+impl DnsPacket {
+    pub fn new() -> DnsPacket {
+        DnsPacket {
+            ..Default::default()
+        }
+    }
+
+    pub fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<DnsPacket> {
+        let mut result = DnsPacket::new();
+        result.header.read(buffer)?;
+
+        for _ in 0..result.header.questions {
+            let mut question = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0));
+            question.read(buffer)?;
+            result.questions.push(question);
+        }
+
+        for _ in 0..result.header.answers {
+            let rec = DnsRecord::read(buffer)?;
+            result.answers.push(rec);
+        }
+        for _ in 0..result.header.authoritative_entries {
+            let rec = DnsRecord::read(buffer)?;
+            result.authorities.push(rec);
+        }
+        for _ in 0..result.header.resource_entries {
+            let rec = DnsRecord::read(buffer)?;
+            result.resources.push(rec);
+        }
+
+        Ok(result)
+    }
+
+    /// A copy of the packet with the answers left out and the truncated flag set. The
+    /// client is expected to retry the query over TCP.
+    pub fn truncated(&self) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header = self.header.clone();
+        packet.header.truncated_message = true;
+        packet.questions = self.questions.clone();
+        // the EDNS0 record is about the transport, and stays.
+        packet.resources = self
+            .resources
+            .iter()
+            .filter_map(|r| match r {
+                DnsRecord::OPT { .. } => Some(r.clone()),
+                _ => None,
+            })
+            .collect();
+        packet
+    }
+
+    /// The max UDP payload size of the sender, if it supports EDNS0.
+    pub fn edns_udp_size(&self) -> Option<u16> {
+        self.resources.iter().find_map(|r| match r {
+            DnsRecord::OPT { udp_size, .. } => Some(*udp_size),
+            _ => None,
+        })
+    }
+
+    pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        self.header.questions = self.questions.len() as u16;
+        self.header.answers = self.answers.len() as u16;
+        self.header.authoritative_entries = self.authorities.len() as u16;
+        self.header.resource_entries = self.resources.len() as u16;
+
+        self.header.write(buffer)?;
+
+        for question in &self.questions {
+            question.write(buffer)?;
+        }
+        for rec in &self.answers {
+            rec.write(buffer)?;
+        }
+        for rec in &self.authorities {
+            rec.write(buffer)?;
+        }
+        for rec in &self.resources {
+            rec.write(buffer)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SRV_RESPONSE: &[u8] = &[
+        0x1a, 0x2b, 0x85, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x5f, 0x73,
+        0x69, 0x70, 0x04, 0x5f, 0x74, 0x63, 0x70, 0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65,
+        0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x21, 0x00, 0x01, 0xc0, 0x0c, 0x00, 0x21, 0x00, 0x01,
+        0x00, 0x00, 0x0e, 0x10, 0x00, 0x0c, 0x00, 0x0a, 0x00, 0x3c, 0x13, 0xc4, 0x03, 0x73, 0x69,
+        0x70, 0xc0, 0x16, 0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const CAA_RESPONSE: &[u8] = &[
+        0x04, 0x07, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x07, 0x65, 0x78,
+        0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x01, 0x01, 0x00, 0x01, 0xc0,
+        0x0c, 0x01, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x16, 0x00, 0x05, 0x69, 0x73,
+        0x73, 0x75, 0x65, 0x6c, 0x65, 0x74, 0x73, 0x65, 0x6e, 0x63, 0x72, 0x79, 0x70, 0x74, 0x2e,
+        0x6f, 0x72, 0x67, 0xc0, 0x0c, 0x00, 0x06, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x27,
+        0x03, 0x6e, 0x73, 0x31, 0xc0, 0x0c, 0x0a, 0x68, 0x6f, 0x73, 0x74, 0x6d, 0x61, 0x73, 0x74,
+        0x65, 0x72, 0xc0, 0x0c, 0x78, 0x59, 0x32, 0x4d, 0x00, 0x00, 0x1c, 0x20, 0x00, 0x00, 0x0e,
+        0x10, 0x00, 0x12, 0x75, 0x00, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x00, 0x29, 0x10, 0x00, 0x00,
+        0x00, 0x80, 0x00, 0x00, 0x0c, 0x00, 0x0a, 0x00, 0x08, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
+        0x07, 0x08,
+    ];
+
+    const TXT_RESPONSE: &[u8] = &[
+        0xbe, 0xef, 0x84, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x5f, 0x61,
+        0x63, 0x6d, 0x65, 0x2d, 0x63, 0x68, 0x61, 0x6c, 0x6c, 0x65, 0x6e, 0x67, 0x65, 0x07, 0x65,
+        0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x10, 0x00, 0x01,
+        0xc0, 0x0c, 0x00, 0x10, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x05, 0x68, 0x65,
+        0x6c, 0x6c, 0x6f, 0x05, 0x77, 0x6f, 0x72, 0x6c, 0x64,
+    ];
+
+    fn parse(bytes: &[u8]) -> DnsPacket {
+        let mut buffer = BytePacketBuffer::from_vec(bytes.to_vec());
+        DnsPacket::from_buffer(&mut buffer).unwrap()
+    }
+
+    /// Write the packet and read it back.
+    fn round_trip(packet: &DnsPacket) {
+        let mut written = packet.clone();
+        let mut buffer = BytePacketBuffer::new();
+        written.write(&mut buffer).unwrap();
+        buffer.pos = 0;
+        let read = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(read.header.id, packet.header.id);
+        assert_eq!(read.header.rescode, packet.header.rescode);
+        assert_eq!(read.questions, packet.questions);
+        assert_eq!(read.answers, packet.answers);
+        assert_eq!(read.authorities, packet.authorities);
+        assert_eq!(read.resources, packet.resources);
+    }
+
+    #[test]
+    fn test_srv() {
+        let packet = parse(SRV_RESPONSE);
+        assert!(packet.header.response);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(
+            packet.questions,
+            vec![DnsQuestion::new(
+                "_sip._tcp.example.com".into(),
+                QueryType::SRV
+            )]
+        );
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::SRV {
+                domain: "_sip._tcp.example.com".into(),
+                priority: 10,
+                weight: 60,
+                port: 5060,
+                host: "sip.example.com".into(),
+                ttl: 3600,
+            }]
+        );
+        assert_eq!(packet.edns_udp_size(), Some(1232));
+        round_trip(&packet);
+    }
+
+    #[test]
+    fn test_caa_soa_opt() {
+        let packet = parse(CAA_RESPONSE);
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::CAA {
+                domain: "example.com".into(),
+                flags: 0,
+                tag: "issue".into(),
+                value: b"letsencrypt.org".to_vec(),
+                ttl: 300,
+            }]
+        );
+        assert_eq!(
+            packet.authorities,
+            vec![DnsRecord::SOA {
+                domain: "example.com".into(),
+                mname: "ns1.example.com".into(),
+                rname: "hostmaster.example.com".into(),
+                serial: 2_019_111_501,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1_209_600,
+                minimum: 300,
+                ttl: 300,
+            }]
+        );
+        assert_eq!(
+            packet.resources,
+            vec![DnsRecord::OPT {
+                udp_size: 4096,
+                flags: 0x8000,
+                options: vec![0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8],
+            }]
+        );
+        round_trip(&packet);
+    }
+
+    #[test]
+    fn test_txt() {
+        let packet = parse(TXT_RESPONSE);
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::TXT {
+                domain: "_acme-challenge.example.com".into(),
+                data: vec![b"hello".to_vec(), b"world".to_vec()],
+                ttl: 0,
+            }]
+        );
+        round_trip(&packet);
+    }
+
+    #[test]
+    fn test_label_length() {
+        let mut buffer = BytePacketBuffer::new();
+        let label = "a".repeat(63);
+        buffer.write_qname(&format!("{}.com", label)).unwrap();
+        buffer.pos = 0;
+        let mut name = String::new();
+        buffer.read_qname(&mut name).unwrap();
+        assert_eq!(name, format!("{}.com", label));
+
+        let label = "a".repeat(64);
+        assert!(buffer.write_qname(&format!("{}.com", label)).is_err());
+    }
+
+    #[test]
+    fn test_root_name() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("").unwrap();
+        assert_eq!(buffer.pos(), 1);
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut packet = DnsPacket::new();
+        for i in 0..100 {
+            packet.answers.push(DnsRecord::TXT {
+                domain: "example.com".into(),
+                data: vec![format!("value {}", i).into_bytes()],
+                ttl: 0,
+            });
+        }
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let bytes = buffer.into_vec();
+        assert!(bytes.len() > MAX_UDP_SIZE);
+
+        // the last byte of the buffer must be readable.
+        let read = parse(&bytes);
+        assert_eq!(read.answers, packet.answers);
+    }
+
+    #[test]
+    fn test_end_of_buffer() {
+        let mut buffer = BytePacketBuffer::from_vec(SRV_RESPONSE[..40].to_vec());
+        assert!(DnsPacket::from_buffer(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let packet = parse(CAA_RESPONSE).truncated();
+        assert!(packet.header.truncated_message);
+        assert_eq!(packet.questions.len(), 1);
+        assert!(packet.answers.is_empty());
+        assert!(packet.authorities.is_empty());
+        assert_eq!(packet.edns_udp_size(), Some(4096));
+        round_trip(&packet);
+    }
+
+    /// A query with one question for the name in wire format.
+    fn query_for(name: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        bytes.extend_from_slice(name);
+        bytes.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+        bytes
+    }
+
+    fn parse_name(name: &[u8]) -> Result<DnsPacket> {
+        let mut buffer = BytePacketBuffer::from_vec(query_for(name));
+        DnsPacket::from_buffer(&mut buffer)
+    }
+
+    #[test]
+    fn test_name_pointer_loop() {
+        // the name at offset 12 points to itself.
+        assert!(parse_name(&[0xc0, 0x0c]).is_err());
+        // a label followed by a pointer back to the label.
+        assert!(parse_name(&[0x01, b'a', 0xc0, 0x0c]).is_err());
+        // pointers must point back.
+        assert!(parse_name(&[0xc0, 0x0e, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_name_length() {
+        let label = [&[63][..], &[b'a'; 63][..]].concat();
+        let name = [&label[..], &label[..], &label[..], &[61], &[b'a'; 61], &[0]].concat();
+        assert_eq!(name.len(), 255);
+        assert_eq!(parse_name(&name).unwrap().questions[0].name.len(), 253);
+
+        let name = [&label[..], &label[..], &label[..], &label[..], &[0]].concat();
+        assert!(parse_name(&name).is_err());
+        // the high bits of a label length are only allowed for pointers.
+        assert!(parse_name(&[0x40, b'a', 0x00]).is_err());
+    }
+}
@@ -12,9 +12,58 @@ pub enum LolbError {
     H2(h2::Error),
     Http11Parse(httparse::Error),
     Http(http::Error),
+    /// Error that is answered to the client with a http status.
+    Status(StatusKind, &'static str),
 }
 use LolbError::*;
 
+/// Kinds of errors that are answered to the client with a http status
+/// rather than just dropping the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusKind {
+    /// The request is malformed.
+    BadRequest,
+    /// The request lacks valid authentication.
+    Unauthorized,
+    /// No service is configured for the requested host/path.
+    NotFound,
+    /// The request body is larger than the load balancer accepts.
+    PayloadTooLarge,
+    /// The request uses something the load balancer doesn't support.
+    NotImplemented,
+    /// The service failed to respond properly.
+    BadGateway,
+    /// There is a route, but no live service connections for it.
+    ServiceUnavailable,
+    /// The service didn't respond in time.
+    GatewayTimeout,
+}
+
+impl StatusKind {
+    pub fn status(self) -> http::StatusCode {
+        match self {
+            StatusKind::BadRequest => http::StatusCode::BAD_REQUEST,
+            StatusKind::Unauthorized => http::StatusCode::UNAUTHORIZED,
+            StatusKind::NotFound => http::StatusCode::NOT_FOUND,
+            StatusKind::PayloadTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+            StatusKind::NotImplemented => http::StatusCode::NOT_IMPLEMENTED,
+            StatusKind::BadGateway => http::StatusCode::BAD_GATEWAY,
+            StatusKind::ServiceUnavailable => http::StatusCode::SERVICE_UNAVAILABLE,
+            StatusKind::GatewayTimeout => http::StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
+
+impl LolbError {
+    /// The http status to respond to the client with, if this is such an error.
+    pub fn status(&self) -> Option<http::StatusCode> {
+        match self {
+            Status(kind, _) => Some(kind.status()),
+            _ => None,
+        }
+    }
+}
+
 impl std::error::Error for LolbError {}
 
 impl fmt::Display for LolbError {
@@ -27,6 +76,7 @@ impl fmt::Display for LolbError {
             H2(e) => write!(f, "h2: {}", e),
             Http11Parse(e) => write!(f, "http11parse: {}", e),
             Http(e) => write!(f, "http: {}", e),
+            Status(k, s) => write!(f, "{}: {}", k.status(), s),
         }
     }
 }
@@ -60,3 +110,36 @@ impl From<http::Error> for LolbError {
         LolbError::Http(e)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let kinds = [
+            (StatusKind::BadRequest, 400),
+            (StatusKind::Unauthorized, 401),
+            (StatusKind::NotFound, 404),
+            (StatusKind::PayloadTooLarge, 413),
+            (StatusKind::NotImplemented, 501),
+            (StatusKind::BadGateway, 502),
+            (StatusKind::ServiceUnavailable, 503),
+            (StatusKind::GatewayTimeout, 504),
+        ];
+        for (kind, code) in &kinds {
+            assert_eq!(kind.status().as_u16(), *code);
+            let err = Status(*kind, "x");
+            assert_eq!(err.status(), Some(kind.status()));
+            assert_eq!(err.to_string(), format!("{}: x", kind.status()));
+        }
+    }
+
+    #[test]
+    fn test_no_status() {
+        let io = io::Error::new(io::ErrorKind::ConnectionReset, "x");
+        assert_eq!(LolbError::from(io).status(), None);
+        assert_eq!(Message("x").status(), None);
+        assert_eq!(Owned("x".into()).status(), None);
+    }
+}
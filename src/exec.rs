@@ -0,0 +1,118 @@
+use std::future::Future;
+use std::pin::Pin;
+
+/// A future that can be spawned as an independent task.
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Trait for spawning tasks. Service connections and client connections are driven
+/// as independent tasks spawned using this.
+pub trait Executor: Send + Sync {
+    fn spawn(&self, task: Task);
+}
+
+/// Executor spawning on the default tokio executor.
+#[derive(Debug, Clone, Default)]
+pub struct TokioExecutor;
+
+impl Executor for TokioExecutor {
+    fn spawn(&self, task: Task) {
+        tokio_executor::spawn(task);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+
+    /// Executor for tests, running the tasks on a thread pool rather than on the runtime
+    /// of the test. Any task making progress has been spawned using the executor. The
+    /// tasks use the reactor and timer of the runtime the executor is created in.
+    #[derive(Clone)]
+    pub(crate) struct TestExecutor {
+        pool: futures_executor::ThreadPool,
+        reactor: tokio_net::driver::Handle,
+        timer: tokio_timer::timer::Handle,
+        spawned: Arc<AtomicUsize>,
+    }
+
+    impl TestExecutor {
+        /// Create an executor. This must be called within the runtime of the test.
+        #[allow(deprecated)]
+        pub fn new() -> Self {
+            TestExecutor {
+                pool: futures_executor::ThreadPool::new().expect("Failed to create thread pool"),
+                reactor: tokio_net::driver::Handle::current(),
+                timer: tokio_timer::timer::Handle::current(),
+                spawned: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        /// Number of tasks spawned so far.
+        pub fn spawned(&self) -> usize {
+            self.spawned.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Executor for TestExecutor {
+        fn spawn(&self, task: Task) {
+            self.spawned.fetch_add(1, Ordering::SeqCst);
+            self.pool.spawn_ok(WithRuntime {
+                reactor: self.reactor.clone(),
+                timer: self.timer.clone(),
+                task,
+            });
+        }
+    }
+
+    /// Polls the task with the reactor and timer set as the defaults of the thread.
+    struct WithRuntime {
+        reactor: tokio_net::driver::Handle,
+        timer: tokio_timer::timer::Handle,
+        task: Task,
+    }
+
+    impl Future for WithRuntime {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            let this = self.get_mut();
+            let _reactor = tokio_net::driver::set_default(&this.reactor);
+            let _timer = tokio_timer::set_default(&this.timer);
+            this.task.as_mut().poll(cx)
+        }
+    }
+
+    #[test]
+    fn test_executor_serves_service() {
+        use crate::test::{h2_request, start_lb, start_service, test_lb, wait_live};
+        use std::sync::Mutex;
+        use std::time::Duration;
+        use tokio::runtime::current_thread::Runtime;
+        use tokio_timer::Timeout;
+
+        let mut rt = Runtime::new().unwrap();
+        let (executor, res) = rt.block_on(async {
+            let executor = TestExecutor::new();
+            let mut lb = test_lb();
+            lb.set_executor(executor.clone());
+            let lb = Arc::new(Mutex::new(lb));
+            let served = async {
+                let addr = start_lb(lb.clone()).await;
+                start_service(addr, "a.example.com");
+                wait_live(&lb, "a.example.com").await;
+                let req = http::Request::get("http://a.example.com/")
+                    .body(())
+                    .unwrap();
+                h2_request(addr, req, &[]).await.0
+            };
+            let res = Timeout::new(served, Duration::from_secs(10)).await;
+            (executor, res.expect("Timed out"))
+        });
+        assert_eq!(res.status(), http::StatusCode::NO_CONTENT);
+        // the auth, service and client connections, and the drive of the service
+        // connection.
+        assert_eq!(executor.spawned(), 4);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::conn::PeerAddr;
+use crate::sticky::cookie_value;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Number of points each connection gets on the ring. More points means a more
+/// even spread of keys between the connections.
+const POINTS_PER_CONNECTION: u64 = 160;
+
+/// What part of a request to hash when routing using consistent hashing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashKey {
+    /// Value of the named request header.
+    Header(String),
+    /// Value of the named cookie.
+    Cookie(String),
+    /// The request path.
+    Path,
+    /// The ip address of the connecting client.
+    PeerAddr,
+}
+
+impl HashKey {
+    /// Extract the bytes to hash from the request. `None` if the request doesn't have
+    /// the key, in which case the request falls back on ordinary balancing.
+    pub(crate) fn extract<X>(&self, req: &http::Request<X>) -> Option<Vec<u8>> {
+        match self {
+            HashKey::Header(name) => req
+                .headers()
+                .get(name.as_str())
+                .map(|v| v.as_bytes().to_vec()),
+            HashKey::Cookie(name) => cookie_value(req, name).map(|v| v.as_bytes().to_vec()),
+            HashKey::Path => Some(req.uri().path().as_bytes().to_vec()),
+            HashKey::PeerAddr => req
+                .extensions()
+                .get::<PeerAddr>()
+                // the port changes per connection, only the ip is interesting.
+                .map(|p| p.0.ip().to_string().into_bytes()),
+        }
+    }
+}
+
+/// Ring of service connection ids. A key is mapped to the first connection point
+/// following the hash of the key. Adding or removing a connection only remaps the keys
+/// that fall in the removed/added connection's segments of the ring.
+#[derive(Debug, Default)]
+pub(crate) struct HashRing {
+    /// Connection ids the ring was built for, in sorted order.
+    ids: Vec<u64>,
+    /// Points on the ring as (hash, connection id), sorted by hash.
+    points: Vec<(u64, u64)>,
+}
+
+impl HashRing {
+    pub fn new(ids: &[u64]) -> Self {
+        let mut ids = ids.to_vec();
+        ids.sort();
+        let mut points = Vec::with_capacity(ids.len() * POINTS_PER_CONNECTION as usize);
+        for id in &ids {
+            for n in 0..POINTS_PER_CONNECTION {
+                points.push((hash(&(id, n)), *id));
+            }
+        }
+        points.sort();
+        HashRing { ids, points }
+    }
+
+    /// Tells if the ring is built for the given set of connection ids.
+    pub fn is_for(&self, ids: &[u64]) -> bool {
+        self.ids.len() == ids.len() && ids.iter().all(|id| self.ids.binary_search(id).is_ok())
+    }
+
+    /// Find the connection id for the key.
+    pub fn lookup(&self, key: &[u8]) -> Option<u64> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let h = hash(&key);
+        let idx = match self.points.binary_search_by_key(&h, |p| p.0) {
+            Ok(idx) => idx,
+            Err(idx) => idx,
+        };
+        // wrap around to the start of the ring.
+        let (_, id) = self.points[idx % self.points.len()];
+        Some(id)
+    }
+}
+
+fn hash<T: Hash>(t: &T) -> u64 {
+    // DefaultHasher::new() uses fixed keys, which makes it stable between instances.
+    let mut hasher = DefaultHasher::new();
+    t.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keys() -> Vec<Vec<u8>> {
+        (0..10_000)
+            .map(|n| format!("key-{}", n).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_empty() {
+        let ring = HashRing::new(&[]);
+        assert_eq!(ring.lookup(b"foo"), None);
+    }
+
+    #[test]
+    fn test_stable() {
+        let ring1 = HashRing::new(&[1, 2, 3]);
+        let ring2 = HashRing::new(&[3, 1, 2]);
+        assert!(ring1.is_for(&[2, 3, 1]));
+        assert!(!ring1.is_for(&[1, 2]));
+        for k in keys() {
+            assert_eq!(ring1.lookup(&k), ring2.lookup(&k));
+        }
+    }
+
+    #[test]
+    fn test_spread() {
+        let ring = HashRing::new(&[1, 2, 3, 4]);
+        let mut counts = [0; 4];
+        for k in keys() {
+            counts[ring.lookup(&k).unwrap() as usize - 1] += 1;
+        }
+        // each should be somewhere around 2500.
+        assert!(counts.iter().all(|c| *c > 1500), "{:?}", counts);
+    }
+
+    #[test]
+    fn test_remove_remaps_few() {
+        let before = HashRing::new(&[1, 2, 3, 4, 5]);
+        let after = HashRing::new(&[1, 2, 3, 4]);
+        let mut moved = 0;
+        for k in keys() {
+            let b = before.lookup(&k).unwrap();
+            let a = after.lookup(&k).unwrap();
+            if b != 5 {
+                // keys not on the removed connection must stay put.
+                assert_eq!(a, b);
+            } else {
+                moved += 1;
+            }
+        }
+        // roughly a fifth of the keys were on the removed connection.
+        assert!(moved < 3000, "{}", moved);
+    }
+
+    #[test]
+    fn test_add_remaps_few() {
+        let before = HashRing::new(&[1, 2, 3, 4]);
+        let after = HashRing::new(&[1, 2, 3, 4, 5]);
+        let moved = keys()
+            .iter()
+            .filter(|k| before.lookup(k) != after.lookup(k))
+            .count();
+        assert!(moved < 3000, "{}", moved);
+    }
+
+    #[test]
+    fn test_extract() {
+        let req = http::Request::builder()
+            .uri("https://example.com/some/path")
+            .header("x-user", "martin")
+            .header("cookie", "a=1; session=abc")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            HashKey::Header("x-user".into()).extract(&req),
+            Some(b"martin".to_vec())
+        );
+        assert_eq!(HashKey::Header("x-nope".into()).extract(&req), None);
+        assert_eq!(
+            HashKey::Cookie("session".into()).extract(&req),
+            Some(b"abc".to_vec())
+        );
+        assert_eq!(HashKey::Path.extract(&req), Some(b"/some/path".to_vec()));
+        assert_eq!(HashKey::PeerAddr.extract(&req), None);
+    }
+}
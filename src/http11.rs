@@ -1,8 +1,8 @@
 use crate::body::RecvBody;
 use crate::chunked::ChunkedDecoder;
-use crate::conn::{Connection, Socket};
+use crate::conn::{Connection, PeerAddr, Socket};
 use crate::limit::LimitRead;
-use crate::{AsyncReadExt, LolbError, LolbResult};
+use crate::{AsyncReadExt, LolbError, LolbResult, StatusKind};
 use std::io;
 
 // Request headers today vary in size from ~200 bytes to over 2KB.
@@ -11,6 +11,58 @@ use std::io;
 // http://dev.chromium.org/spdy/spdy-whitepaper
 const HTTP11_PARSE_BUF_SIZE: usize = 16_384;
 
+const BAD_REQUEST: LolbError = LolbError::Status(StatusKind::BadRequest, "Bad http11 request");
+const BAD_FRAMING: LolbError =
+    LolbError::Status(StatusKind::BadRequest, "Ambiguous http11 body length");
+const UNKNOWN_CODING: LolbError =
+    LolbError::Status(StatusKind::NotImplemented, "Unknown transfer-encoding");
+
+/// How the length of a request body is determined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    /// Transfer-encoding chunked.
+    Chunked,
+    /// Content-length, which is 0 when there is no header.
+    Length(usize),
+}
+
+/// Whether a http11 connection is reused for another request after the response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct KeepAlive {
+    /// The client is http/1.0, which has no chunked encoding and closes by default.
+    pub http10: bool,
+    /// The connection is closed after the response.
+    pub close: bool,
+}
+
+impl KeepAlive {
+    /// From the version and `connection` header of a request.
+    fn from_request(version: Option<u8>, headers: &http::HeaderMap) -> Self {
+        let http10 = version == Some(0);
+        let mut close = http10;
+        for token in list_tokens(headers, "connection") {
+            if token == "close" {
+                return KeepAlive {
+                    http10,
+                    close: true,
+                };
+            } else if token == "keep-alive" {
+                close = false;
+            }
+        }
+        KeepAlive { http10, close }
+    }
+
+    /// Set the `connection` header of a response to tell the client what happens.
+    pub fn set_header(&self, headers: &mut http::HeaderMap) {
+        if self.close {
+            headers.insert("connection", http::HeaderValue::from_static("close"));
+        } else if self.http10 {
+            headers.insert("connection", http::HeaderValue::from_static("keep-alive"));
+        }
+    }
+}
+
 pub(crate) async fn parse_http11<'a, S>(
     conn: &'a mut Connection<S>,
 ) -> LolbResult<Option<http::Request<RecvBody<'a, S>>>>
@@ -24,10 +76,10 @@ where
     let peeked_amount = conn
         .socket()
         .peek(&mut buf, &|so_far| {
+            // a bad request is also enough, there is no point reading more.
             try_parse_http11(so_far, scheme)
-                .ok()
-                .and_then(|v| v)
-                .is_some()
+                .map(|v| v.is_some())
+                .unwrap_or(true)
         })
         .await?;
 
@@ -50,28 +102,19 @@ where
     let (mut req, header_len) = result.unwrap();
 
     // discard header_len from the socket to position it where the body starts.
-    conn.socket().read(&mut buf[0..header_len]).await?;
-
-    let recv_chunked = req
-        .headers_mut()
-        // transfer-encoding is not allowed in http2, so we remove it
-        .remove("transfer-encoding")
-        .map(|h| h.to_str().unwrap_or("").to_ascii_lowercase() == "chunked")
-        .unwrap_or(false);
-
-    let content_len = req
-        .headers()
-        .get("content-length")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(0);
+    conn.socket().read_exact(&mut buf[0..header_len]).await?;
+
+    let framing = framing(req.headers())?;
+
+    // transfer-encoding is not allowed in http2, so we remove it
+    req.headers_mut().remove("transfer-encoding");
 
-    let (parts, _) = req.into_parts();
+    let (mut parts, _) = req.into_parts();
+    parts.extensions.insert(PeerAddr(conn.peer_addr()));
 
-    let body = if recv_chunked {
-        RecvBody::Http11Chunked(ChunkedDecoder::new(conn.socket()))
-    } else {
-        RecvBody::Http11Plain(LimitRead::new(conn.socket(), content_len))
+    let body = match framing {
+        Framing::Chunked => RecvBody::Http11Chunked(ChunkedDecoder::new(conn.socket())),
+        Framing::Length(len) => RecvBody::Http11Plain(LimitRead::new(conn.socket(), len)),
     };
 
     Ok(Some(http::Request::from_parts(parts, body)))
@@ -80,7 +123,11 @@ where
 fn try_parse_http11(buf: &[u8], scheme: &str) -> LolbResult<Option<(http::Request<()>, usize)>> {
     let mut headers = [httparse::EMPTY_HEADER; 128];
     let mut request = httparse::Request::new(&mut headers);
-    let status = request.parse(buf)?;
+    // this also rejects obsolete line folding, and whitespace before the colon.
+    let status = request.parse(buf).map_err(|e| {
+        debug!("Failed to parse http11 request: {}", e);
+        BAD_REQUEST
+    })?;
     if status.is_partial() {
         return Ok(None);
     }
@@ -96,13 +143,23 @@ fn try_parse_http11(buf: &[u8], scheme: &str) -> LolbResult<Option<(http::Reques
     for head in request.headers.iter() {
         // the upstream is always http2, so we
         // translate host to :authority
-        if head.name.to_ascii_lowercase() == "host" {
+        if head.name.eq_ignore_ascii_case("host") {
+            if authority.is_some() {
+                debug!("Duplicate host header");
+                return Err(BAD_REQUEST);
+            }
             authority = Some(head.value);
         } else {
             bld.header(head.name, head.value);
         }
     }
 
+    // http/1.1 requires host.
+    if authority.is_none() && request.version == Some(1) {
+        debug!("Missing host header");
+        return Err(BAD_REQUEST);
+    }
+
     let mut uri = http::uri::Builder::new();
     uri.scheme(scheme);
     if let Some(authority) = authority {
@@ -111,11 +168,140 @@ fn try_parse_http11(buf: &[u8], scheme: &str) -> LolbResult<Option<(http::Reques
     if let Some(path) = request.path {
         uri.path_and_query(path);
     }
-    bld.uri(uri.build()?);
+    bld.uri(uri.build().map_err(|_| BAD_REQUEST)?);
 
     let head_len = status.unwrap();
 
-    Ok(bld.body(()).map(|r| Some((r, head_len)))?)
+    let mut req = bld.body(()).map_err(|e| {
+        debug!("Bad http11 request: {}", e);
+        BAD_REQUEST
+    })?;
+
+    let keep_alive = KeepAlive::from_request(request.version, req.headers());
+    req.extensions_mut().insert(keep_alive);
+    request_to_http2(req.headers_mut());
+
+    Ok(Some((req, head_len)))
+}
+
+/// Headers that only apply to one connection, RFC 7230 section 6.1. They are not
+/// allowed in http2.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "upgrade",
+    "te",
+];
+
+/// Headers that are needed to find the end of a body, and can't be removed by being
+/// named in `connection`.
+const FRAMING: &[&str] = &["content-length", "transfer-encoding"];
+
+/// Lowercase elements of a comma separated header, across all headers with the name.
+fn list_tokens(headers: &http::HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .flat_map(|v| v.as_bytes().split(|c| *c == b','))
+        .map(|t| String::from_utf8_lossy(t).trim().to_ascii_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// Remove the hop-by-hop headers, and the headers named in `connection`.
+fn strip_hop_by_hop(headers: &mut http::HeaderMap) {
+    for token in list_tokens(headers, "connection") {
+        if FRAMING.contains(&token.as_str()) {
+            debug!("Ignore framing header in connection: {}", token);
+            continue;
+        }
+        if let Ok(name) = http::header::HeaderName::from_bytes(token.as_bytes()) {
+            headers.remove(name);
+        }
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+/// Translate the headers of a http11 request for http2. `te: trailers` is the only
+/// hop-by-hop header http2 allows, and is kept since gRPC requires it.
+fn request_to_http2(headers: &mut http::HeaderMap) {
+    let trailers = list_tokens(headers, "te")
+        .iter()
+        .any(|t| t.split(';').next().map(|c| c.trim()) == Some("trailers"));
+    strip_hop_by_hop(headers);
+    if trailers {
+        headers.insert("te", http::HeaderValue::from_static("trailers"));
+    }
+}
+
+/// Translate the headers of a http2 response for http11. The body length headers are
+/// decided when writing the response.
+pub(crate) fn response_to_http11(headers: &mut http::HeaderMap) {
+    strip_hop_by_hop(headers);
+    headers.remove("transfer-encoding");
+}
+
+/// The framing of the request body, RFC 7230 section 3.3.3. Anything ambiguous is
+/// rejected, since the service might read it differently than we do, which would let
+/// a client smuggle a request past the load balancer.
+fn framing(headers: &http::HeaderMap) -> LolbResult<Framing> {
+    let te = headers.get_all("transfer-encoding");
+    let cl = headers.get_all("content-length");
+
+    let mut codings = vec![];
+    for value in te.iter() {
+        let value = value.to_str().map_err(|_| BAD_FRAMING)?;
+        // empty list elements are allowed.
+        for coding in value.split(',').map(|c| c.trim()).filter(|c| !c.is_empty()) {
+            codings.push(coding.to_ascii_lowercase());
+        }
+    }
+    let has_te = te.iter().next().is_some();
+
+    let mut lengths = cl.iter();
+    let length = lengths.next();
+
+    if has_te {
+        if length.is_some() {
+            debug!("Both transfer-encoding and content-length");
+            return Err(BAD_FRAMING);
+        }
+        // chunked must be the final coding, and only applied once.
+        let chunked = codings.iter().filter(|c| *c == "chunked").count();
+        if codings.last().map(|c| c.as_str()) != Some("chunked") || chunked > 1 {
+            debug!("Transfer-encoding not ending with chunked: {:?}", codings);
+            return Err(BAD_FRAMING);
+        }
+        // no other transfer-codings are supported.
+        if codings.len() > 1 {
+            debug!("Unsupported transfer-encoding: {:?}", codings);
+            return Err(UNKNOWN_CODING);
+        }
+        return Ok(Framing::Chunked);
+    }
+
+    let length = match length {
+        Some(x) => x,
+        None => return Ok(Framing::Length(0)),
+    };
+    if lengths.next().is_some() {
+        debug!("Multiple content-length");
+        return Err(BAD_FRAMING);
+    }
+    // only digits, which also rejects lists like `5, 5`, signs and whitespace.
+    let length = length.as_bytes();
+    if length.is_empty() || !length.iter().all(|c| c.is_ascii_digit()) {
+        debug!("Bad content-length: {:?}", length);
+        return Err(BAD_FRAMING);
+    }
+    std::str::from_utf8(length)
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .map(Framing::Length)
+        .ok_or(BAD_FRAMING)
 }
 
 /// Helper with generic writer.
@@ -138,3 +324,220 @@ pub(crate) fn write_http11_response<W: io::Write, X>(
     write!(w, "\r\n")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conn::test::duplex;
+    use crate::conn::HttpVersion;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn parse(head: &str) -> LolbResult<Framing> {
+        let (req, _) = try_parse_http11(head.as_bytes(), "http")?.expect("complete head");
+        framing(req.headers())
+    }
+
+    fn status(res: LolbResult<Framing>) -> u16 {
+        res.unwrap_err().status().expect("status error").as_u16()
+    }
+
+    #[test]
+    fn test_framing_ok() {
+        let ok = [
+            ("GET / HTTP/1.1\r\nHost: x\r\n\r\n", Framing::Length(0)),
+            ("GET / HTTP/1.0\r\nHost: x\r\n\r\n", Framing::Length(0)),
+            (
+                "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\n",
+                Framing::Length(5),
+            ),
+            (
+                "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n",
+                Framing::Length(0),
+            ),
+            (
+                "POST / HTTP/1.1\r\nHost: x\r\nContent-Length:\t7 \r\n\r\n",
+                Framing::Length(7),
+            ),
+            (
+                "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n",
+                Framing::Chunked,
+            ),
+            (
+                "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: CHUNKED\r\n\r\n",
+                Framing::Chunked,
+            ),
+            (
+                "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: ,chunked\r\n\r\n",
+                Framing::Chunked,
+            ),
+        ];
+        for (head, expect) in &ok {
+            assert_eq!(parse(head).unwrap(), *expect, "{:?}", head);
+        }
+    }
+
+    #[test]
+    fn test_smuggling_payloads() {
+        let bad = [
+            // CL.TE and TE.CL
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 6\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nContent-Length: 0\r\n\r\n",
+            // obfuscated transfer-encoding
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: xchunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked, identity\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked, chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: \"chunked\"\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: x\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding:\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding : chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: x\r\n chunked\r\n\r\n",
+            // bad content-length
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5, 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: +5\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: -1\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 0x5\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5a\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length:\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+            // obsolete line folding
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length:\r\n 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nX-A: b\r\n c\r\n\r\n",
+            "POST / HTTP/1.1\r\n Host: x\r\n\r\n",
+            // ambiguous host
+            "GET / HTTP/1.1\r\nHost: x\r\nHost: y\r\n\r\n",
+            "GET / HTTP/1.1\r\n\r\n",
+        ];
+        for head in &bad {
+            assert_eq!(status(parse(head)), 400, "{:?}", head);
+        }
+    }
+
+    #[test]
+    fn test_unknown_coding() {
+        let unknown = [
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: identity, chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n",
+        ];
+        for head in &unknown {
+            assert_eq!(status(parse(head)), 501, "{:?}", head);
+        }
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let keep_alive = |head: &str| {
+            let (req, _) = try_parse_http11(head.as_bytes(), "http").unwrap().unwrap();
+            let k = *req.extensions().get::<KeepAlive>().unwrap();
+            (k.http10, k.close)
+        };
+        let cases = [
+            ("GET / HTTP/1.1\r\nHost: x\r\n\r\n", (false, false)),
+            (
+                "GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+                (false, true),
+            ),
+            (
+                "GET / HTTP/1.1\r\nHost: x\r\nConnection: foo, Close\r\n\r\n",
+                (false, true),
+            ),
+            ("GET / HTTP/1.0\r\nHost: x\r\n\r\n", (true, true)),
+            (
+                "GET / HTTP/1.0\r\nHost: x\r\nConnection: Keep-Alive\r\n\r\n",
+                (true, false),
+            ),
+            (
+                "GET / HTTP/1.0\r\nHost: x\r\nConnection: keep-alive\r\nConnection: close\r\n\r\n",
+                (true, true),
+            ),
+        ];
+        for (head, expect) in &cases {
+            assert_eq!(keep_alive(head), *expect, "{:?}", head);
+        }
+    }
+
+    #[test]
+    fn test_hop_by_hop_request() {
+        let head =
+            "POST / HTTP/1.1\r\nHost: x\r\nConnection: keep-alive, X-Foo, content-length\r\n\
+                    X-Foo: 1\r\nX-Bar: 2\r\nKeep-Alive: timeout=5\r\nUpgrade: h2c\r\n\
+                    Proxy-Connection: close\r\nTE: deflate;q=0.5, trailers\r\n\
+                    Content-Length: 5\r\n\r\n";
+        let (req, _) = try_parse_http11(head.as_bytes(), "http").unwrap().unwrap();
+        let mut names: Vec<_> = req.headers().keys().map(|k| k.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["content-length", "te", "x-bar"]);
+        assert_eq!(req.headers().get("te").unwrap(), "trailers");
+        assert_eq!(framing(req.headers()).unwrap(), Framing::Length(5));
+        assert!(!req.extensions().get::<KeepAlive>().unwrap().close);
+
+        let head = "GET / HTTP/1.1\r\nHost: x\r\nTE: gzip\r\n\r\n";
+        let (req, _) = try_parse_http11(head.as_bytes(), "http").unwrap().unwrap();
+        assert!(req.headers().get("te").is_none());
+    }
+
+    #[test]
+    fn test_hop_by_hop_response() {
+        let mut res = http::Response::builder()
+            .header("connection", "x-foo")
+            .header("x-foo", "1")
+            .header("keep-alive", "timeout=5")
+            .header("transfer-encoding", "chunked")
+            .header("content-type", "text/plain")
+            .body(())
+            .unwrap();
+        response_to_http11(res.headers_mut());
+        let names: Vec<_> = res.headers().keys().map(|k| k.as_str()).collect();
+        assert_eq!(names, vec!["content-type"]);
+    }
+
+    #[test]
+    fn test_partial_is_not_enough() {
+        let head = b"POST / HTTP/1.1\r\nHost: x\r\nContent-Len";
+        assert!(try_parse_http11(head, "http").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let input = b"POST /a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello\
+                          POST /b HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                          3\r\nabc\r\n0\r\n\r\n\
+                          GET /c HTTP/1.1\r\nHost: x\r\n\r\n";
+            let addr = "127.0.0.1:1".parse().unwrap();
+            let mut conn = Connection::new(duplex(input), addr, HttpVersion::Http11, false);
+
+            for (path, expect) in &[("/a", &b"hello"[..]), ("/b", b"abc"), ("/c", b"")] {
+                let req = parse_http11(&mut conn).await.unwrap().unwrap();
+                assert_eq!(req.uri().path(), *path);
+                let mut body = req.into_body();
+                let mut data = vec![];
+                while let Some(chunk) = body.data().await {
+                    data.extend_from_slice(&chunk.unwrap()[..]);
+                }
+                assert_eq!(&data[..], *expect);
+                assert!(body.is_end());
+            }
+            assert!(parse_http11(&mut conn).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_truncated_body() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let input = b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\nhello";
+            let addr = "127.0.0.1:1".parse().unwrap();
+            let mut conn = Connection::new(duplex(input), addr, HttpVersion::Http11, false);
+            let req = parse_http11(&mut conn).await.unwrap().unwrap();
+            let mut body = req.into_body();
+            assert_eq!(&body.data().await.unwrap().unwrap()[..], b"hello");
+            assert!(body.data().await.unwrap().is_err());
+        });
+    }
+}
@@ -4,44 +4,73 @@
 #[macro_use]
 extern crate log;
 
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::future::{self, select, Either};
+use futures_util::pin_mut;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use std::future::Future;
 use std::io::Cursor;
 use std::sync::{Arc, Mutex};
+use tokio_timer::timeout::Elapsed;
+use tokio_timer::Timeout;
 
 pub(crate) use tokio_io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+pub mod acme;
+mod balance;
 mod body;
 mod chunked;
+pub mod client;
 mod conf;
 mod conn;
+pub mod dns;
 mod error;
+mod exec;
+mod hashring;
 mod http11;
 mod limit;
 pub mod peek;
 pub mod persist;
+pub mod reload;
 mod respond;
 mod serv_auth;
 mod serv_conn;
 mod service;
+mod sticky;
+pub mod tls;
 mod util;
 
+pub use balance::BalanceStrategy;
 use body::*;
 pub use conf::*;
 use conn::*;
 pub use error::*;
+pub use exec::{Executor, Task, TokioExecutor};
+pub use hashring::HashKey;
+use http11::KeepAlive;
 use respond::*;
 use serv_auth::*;
 use serv_conn::*;
+pub use service::ServiceAuth;
 use service::*;
 
-use crate::persist::{load_preauthed, save_preauthed, Persist};
-use acme_lib::Account;
+use crate::acme::{AcmeAccount, ChallengeSink, ChallengeStore};
+use crate::persist::{remove_preauthed, save_preauthed, take_preauthed, Persist};
+use crate::util::current_time_millis;
 
 pub(crate) const PATH_NODE_REGISTER: &str = "/__lolb_node_register";
 pub(crate) const PATH_KEEP_ALIVE: &str = "/__lolb_keep_alive";
 pub(crate) const HEADER_AUTH: &str = "x-lolb-auth";
 
+/// Max number of concurrent streams handled for one http2 client connection.
+pub(crate) const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Max number of requests on one http11 connection before it is closed.
+pub(crate) const MAX_HTTP11_REQUESTS: usize = 1000;
+
+/// Max size of an unread http11 request body that is drained to reuse the connection.
+pub(crate) const MAX_HTTP11_DRAIN: usize = 65_536;
+
 /// A load balancer instance.
 pub struct LoadBalancer<P>
 where
@@ -52,9 +81,84 @@ where
     /// Persistence for saving/loading stuff.
     persist: P,
     /// The acme account to use for managing TLS certificates.
-    account: Account<P>,
+    account: Arc<AcmeAccount<P>>,
+    /// Where to publish ACME challenges when ordering certificates.
+    challenges: Arc<dyn ChallengeSink>,
+    /// Challenges answered by the load balancer itself. This is the default sink.
+    challenge_store: ChallengeStore,
+    /// Certificate orders in progress, and backoff of failed ones.
+    acme_orders: acme::Orders,
     /// Configured serviced domains.
     services: Services,
+    /// Executor to spawn connection handling tasks on.
+    executor: Arc<dyn Executor>,
+    /// Reconnect keys issued with the unix time millis when they expire. Used to clean
+    /// up keys that are never used.
+    issued_keys: Vec<(ReconnectKey, u64)>,
+}
+
+impl<P: Persist> LoadBalancer<P> {
+    /// Create a new load balancer from the config. Tasks are spawned on the default
+    /// tokio executor unless another is set using `set_executor`.
+    ///
+    /// The load balancer doesn't bind any sockets. The embedder binds them, and hands
+    /// incoming connections to `accept_incoming` using a `ConnectionProvider`.
+    ///
+    /// Nothing runs by itself. Along with `accept_incoming`, the embedder spawns
+    /// `acme::renew_certificates`, which orders certificates for new hosts and renews
+    /// them before they expire. Without it, a certificate is only ordered when a service
+    /// connection is added, and never renewed. Optionally also `reload::watch_config`,
+    /// and the `dns::DnsServer`.
+    ///
+    /// The ACME account is created when the first certificate is ordered.
+    pub fn new(config: Config, persist: P) -> LolbResult<Self> {
+        config.validate()?;
+
+        let account = Arc::new(AcmeAccount::new(config.acme.clone(), persist.clone()));
+        let services = Services::new(&config.domains);
+        let challenge_store = ChallengeStore::default();
+
+        Ok(LoadBalancer {
+            config,
+            persist,
+            account,
+            challenges: Arc::new(challenge_store.clone()),
+            challenge_store,
+            acme_orders: acme::Orders::default(),
+            services,
+            executor: Arc::new(TokioExecutor),
+            issued_keys: vec![],
+        })
+    }
+
+    /// Set the executor to spawn connection handling tasks on.
+    pub fn set_executor<E: Executor + 'static>(&mut self, executor: E) {
+        self.executor = Arc::new(executor);
+    }
+
+    /// Set where to publish ACME challenges when ordering certificates. By default the
+    /// load balancer answers the challenges itself.
+    pub fn set_challenge_sink<C: ChallengeSink + 'static>(&mut self, challenges: C) {
+        self.challenges = Arc::new(challenges);
+    }
+
+    /// Apply a new config to the running load balancer. Service connections of domains
+    /// that are still configured are kept, and those of removed domains are closed. An
+    /// invalid config leaves the current one in place.
+    ///
+    /// This doesn't block. A changed ACME account is created with the next order.
+    pub fn apply_config(&mut self, config: Config) -> LolbResult<()> {
+        config.validate()?;
+
+        if config.acme != self.config.acme {
+            self.account = Arc::new(AcmeAccount::new(config.acme.clone(), self.persist.clone()));
+        }
+
+        self.services.apply(&config.domains);
+        self.config = config;
+
+        Ok(())
+    }
 }
 
 pub async fn accept_incoming<P, S, R, F>(
@@ -63,23 +167,28 @@ pub async fn accept_incoming<P, S, R, F>(
 ) -> LolbResult<()>
 where
     P: Persist,
+    P: 'static,
     S: Socket,
-    S: 'static,
+    S: Send + 'static,
     R: ConnectionProvider<S, F>,
     F: Future<Output = LolbResult<Connection<S>>>,
 {
+    let executor = lb.lock().unwrap().executor.clone();
     loop {
         // wait for provider to produce the next incoming connection. A failure here
         // means we abort the entire handling.
         let conn = provider.accept().await?;
 
         // async handling of incoming request.
-        match handle_incoming(lb.clone(), conn).await {
-            Ok(_) => {}
-            // requests fail, that's life on the internet. just debug output in case
-            // it's needed for hunting bugs.
-            Err(e) => debug!("{}", e),
-        }
+        let lb = lb.clone();
+        executor.spawn(Box::pin(async move {
+            match handle_incoming(lb, conn).await {
+                Ok(_) => {}
+                // requests fail, that's life on the internet. just debug output in case
+                // it's needed for hunting bugs.
+                Err(e) => debug!("{}", e),
+            }
+        }));
     }
 }
 
@@ -89,8 +198,9 @@ async fn handle_incoming<P, S>(
 ) -> LolbResult<()>
 where
     P: Persist,
+    P: 'static,
     S: Socket,
-    S: 'static,
+    S: Send + 'static,
 {
     // First we must check if the incoming connection is a preauthed service connection.
     // If it is, then we are acting as an h2 client instead of a server.
@@ -114,8 +224,10 @@ where
         let n = Cursor::new(&mut peeked[4..]).get_u64_be();
         let authed = {
             let key = ReconnectKey(n);
-            let lock = lb.lock().unwrap();
-            load_preauthed(&lock.persist, key).await?
+            // the lock must not be held over the await.
+            let persist = lb.lock().unwrap().persist.clone();
+            // the key is consumed, which means it can't be replayed.
+            take_preauthed(&persist, key).await?
         };
         if let Some(authed) = authed {
             // Discard the preauth from the incoming bytes.
@@ -128,7 +240,7 @@ where
             add_preauthed_service(lb.clone(), conn, authed).await?;
             return Ok(());
         } else {
-            // sending "lolb" without any corresonding preauth is an error
+            // sending "lolb" without any corresonding (valid) preauth is an error
             return Err(LolbError::Message("No preauth for incoming 'lolb' prefix"));
         }
     }
@@ -143,36 +255,48 @@ where
 
 async fn add_preauthed_service<P, S>(
     lb: Arc<Mutex<LoadBalancer<P>>>,
-    mut conn: Connection<S>,
+    conn: Connection<S>,
     preauthed: Preauthed,
 ) -> LolbResult<()>
 where
     P: Persist,
+    P: 'static,
     S: Socket,
+    S: Send + 'static,
 {
-    // Start an h2 client against this service.
-    let (h2, conn) = h2::client::handshake(conn.socket()).await?;
+    // Start an h2 client against this service. The socket is moved into the
+    // connection since it is driven as a task of its own.
+    let (h2, conn) = h2::client::handshake(conn.into_socket()).await?;
 
     // The idea is that the drive closure below retains the strong reference to
     // the service connection and the weak reference goes into the service routing
     // logic. Thus on disconnect, the weak refererence will instantly be invalid.
-    let service_conn = ServiceConnection(h2);
+    let service_conn = ServiceConnection::new(h2);
     let strong = Arc::new(service_conn);
     let weak = Arc::downgrade(&strong);
 
+    let closed = strong.closed();
     let drive = async move {
         let _strong = strong;
-        if let Err(e) = conn.await {
+        match select(conn, closed).await {
             // service probably disconnected. that's expected.
-            debug!("Service disconnect: {}", e);
+            Either::Left((Err(e), _)) => debug!("Service disconnect: {}", e),
+            Either::Left((Ok(_), _)) => {}
+            // the connection is dropped, which disconnects the service.
+            Either::Right(_) => debug!("Closing service connection"),
         }
     };
 
-    // tokio::spawn(drive);
+    // add service connection to service definitions. If that fails the connection is
+    // dropped without ever being driven.
+    {
+        let mut lock = lb.lock().unwrap();
+        lock.services.add_preauthed(preauthed, weak)?;
+        lock.executor.spawn(Box::pin(drive));
+    }
 
-    // add service connection to service definitions.
-    let mut lock = lb.lock().unwrap();
-    lock.services.add_preauthed(preauthed, weak);
+    // a new host needs a certificate.
+    acme::order_needed(&lb);
 
     Ok(())
 }
@@ -212,37 +336,109 @@ where
     // to a common format for routing, then normalize the responses to a common format
     // for responding.
     if http_version == HttpVersion::Http2 {
-        let mut h2 = h2::server::handshake(conn.socket()).await?;
-        let mut check_service_auth = true;
-        // http2 can have several streams (requests) in the same socket.
-        while let Some(r) = h2.accept().await {
-            let (h2req, send_resp) = r?;
+        let peer_addr = conn.peer_addr();
+        let mut h2 = h2::server::Builder::new()
+            .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+            .handshake::<_, Bytes>(conn.socket())
+            .await?;
+
+        let to_req = |h2req: http::Request<h2::RecvStream>| {
             let (parts, body) = h2req.into_parts();
-            let req = http::Request::from_parts(parts, RecvBody::<S>::Http2(body));
-
-            // we only check service auth once in the first stream.
-            if check_service_auth {
-                check_service_auth = false;
-                if is_service_auth(lb.clone(), &req) {
-                    // this is a service auth request, deal with it and no further processing
-                    // of streams in this h2 connection.
-                    handle_service_auth(lb.clone(), req).await?;
-                    return Ok(());
-                }
-            }
+            let mut req = http::Request::from_parts(parts, RecvBody::<S>::Http2(body));
+            req.extensions_mut().insert(PeerAddr(peer_addr));
+            req
+        };
+
+        let (h2req, send_resp) = match h2.accept().await {
+            Some(r) => r?,
+            // connection is closed.
+            None => return Ok(()),
+        };
+        let req = to_req(h2req);
 
-            // route request to service and wait for a response
-            let res = request_to_service(lb.clone(), req).await?;
+        // we only check service auth once in the first stream.
+        if is_service_auth(lb.clone(), &req) {
+            // this is a service auth request, deal with it and no further processing
+            // of streams in this h2 connection. the connection is still driven until the
+            // service hangs up, since that is what reads the auth and sends the response.
             let respond = Responder::<S>::Http2(send_resp);
-            respond.send_response(res).await?;
+            let auth = handle_service_auth(lb.clone(), req, respond);
+            serve_h2_streams(&mut h2, auth, |_, mut send_resp| {
+                send_resp.send_reset(h2::Reason::REFUSED_STREAM);
+                future::ready(Ok(()))
+            })
+            .await?;
+            return Ok(());
         }
+
+        let first = handle_h2_stream(lb.clone(), req, send_resp);
+        serve_h2_streams(&mut h2, first, |h2req, send_resp| {
+            handle_h2_stream(lb.clone(), to_req(h2req), send_resp)
+        })
+        .await?;
     } else if http_version == HttpVersion::Http11 {
+        let idle = lb.lock().unwrap().config.timeouts.keep_alive();
+
         // http11 have one request at a time.
-        while let Some(req) = http11::parse_http11(&mut conn).await? {
-            // route request to service and wait for a response
-            let res = request_to_service(lb.clone(), req).await?;
-            let respond = Responder::Http11(conn.socket());
-            respond.send_response(res).await?;
+        for served in 1..=MAX_HTTP11_REQUESTS {
+            let parsed = match Timeout::new(http11::parse_http11(&mut conn), idle).await {
+                Ok(x) => x,
+                Err(_) => {
+                    trace!("Idle http11 connection timed out");
+                    break;
+                }
+            };
+            let mut req = match parsed {
+                Ok(Some(req)) => req,
+                Ok(None) => break,
+                Err(e) => {
+                    // the framing of the request is unknown, so the connection can't
+                    // be used after the error response.
+                    let mut keep_alive = KeepAlive {
+                        close: true,
+                        ..KeepAlive::default()
+                    };
+                    Responder::Http11(conn.socket(), &mut keep_alive)
+                        .send_error(e)
+                        .await?;
+                    break;
+                }
+            };
+            let mut keep_alive = req
+                .extensions_mut()
+                .remove::<KeepAlive>()
+                .unwrap_or_default();
+            if served == MAX_HTTP11_REQUESTS {
+                keep_alive.close = true;
+            }
+            if let Some(proof) = acme_challenge(&lb, &req) {
+                // the next request starts after the body.
+                if !req.body_mut().drain(MAX_HTTP11_DRAIN).await? {
+                    keep_alive.close = true;
+                }
+                // the request borrows the socket we respond on.
+                drop(req);
+                let respond = Responder::Http11(conn.socket(), &mut keep_alive);
+                send_acme_challenge(proof, respond).await?;
+            } else {
+                let has_body = !req.body().is_end();
+                // route request to service and wait for a response
+                let result = request_to_service(lb.clone(), req).await;
+                if result.is_err() && has_body {
+                    // the body might be partly read, so the next request can't be found.
+                    keep_alive.close = true;
+                }
+                let respond = Responder::Http11(conn.socket(), &mut keep_alive);
+                match result {
+                    Ok((res, set_cookie)) => respond.send_response(res, set_cookie).await?,
+                    // non-status errors leave the connection in an unknown state and
+                    // are propagated by send_error.
+                    Err(e) => respond.send_error(e).await?,
+                }
+            }
+            if keep_alive.close {
+                break;
+            }
         }
     } else {
         panic!("Unknown http version after peek: {:?}", http_version);
@@ -251,6 +447,81 @@ where
     Ok(())
 }
 
+/// Handle the streams (requests) of a http2 connection concurrently, while the connection
+/// itself is driven by accepting new streams. Once no more streams are accepted, the
+/// streams in flight are run to completion rather than cancelled.
+async fn serve_h2_streams<T, F, Q, R>(
+    h2: &mut h2::server::Connection<T, Bytes>,
+    first: Q,
+    mut handle: F,
+) -> LolbResult<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut(http::Request<h2::RecvStream>, h2::server::SendResponse<Bytes>) -> R,
+    Q: Future<Output = LolbResult<()>>,
+    R: Future<Output = LolbResult<()>>,
+{
+    let mut streams = FuturesUnordered::new();
+    streams.push(Either::Left(first));
+
+    let result = loop {
+        let accepted = if streams.is_empty() {
+            h2.accept().await
+        } else {
+            let accept = h2.accept();
+            pin_mut!(accept);
+            let res = select(accept, streams.next()).await;
+            match res {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right((done, _)) => {
+                    if let Some(Err(e)) = done {
+                        // a failing stream must not take down the other streams.
+                        debug!("Stream failed: {}", e);
+                    }
+                    continue;
+                }
+            }
+        };
+
+        match accepted {
+            Some(Ok((h2req, send_resp))) => streams.push(Either::Right(handle(h2req, send_resp))),
+            Some(Err(e)) => break Err(e.into()),
+            // client went away or connection is closed.
+            None => break Ok(()),
+        }
+    };
+
+    while let Some(done) = streams.next().await {
+        if let Err(e) = done {
+            debug!("Stream failed: {}", e);
+        }
+    }
+
+    result
+}
+
+/// Route one http2 stream to a service and respond on it.
+async fn handle_h2_stream<'a, P, S>(
+    lb: Arc<Mutex<LoadBalancer<P>>>,
+    req: http::Request<RecvBody<'a, S>>,
+    send_resp: h2::server::SendResponse<Bytes>,
+) -> LolbResult<()>
+where
+    P: Persist,
+    S: Socket,
+{
+    let respond = Responder::<S>::Http2(send_resp);
+    if let Some(proof) = acme_challenge(&lb, &req) {
+        return send_acme_challenge(proof, respond).await;
+    }
+    // route request to service and wait for a response
+    match request_to_service(lb, req).await {
+        Ok((res, set_cookie)) => respond.send_response(res, set_cookie).await,
+        // errors without a http status drop the responder, which resets the stream.
+        Err(e) => respond.send_error(e).await,
+    }
+}
+
 /// Check if this request is a service auth.
 pub(crate) fn is_service_auth<'a, P, S>(
     _lb: Arc<Mutex<LoadBalancer<P>>>,
@@ -263,18 +534,115 @@ where
     req.uri().path() == PATH_NODE_REGISTER
 }
 
-/// Authenticate incoming service auth.
+/// The proof if the request is for a published ACME http-01 challenge. These are answered
+/// before routing, while other requests are routed as usual, also those under the
+/// challenge path.
+fn acme_challenge<P, X>(lb: &Arc<Mutex<LoadBalancer<P>>>, req: &http::Request<X>) -> Option<String>
+where
+    P: Persist,
+{
+    let lock = lb.lock().unwrap();
+    lock.challenge_store.http_proof(req.uri().path())
+}
+
+/// Respond to an ACME http-01 challenge with the proof.
+async fn send_acme_challenge<'a, S>(proof: String, respond: Responder<'a, S>) -> LolbResult<()>
+where
+    S: Socket,
+{
+    let res = http::Response::builder()
+        .header("content-type", "text/plain")
+        .body(())?;
+    respond.send_body(res, proof.into()).await
+}
+
+/// Authenticate incoming service auth and respond with the reconnect key.
 async fn handle_service_auth<'a, P, S>(
     lb: Arc<Mutex<LoadBalancer<P>>>,
     req: http::Request<RecvBody<'a, S>>,
+    respond: Responder<'a, S>,
 ) -> LolbResult<()>
 where
     P: Persist,
     S: Socket,
+{
+    match service_auth(lb, req).await {
+        Ok(auth_res) => {
+            let json =
+                serde_json::to_vec(&auth_res).expect("Failed to json serialize AuthResponse");
+            let res = http::Response::builder()
+                .header("content-type", "application/json")
+                .body(())?;
+            respond.send_body(res, json.into()).await
+        }
+        Err(e) => respond.send_error(e).await,
+    }
+}
+
+async fn service_auth<'a, P, S>(
+    lb: Arc<Mutex<LoadBalancer<P>>>,
+    req: http::Request<RecvBody<'a, S>>,
+) -> LolbResult<AuthResponse>
+where
+    P: Persist,
+    S: Socket,
+{
+    let (secret, mut preauthed) = read_preauth(req).await?;
+
+    // check auth
+    let persist = {
+        let lock = lb.lock().unwrap();
+        check_preauth(&lock.services, &preauthed, &secret)?;
+        lock.persist.clone()
+    };
+
+    // auth success, generate a new one-off reconnect key
+    let key = ReconnectKey(rand::random());
+    preauthed.set_created_now();
+
+    save_preauthed(&persist, key, &preauthed).await?;
+
+    // clean up keys that expired without being used.
+    let expired: Vec<ReconnectKey> = {
+        let mut lock = lb.lock().unwrap();
+        let now = current_time_millis();
+        lock.issued_keys.push((key, preauthed.expires()));
+        let (expired, issued): (Vec<_>, Vec<_>) =
+            lock.issued_keys.drain(..).partition(|(_, exp)| *exp <= now);
+        lock.issued_keys = issued;
+        expired.into_iter().map(|(k, _)| k).collect()
+    };
+    for key in expired {
+        remove_preauthed(&persist, key).await?;
+    }
+
+    Ok(AuthResponse {
+        key: key.0,
+        expires: preauthed.expires(),
+    })
+}
+
+const BAD_PREAUTH: LolbError = LolbError::Status(StatusKind::BadRequest, "Bad preauth");
+const BAD_AUTH: LolbError = LolbError::Status(StatusKind::Unauthorized, "Bad auth");
+
+/// Read the secret and the Preauthed with details of the service from a service auth request.
+async fn read_preauth<'a, S>(req: http::Request<RecvBody<'a, S>>) -> LolbResult<(String, Preauthed)>
+where
+    S: Socket,
 {
     let (parts, mut body) = req.into_parts();
     let req = http::Request::from_parts(parts, ());
 
+    // read header with secret
+    let secret = req
+        .headers()
+        .get(HEADER_AUTH)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| {
+            debug!("Missing or bad {} header", HEADER_AUTH);
+            BAD_AUTH
+        })?;
+
     // read body into a Preauthed with details of the service.
     let preauthed: Preauthed = {
         let mut bytes = BytesMut::new();
@@ -282,59 +650,519 @@ where
             let data = data?;
             bytes.extend_from_slice(&data[..]);
             if bytes.len() > 100 * 1024 {
-                return LolbResult::Err(LolbError::Message("Preauth is too big"));
+                debug!("Preauth is too big");
+                return Err(BAD_PREAUTH);
             }
         }
-        serde_json::from_slice(&bytes[..])
-            .map_err(|e| LolbError::Owned(format!("Bad preauth: {}", e)))?
+        serde_json::from_slice(&bytes[..]).map_err(|e| {
+            debug!("Bad preauth: {}", e);
+            BAD_PREAUTH
+        })?
     };
 
-    // read header with secret
-    let secret = req
-        .headers()
-        .get(HEADER_AUTH)
-        .ok_or_else(|| LolbError::Owned(format!("Missing {} header", HEADER_AUTH)))
-        .and_then(|v| {
-            v.to_str()
-                .map_err(|_| LolbError::Message("Failed to interpret auth header as a string"))
-        })?;
-
-    // check auth
-    let persist = {
-        let lock = lb.lock().unwrap();
-
-        if !lock.services.is_valid_secret(&preauthed, &secret) {
-            // XXX log something
-            return LolbResult::Err(LolbError::Message("Bad auth"));
-        }
-
-        lock.persist.clone()
-    };
-
-    // auth success, generate a new one-off reconnect key
-    let key = ReconnectKey(rand::random());
-
-    save_preauthed(&persist, key, &preauthed).await?;
+    Ok((secret.to_string(), preauthed))
+}
 
+/// Check the secret of a service auth, and that the service is for a host in its domain.
+fn check_preauth(services: &Services, preauthed: &Preauthed, secret: &str) -> LolbResult<()> {
+    if !services.is_valid_secret(preauthed, secret) {
+        info!("Bad auth for domain: {}", preauthed.domain());
+        return Err(BAD_AUTH);
+    }
+    if !services.is_valid_host(preauthed) {
+        info!(
+            "Host {} is not in domain: {}",
+            preauthed.host(),
+            preauthed.domain()
+        );
+        return Err(BAD_PREAUTH);
+    }
     Ok(())
 }
 
 /// Route a normalized request to a matching service.
+///
+/// Returns the response together with an affinity cookie to set, if the client is to be
+/// pinned to the service connection.
 async fn request_to_service<'a, P, S>(
     lb: Arc<Mutex<LoadBalancer<P>>>,
     req: http::Request<RecvBody<'a, S>>,
-) -> LolbResult<http::Response<h2::RecvStream>>
+) -> LolbResult<(http::Response<h2::RecvStream>, Option<http::HeaderValue>)>
 where
     P: Persist,
     S: Socket,
 {
-    let s_conn = {
+    let (routed, timeout) = {
         let mut lock = lb.lock().unwrap();
-        lock.services.route(&req)
+        let timeout = lock.config.timeouts.service_response();
+        (lock.services.route(&req)?, timeout)
     };
-    if let Some(s_conn) = s_conn {
-        Ok(s_conn.send_request(req).await?)
-    } else {
-        Err(LolbError::Message("No service accepted incoming request"))
+    let send = routed.conn.send_request(req);
+    let res = service_response(Timeout::new(send, timeout).await)?;
+    Ok((res, routed.set_cookie))
+}
+
+/// Map a failed or timed out service request to the http status answered to the client.
+fn service_response<T>(result: Result<LolbResult<T>, Elapsed>) -> LolbResult<T> {
+    match result {
+        Ok(Ok(res)) => Ok(res),
+        Ok(Err(LolbError::H2(e))) => {
+            debug!("Service request failed: {}", e);
+            Err(LolbError::Status(
+                StatusKind::BadGateway,
+                "Service request failed",
+            ))
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(LolbError::Status(
+            StatusKind::GatewayTimeout,
+            "Service did not respond in time",
+        )),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::client::{ServiceClient, ServiceConfig};
+    use crate::conn::test::{duplex, TcpProvider};
+    use crate::persist::MemoryPersist;
+    use std::net::{Shutdown, SocketAddr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_net::tcp::{TcpListener, TcpStream};
+
+    pub(crate) type TestLb = Arc<Mutex<LoadBalancer<MemoryPersist>>>;
+
+    /// Config serving `example.com` with the secret `secret`. Certificate orders fail
+    /// fast since there is no ACME directory.
+    pub(crate) fn test_config() -> Config {
+        Config::from_toml(
+            r#"
+[[domains]]
+domain = "example.com"
+auth = { PresharedKey = "secret" }
+
+[acme]
+contact = "admin@example.com"
+url = "http://127.0.0.1:1/directory"
+"#,
+        )
+        .unwrap()
+    }
+
+    pub(crate) fn test_lb() -> LoadBalancer<MemoryPersist> {
+        LoadBalancer::new(test_config(), MemoryPersist::new()).unwrap()
+    }
+
+    /// Accept plain tcp connections to the load balancer on a local port.
+    pub(crate) async fn start_lb(lb: TestLb) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            accept_incoming(lb, TcpProvider::new(listener))
+                .await
+                .unwrap();
+        });
+        addr
+    }
+
+    /// Register a service for `host` that responds 204 with the path in `x-path`.
+    pub(crate) fn start_service(addr: SocketAddr, host: &str) {
+        let config = ServiceConfig::new(addr, "secret", "example.com", host, "/");
+        tokio::spawn(async move {
+            let mut client = ServiceClient::new(config);
+            loop {
+                let (req, mut send_resp) = client.accept().await.unwrap();
+                let res = http::Response::builder()
+                    .status(204)
+                    .header("x-path", req.uri().path())
+                    .body(())
+                    .unwrap();
+                send_resp.send_response(res, true).unwrap();
+            }
+        });
+    }
+
+    /// Wait until a service connection for the host is added.
+    pub(crate) async fn wait_live(lb: &TestLb, host: &str) {
+        while !lb.lock().unwrap().services.is_live_host(host) {
+            tokio_timer::delay_for(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Make an http2 request to the load balancer, returning the response and its body.
+    pub(crate) async fn h2_request(
+        addr: SocketAddr,
+        req: http::Request<()>,
+        body: &[u8],
+    ) -> (http::Response<()>, Vec<u8>) {
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let (send_req, conn) = h2::client::handshake(tcp).await.unwrap();
+        tokio::spawn(async move {
+            conn.await.ok();
+        });
+        let mut h2 = send_req.ready().await.unwrap();
+        let (response, mut send_body) = h2.send_request(req, body.is_empty()).unwrap();
+        if !body.is_empty() {
+            send_body.send_data(body.to_vec().into(), true).unwrap();
+        }
+        let (parts, mut recv) = response.await.unwrap().into_parts();
+        let mut bytes = vec![];
+        while let Some(data) = recv.data().await {
+            let data = data.unwrap();
+            recv.release_capacity()
+                .release_capacity(data.len())
+                .unwrap();
+            bytes.extend_from_slice(&data[..]);
+        }
+        (http::Response::from_parts(parts, ()), bytes)
+    }
+
+    /// Post a service auth to the load balancer.
+    async fn post_auth(addr: SocketAddr, secret: &str, body: &str) -> (http::StatusCode, Vec<u8>) {
+        let req = http::Request::post(format!("http://lb.example.com{}", PATH_NODE_REGISTER))
+            .header(HEADER_AUTH, secret)
+            .body(())
+            .unwrap();
+        let (res, body) = h2_request(addr, req, body.as_bytes()).await;
+        (res.status(), body)
+    }
+
+    #[test]
+    fn test_service_auth_h2() {
+        let mut rt = Runtime::new().unwrap();
+        let lb = Arc::new(Mutex::new(test_lb()));
+        let persist = lb.lock().unwrap().persist.clone();
+        let done = rt.block_on(Timeout::new(
+            async move {
+                let addr = start_lb(lb).await;
+
+                let (status, body) = post_auth(addr, "secret", PREAUTH).await;
+                assert_eq!(status, http::StatusCode::OK);
+                let auth: AuthResponse = serde_json::from_slice(&body[..]).unwrap();
+                let preauthed = take_preauthed(&persist, ReconnectKey(auth.key)).await;
+                assert_eq!(preauthed.unwrap().unwrap().host(), "a.example.com");
+
+                let (status, _) = post_auth(addr, "wrong", PREAUTH).await;
+                assert_eq!(status, http::StatusCode::UNAUTHORIZED);
+                let (status, _) = post_auth(addr, "secret", "{").await;
+                assert_eq!(status, http::StatusCode::BAD_REQUEST);
+            },
+            Duration::from_secs(10),
+        ));
+        assert!(done.is_ok(), "Timed out");
+    }
+
+    #[test]
+    fn test_route_to_service() {
+        let mut rt = Runtime::new().unwrap();
+        let lb = Arc::new(Mutex::new(test_lb()));
+        let res = rt.block_on(Timeout::new(
+            async move {
+                let addr = start_lb(lb.clone()).await;
+                start_service(addr, "a.example.com");
+                wait_live(&lb, "a.example.com").await;
+                let req = http::Request::get("http://a.example.com/path")
+                    .body(())
+                    .unwrap();
+                h2_request(addr, req, &[]).await.0
+            },
+            Duration::from_secs(10),
+        ));
+        let res = res.expect("Timed out");
+        assert_eq!(res.status(), http::StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()["x-path"], "/path");
+    }
+
+    #[test]
+    fn test_service_response() {
+        let mut rt = Runtime::new().unwrap();
+        let timed_out = rt.block_on(async {
+            let never = futures_util::future::pending::<LolbResult<()>>();
+            Timeout::new(never, Duration::from_millis(10)).await
+        });
+        let err = service_response(timed_out).unwrap_err();
+        assert_eq!(err.status(), Some(http::StatusCode::GATEWAY_TIMEOUT));
+
+        let failed: LolbResult<()> = Err(h2::Error::from(h2::Reason::REFUSED_STREAM).into());
+        let err = service_response(Ok(failed)).unwrap_err();
+        assert_eq!(err.status(), Some(http::StatusCode::BAD_GATEWAY));
+
+        // other errors are kept as they are.
+        let err = service_response::<()>(Ok(Err(LolbError::Message("x")))).unwrap_err();
+        assert_eq!(err.status(), None);
+        assert_eq!(service_response(Ok(Ok(42))).unwrap(), 42);
+    }
+
+    /// A raw http2 frame.
+    fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Preface, settings and GET requests on streams 1 and 3.
+    fn two_requests() -> Vec<u8> {
+        let mut out = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        out.extend(frame(0x4, 0, 0, &[]));
+        // GET http://x/ as hpack, with END_STREAM | END_HEADERS.
+        let headers = [0x82, 0x86, 0x84, 0x41, 0x01, b'x'];
+        out.extend(frame(0x1, 0x5, 1, &headers));
+        out.extend(frame(0x1, 0x5, 3, &headers));
+        out
+    }
+
+    struct Counts {
+        started: AtomicUsize,
+        finished: AtomicUsize,
+    }
+
+    /// Respond once `wait_for` streams have started.
+    async fn respond_when_started(
+        counts: Arc<Counts>,
+        wait_for: usize,
+        mut send_resp: h2::server::SendResponse<Bytes>,
+    ) -> LolbResult<()> {
+        counts.started.fetch_add(1, Ordering::SeqCst);
+        while counts.started.load(Ordering::SeqCst) < wait_for {
+            tokio_timer::delay_for(Duration::from_millis(5)).await;
+        }
+        tokio_timer::delay_for(Duration::from_millis(50)).await;
+        let res = http::Response::builder().body(()).unwrap();
+        let sent = send_resp.send_response(res, true);
+        counts.finished.fetch_add(1, Ordering::SeqCst);
+        sent?;
+        Ok(())
+    }
+
+    /// Serve the streams of one http2 connection, returning the local address and a
+    /// future that serves it.
+    async fn serve(
+        counts: Arc<Counts>,
+        wait_for: usize,
+    ) -> (std::net::SocketAddr, impl Future<Output = LolbResult<()>>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serve = async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut h2 = h2::server::handshake(tcp).await?;
+            let (_, send_resp) = h2.accept().await.unwrap()?;
+            let first = respond_when_started(counts.clone(), wait_for, send_resp);
+            serve_h2_streams(&mut h2, first, |_, send_resp| {
+                respond_when_started(counts.clone(), wait_for, send_resp)
+            })
+            .await
+        };
+        (addr, serve)
+    }
+
+    #[test]
+    fn test_h2_streams_after_goaway() {
+        let mut rt = Runtime::new().unwrap();
+        let counts = Arc::new(Counts {
+            started: AtomicUsize::new(0),
+            finished: AtomicUsize::new(0),
+        });
+        let responded = rt.block_on(async {
+            // both streams wait for each other, so they must be handled concurrently.
+            let (addr, serve) = serve(counts.clone(), 2).await;
+            let client = async move {
+                let mut tcp = TcpStream::connect(addr).await.unwrap();
+                let mut out = two_requests();
+                // no more streams after 3, while both are still open.
+                out.extend(frame(0x7, 0, 0, &[0, 0, 0, 3, 0, 0, 0, 0]));
+                tcp.write_all(&out).await.unwrap();
+
+                let mut responded = vec![];
+                while responded.len() < 2 {
+                    let mut head = [0; 9];
+                    tcp.read_exact(&mut head).await.unwrap();
+                    let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+                    let mut payload = vec![0; len];
+                    tcp.read_exact(&mut payload).await.unwrap();
+                    let stream_id = u32::from_be_bytes([head[5], head[6], head[7], head[8]]);
+                    if head[3] == 0x1 {
+                        responded.push(stream_id);
+                    }
+                }
+                responded.sort();
+                responded
+            };
+            let (served, responded) = futures_util::future::join(serve, client).await;
+            served.unwrap();
+            responded
+        });
+        assert_eq!(responded, vec![1, 3]);
+        assert_eq!(counts.finished.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_h2_streams_after_close() {
+        let mut rt = Runtime::new().unwrap();
+        let counts = Arc::new(Counts {
+            started: AtomicUsize::new(0),
+            finished: AtomicUsize::new(0),
+        });
+        rt.block_on(async {
+            let (addr, serve) = serve(counts.clone(), 2).await;
+            let started = counts.clone();
+            let client = async move {
+                let mut tcp = TcpStream::connect(addr).await.unwrap();
+                tcp.write_all(&two_requests()).await.unwrap();
+                while started.started.load(Ordering::SeqCst) < 2 {
+                    tokio_timer::delay_for(Duration::from_millis(5)).await;
+                }
+                // the connection closes with the streams in flight.
+                tcp.shutdown(Shutdown::Write).unwrap();
+                tcp
+            };
+            let (_, tcp) = futures_util::future::join(serve, client).await;
+            drop(tcp);
+        });
+        // accepted streams are not cancelled.
+        assert_eq!(counts.finished.load(Ordering::SeqCst), 2);
+    }
+
+    /// Make an http11 request to the load balancer, returning the raw response.
+    async fn http11_request(addr: SocketAddr, path: &str) -> String {
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "GET {} HTTP/1.1\r\nhost: a.example.com\r\nconnection: close\r\n\r\n",
+            path
+        );
+        tcp.write_all(req.as_bytes()).await.unwrap();
+        let mut res = vec![];
+        tcp.read_to_end(&mut res).await.unwrap();
+        String::from_utf8(res).unwrap()
+    }
+
+    const CHALLENGE: &str = "/.well-known/acme-challenge/token";
+    const UNKNOWN_CHALLENGE: &str = "/.well-known/acme-challenge/unknown";
+
+    /// Start a load balancer with a published challenge `token`. Requests are only
+    /// routed once a service is started.
+    async fn start_challenge_lb() -> (TestLb, SocketAddr) {
+        let lb = Arc::new(Mutex::new(test_lb()));
+        lb.lock().unwrap().challenge_store.publish("token", "proof");
+        let addr = start_lb(lb.clone()).await;
+        (lb, addr)
+    }
+
+    #[test]
+    fn test_acme_challenge_h2() {
+        let mut rt = Runtime::new().unwrap();
+        let (challenge, unknown) = rt
+            .block_on(Timeout::new(
+                async {
+                    let (lb, addr) = start_challenge_lb().await;
+                    let get = |path: &str| {
+                        let uri = format!("http://a.example.com{}", path);
+                        http::Request::get(uri.as_str()).body(()).unwrap()
+                    };
+                    // there is no service to route to.
+                    let challenge = h2_request(addr, get(CHALLENGE), &[]).await;
+                    start_service(addr, "a.example.com");
+                    wait_live(&lb, "a.example.com").await;
+                    let unknown = h2_request(addr, get(UNKNOWN_CHALLENGE), &[]).await;
+                    (challenge, unknown.0)
+                },
+                Duration::from_secs(10),
+            ))
+            .expect("Timed out");
+        assert_eq!(challenge.0.status(), http::StatusCode::OK);
+        assert_eq!(challenge.1, b"proof");
+        assert_eq!(unknown.status(), http::StatusCode::NO_CONTENT);
+        assert_eq!(unknown.headers()["x-path"], UNKNOWN_CHALLENGE);
+    }
+
+    #[test]
+    fn test_acme_challenge_http11() {
+        let mut rt = Runtime::new().unwrap();
+        let (challenge, unknown) = rt
+            .block_on(Timeout::new(
+                async {
+                    let (lb, addr) = start_challenge_lb().await;
+                    // there is no service to route to.
+                    let challenge = http11_request(addr, CHALLENGE).await;
+                    start_service(addr, "a.example.com");
+                    wait_live(&lb, "a.example.com").await;
+                    let unknown = http11_request(addr, UNKNOWN_CHALLENGE).await;
+                    (challenge, unknown)
+                },
+                Duration::from_secs(10),
+            ))
+            .expect("Timed out");
+        assert!(challenge.starts_with("HTTP/1.1 200"), "{}", challenge);
+        assert!(challenge.ends_with("\r\n\r\nproof"), "{}", challenge);
+        assert!(unknown.starts_with("HTTP/1.1 204"), "{}", unknown);
+        let x_path = format!("x-path: {}\r\n", UNKNOWN_CHALLENGE);
+        assert!(unknown.contains(&x_path), "{}", unknown);
+    }
+
+    /// Parse and check a service auth request against services of `example.com`.
+    fn auth(headers: &str, body: &str) -> LolbResult<Preauthed> {
+        let input = format!(
+            "POST {} HTTP/1.1\r\nhost: lb.example.com\r\n{}content-length: {}\r\n\r\n{}",
+            PATH_NODE_REGISTER,
+            headers,
+            body.len(),
+            body
+        );
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        let mut conn = Connection::new(duplex(input.as_bytes()), addr, HttpVersion::Http11, false);
+        futures_executor::block_on(async {
+            let req = http11::parse_http11(&mut conn).await?.unwrap();
+            let (secret, preauthed) = read_preauth(req).await?;
+            let services = Services::new(&[DomainConfig {
+                domain: "example.com".into(),
+                auth: ServiceAuth::PresharedKey("secret".into()),
+                wildcard: false,
+            }]);
+            check_preauth(&services, &preauthed, &secret)?;
+            Ok(preauthed)
+        })
+    }
+
+    const PREAUTH: &str = r#"{"domain":"example.com","host":"a.example.com","prefix":"/"}"#;
+
+    #[test]
+    fn test_service_auth() {
+        let p = auth("x-lolb-auth: secret\r\n", PREAUTH).unwrap();
+        assert_eq!(p.host(), "a.example.com");
+    }
+
+    #[test]
+    fn test_service_auth_unauthorized() {
+        let status = |res: LolbResult<Preauthed>| res.unwrap_err().status();
+        let unauthorized = Some(http::StatusCode::UNAUTHORIZED);
+        assert_eq!(status(auth("", PREAUTH)), unauthorized);
+        assert_eq!(
+            status(auth("x-lolb-auth: wrong\r\n", PREAUTH)),
+            unauthorized
+        );
+        let other = r#"{"domain":"example.org","host":"example.org","prefix":"/"}"#;
+        assert_eq!(status(auth("x-lolb-auth: secret\r\n", other)), unauthorized);
+    }
+
+    #[test]
+    fn test_service_auth_bad_request() {
+        let status = |res: LolbResult<Preauthed>| res.unwrap_err().status();
+        let bad_request = Some(http::StatusCode::BAD_REQUEST);
+        let secret = "x-lolb-auth: secret\r\n";
+        assert_eq!(status(auth(secret, "{")), bad_request);
+        assert_eq!(
+            status(auth(secret, &" ".repeat(100 * 1024 + 1))),
+            bad_request
+        );
+        // the host must be the domain or a host under it.
+        for host in &["example.org", "aexample.com"] {
+            let body = PREAUTH.replace("a.example.com", host);
+            assert_eq!(status(auth(secret, &body)), bad_request);
+        }
     }
 }
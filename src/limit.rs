@@ -21,6 +21,11 @@ impl<S: AsyncRead> LimitRead<S> {
             limit,
         }
     }
+
+    /// Whether all bytes up to the limit are read.
+    pub fn is_end(&self) -> bool {
+        self.read == self.limit
+    }
 }
 
 impl<S: AsyncRead + Unpin> AsyncRead for LimitRead<S> {
@@ -30,7 +35,28 @@ impl<S: AsyncRead + Unpin> AsyncRead for LimitRead<S> {
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
         let max = (self.limit - self.read).min(buf.len());
-        Pin::new(&mut self.get_mut().source).poll_read(cx, &mut buf[0..max])
+        if max == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        let self_mut = self.get_mut();
+        match Pin::new(&mut self_mut.source).poll_read(cx, &mut buf[0..max]) {
+            Poll::Ready(r) => {
+                let rd = r?;
+                if rd == 0 {
+                    // the stream must not end before the limit.
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        LolbError::Owned(format!(
+                            "Stream ended before LimitRead: {} < {}",
+                            self_mut.read, self_mut.limit
+                        )),
+                    )));
+                }
+                self_mut.read += rd;
+                Poll::Ready(Ok(rd))
+            }
+            Poll::Pending => Poll::Pending,
+        }
     }
 }
 
@@ -52,6 +78,11 @@ impl<S: AsyncWrite> LimitWrite<S> {
             limit,
         }
     }
+
+    /// Whether all bytes up to the limit are written.
+    pub fn is_end(&self) -> bool {
+        self.written == self.limit
+    }
 }
 
 impl<S: AsyncWrite + Unpin> AsyncWrite for LimitWrite<S> {
@@ -27,32 +27,45 @@ impl<R: io::Read + AsyncRead + Unpin + io::Write + AsyncWrite> Peekable<R> {
     pub async fn peek(
         &mut self,
         buf: &mut [u8],
-        is_enough: &dyn Fn(&[u8]) -> bool,
+        is_enough: &(dyn Fn(&[u8]) -> bool + Sync),
     ) -> io::Result<usize> {
         let mut total = self.buffered.len();
 
-        // ensure we have enough space in the buffered to hold the amount needed to peek.
-        if self.buffered.len() < buf.len() {
+        // a previous peek might already have buffered enough, like a pipelined request.
+        let enough = total >= buf.len() || (total > 0 && is_enough(&self.buffered[..]));
+
+        if !enough {
+            // ensure we have enough space in the buffered to hold the amount needed to peek.
             self.buffered.resize(buf.len(), 0x0);
-        }
 
-        // fill buffered
-        while total < buf.len() {
-            let read = AsyncReadExt::read(&mut self.wrapped, &mut self.buffered[total..]).await?;
-            if read == 0 {
-                // end
-                break;
-            }
-            total += read;
-            if is_enough(&self.buffered[0..total]) {
-                break;
+            // fill buffered
+            while total < buf.len() {
+                let read =
+                    AsyncReadExt::read(&mut self.wrapped, &mut self.buffered[total..]).await?;
+                if read == 0 {
+                    // end
+                    break;
+                }
+                total += read;
+                if is_enough(&self.buffered[0..total]) {
+                    break;
+                }
             }
+
+            // only keep what was actually read.
+            self.buffered.truncate(total);
         }
 
         // at this point we have total or enough amount of bytes to copy out.
-        (&mut buf[0..total]).copy_from_slice(&self.buffered[0..total]);
+        let amount = total.min(buf.len());
+        buf[0..amount].copy_from_slice(&self.buffered[0..amount]);
+
+        Ok(amount)
+    }
 
-        Ok(total)
+    /// Drop peeked bytes, so the next read starts after them.
+    pub(crate) fn consume(&mut self, amount: usize) {
+        self.buffered.advance(amount);
     }
 }
 
@@ -62,6 +75,15 @@ impl<R: io::Read + AsyncRead + Unpin + io::Write> io::Read for Peekable<R> {
     }
 }
 
+impl<R: io::Read + AsyncRead + Unpin + io::Write> io::Write for Peekable<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::Write::write(&mut self.wrapped, buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        io::Write::flush(&mut self.wrapped)
+    }
+}
+
 impl<R: io::Read + AsyncRead + Unpin + io::Write> AsyncRead for Peekable<R> {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -72,7 +94,7 @@ impl<R: io::Read + AsyncRead + Unpin + io::Write> AsyncRead for Peekable<R> {
         if !self.buffered.is_empty() {
             let max = self.buffered.len().min(buf.len());
             let removed = self.get_mut().buffered.split_to(max);
-            (&mut buf[0..max]).copy_from_slice(&removed[..]);
+            buf[0..max].copy_from_slice(&removed[..]);
             return Poll::Ready(Ok(max));
         }
 
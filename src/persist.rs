@@ -2,7 +2,9 @@ use crate::serv_auth::{Preauthed, ReconnectKey};
 use crate::LolbResult;
 pub use acme_lib::persist::{Persist as AcmePersist, PersistKey as AcmePersistKey};
 pub use acme_lib::{Error as AcmeError, Result as AcmeResult};
+use std::collections::HashMap;
 use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum PersistKey<'a> {
@@ -11,7 +13,7 @@ pub enum PersistKey<'a> {
 }
 
 /// Trait for persistence implementations.
-pub trait Persist: AcmePersist + Clone + Send {
+pub trait Persist: AcmePersist + Clone + Send + Sync {
     /// Bridge ACME put into our own "save" with callback. This stalls the acme thread worker
     /// thread which is ok cause it's expected by that lib.
     fn put(&self, key: &AcmePersistKey, value: &[u8]) -> AcmeResult<()> {
@@ -38,6 +40,11 @@ pub trait Persist: AcmePersist + Clone + Send {
     fn save(&self, key: &PersistKey, value: &[u8], tx: Sender<LolbResult<()>>);
     /// Async takes callback until traits can have async functions.
     fn load(&self, key: &PersistKey, tx: Sender<LolbResult<Option<Vec<u8>>>>);
+    /// Remove the value and send back what was removed. The remove must be atomic, i.e.
+    /// two concurrent removes of the same key must not both get the value.
+    ///
+    /// Async takes callback until traits can have async functions.
+    fn remove(&self, key: &PersistKey, tx: Sender<LolbResult<Option<Vec<u8>>>>);
 }
 
 /// Save a preauthed reconnect key to persistence.
@@ -53,15 +60,133 @@ pub(crate) async fn save_preauthed<P: Persist>(
     rx.recv().expect("Failed to rx.recv()")
 }
 
-/// Read a preauthed reconnect key from persistence.
-pub(crate) async fn load_preauthed<P: Persist>(
+/// Consume a preauthed reconnect key from persistence. The key is removed, which means
+/// it can only be used once. Expired keys are consumed, but not returned.
+pub(crate) async fn take_preauthed<P: Persist>(
     persist: &P,
     key: ReconnectKey,
 ) -> LolbResult<Option<Preauthed>> {
     let (tx, rx) = channel::<LolbResult<Option<Vec<u8>>>>();
     let pk = PersistKey::ReconnectKey(key.0);
-    persist.load(&pk, tx);
-    rx.recv().expect("Failed to rx.recv()").map(|o| {
-        o.map(|b| serde_json::from_slice(&b[..]).expect("Failed to json deserialize Preauthed"))
-    })
+    persist.remove(&pk, tx);
+    let authed: Option<Preauthed> = rx
+        .recv()
+        .expect("Failed to rx.recv()")?
+        .map(|b| serde_json::from_slice(&b[..]).expect("Failed to json deserialize Preauthed"));
+    Ok(authed.filter(|a| a.is_valid()))
+}
+
+/// Remove a preauthed reconnect key from persistence.
+pub(crate) async fn remove_preauthed<P: Persist>(persist: &P, key: ReconnectKey) -> LolbResult<()> {
+    let (tx, rx) = channel::<LolbResult<Option<Vec<u8>>>>();
+    let pk = PersistKey::ReconnectKey(key.0);
+    persist.remove(&pk, tx);
+    rx.recv().expect("Failed to rx.recv()").map(|_| ())
+}
+
+/// Persistence in memory. Nothing survives a restart.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryPersist {
+    values: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl MemoryPersist {
+    pub fn new() -> Self {
+        MemoryPersist {
+            ..Default::default()
+        }
+    }
+
+    fn key_of(key: &PersistKey) -> String {
+        match key {
+            PersistKey::Acme(k) => format!("acme/{}", k),
+            PersistKey::ReconnectKey(n) => format!("reconnect/{}", n),
+        }
+    }
+}
+
+impl AcmePersist for MemoryPersist {
+    fn put(&self, key: &AcmePersistKey, value: &[u8]) -> AcmeResult<()> {
+        Persist::put(self, key, value)
+    }
+    fn get(&self, key: &AcmePersistKey) -> AcmeResult<Option<Vec<u8>>> {
+        Persist::get(self, key)
+    }
+}
+
+impl Persist for MemoryPersist {
+    fn save(&self, key: &PersistKey, value: &[u8], tx: Sender<LolbResult<()>>) {
+        let mut lock = self.values.lock().unwrap();
+        lock.insert(MemoryPersist::key_of(key), value.to_vec());
+        tx.send(Ok(())).ok();
+    }
+    fn load(&self, key: &PersistKey, tx: Sender<LolbResult<Option<Vec<u8>>>>) {
+        let lock = self.values.lock().unwrap();
+        tx.send(Ok(lock.get(&MemoryPersist::key_of(key)).cloned()))
+            .ok();
+    }
+    fn remove(&self, key: &PersistKey, tx: Sender<LolbResult<Option<Vec<u8>>>>) {
+        let mut lock = self.values.lock().unwrap();
+        tx.send(Ok(lock.remove(&MemoryPersist::key_of(key)))).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_executor::block_on;
+
+    fn preauthed(created: u64) -> Preauthed {
+        let json = format!(
+            r#"{{"created":{},"domain":"example.com","host":"foo.example.com","prefix":"/"}}"#,
+            created
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_take_once() {
+        let persist = MemoryPersist::new();
+        let key = ReconnectKey(42);
+        let mut authed = preauthed(0);
+        authed.set_created_now();
+        block_on(save_preauthed(&persist, key, &authed)).unwrap();
+
+        let taken = block_on(take_preauthed(&persist, key)).unwrap();
+        assert_eq!(
+            taken.map(|a| a.host().to_string()),
+            Some("foo.example.com".into())
+        );
+
+        // replaying the same key must fail.
+        let replay = block_on(take_preauthed(&persist, key)).unwrap();
+        assert!(replay.is_none());
+    }
+
+    #[test]
+    fn test_take_expired() {
+        let persist = MemoryPersist::new();
+        let key = ReconnectKey(43);
+        block_on(save_preauthed(&persist, key, &preauthed(1))).unwrap();
+
+        let taken = block_on(take_preauthed(&persist, key)).unwrap();
+        assert!(taken.is_none());
+
+        // the expired key is cleaned up by the take.
+        let (tx, rx) = channel();
+        persist.load(&PersistKey::ReconnectKey(43), tx);
+        assert_eq!(rx.recv().unwrap().unwrap(), None);
+    }
+
+    #[test]
+    fn test_remove() {
+        let persist = MemoryPersist::new();
+        let key = ReconnectKey(44);
+        block_on(save_preauthed(&persist, key, &preauthed(1))).unwrap();
+        block_on(remove_preauthed(&persist, key)).unwrap();
+
+        let (tx, rx) = channel();
+        persist.load(&PersistKey::ReconnectKey(44), tx);
+        assert_eq!(rx.recv().unwrap().unwrap(), None);
+    }
 }
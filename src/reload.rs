@@ -0,0 +1,250 @@
+//! Reloading of config for a running load balancer.
+//!
+//! The config file is reloaded when its modification time changes, or on `SIGHUP`.
+//! A config that fails to read or validate is logged and the current one is kept.
+use crate::persist::Persist;
+use crate::{Config, LoadBalancer, LolbResult};
+use futures_util::pin_mut;
+use futures_util::stream::{self, StreamExt};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio_timer::Interval;
+
+/// What caused a reload check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Trigger {
+    /// Time to check if the file is modified.
+    Poll,
+    /// The process got a `SIGHUP`.
+    Hangup,
+}
+
+/// Watch the config file and apply it to the load balancer when it changes. The file is
+/// polled for changes with the given interval. This future runs forever and only fails
+/// if the signal handler can't be installed.
+pub async fn watch_config<P>(
+    lb: Arc<Mutex<LoadBalancer<P>>>,
+    path: PathBuf,
+    poll: Duration,
+) -> LolbResult<()>
+where
+    P: Persist,
+{
+    let polls = Interval::new_interval(poll).map(|_| Trigger::Poll);
+    let hangups = hangups()?.map(|_| Trigger::Hangup);
+    let triggers = stream::select(polls, hangups);
+    pin_mut!(triggers);
+
+    let mut last = modified(&path);
+
+    while let Some(trigger) = triggers.next().await {
+        let now = modified(&path);
+        // a hangup always reloads, even when the file looks the same.
+        if trigger == Trigger::Poll && now == last {
+            continue;
+        }
+        last = now;
+
+        match reload(&lb, &path) {
+            Ok(()) => info!("Reloaded config: {}", path.display()),
+            Err(e) => warn!("Failed to reload config {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the config file and apply it.
+fn reload<P: Persist>(lb: &Arc<Mutex<LoadBalancer<P>>>, path: &Path) -> LolbResult<()> {
+    let config = read_config(path)?;
+    lb.lock().unwrap().apply_config(config)
+}
+
+/// Read config from a file. Files ending `.json` are JSON, anything else TOML.
+pub fn read_config(path: &Path) -> LolbResult<Config> {
+    let s = fs::read_to_string(path)?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => Config::from_json(&s),
+        _ => Config::from_toml(&s),
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(unix)]
+fn hangups() -> LolbResult<impl stream::Stream<Item = ()>> {
+    use crate::LolbError;
+    use tokio_net::signal::unix::{signal, SignalKind};
+    signal(SignalKind::hangup())
+        .map_err(|e| LolbError::Owned(format!("Failed to listen for SIGHUP: {}", e)))
+}
+
+#[cfg(not(unix))]
+fn hangups() -> LolbResult<impl stream::Stream<Item = ()>> {
+    // no SIGHUP, only polling.
+    Ok(stream::pending())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::persist::MemoryPersist;
+    use crate::serv_auth::Preauthed;
+    use crate::serv_conn::ServiceConnection;
+    use crate::service::test::connections;
+    use crate::BalanceStrategy;
+    use futures_util::future::{select, Either};
+    use tokio::runtime::current_thread::Runtime;
+
+    const OLD: &str = r#"
+[[domains]]
+domain = "example.com"
+auth = { PresharedKey = "old" }
+
+[acme]
+contact = "admin@example.com"
+"#;
+
+    const NEW: &str = r#"
+[[domains]]
+domain = "example.com"
+auth = { PresharedKey = "new" }
+
+[[domains]]
+domain = "other.com"
+auth = { PresharedKey = "other" }
+
+[acme]
+contact = "admin@example.com"
+"#;
+
+    /// A config file that is removed when dropped.
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(config: &str) -> Self {
+            let name = format!("lolb-reload-{}.toml", rand::random::<u64>());
+            let file = ConfigFile(std::env::temp_dir().join(name));
+            file.write(config);
+            file
+        }
+        fn write(&self, config: &str) {
+            fs::write(&self.0, config).unwrap();
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            fs::remove_file(&self.0).ok();
+        }
+    }
+
+    /// Load balancer with the `OLD` config, and the connection serving `a.example.com`.
+    fn load_balancer(conn: &Arc<ServiceConnection>) -> Arc<Mutex<LoadBalancer<MemoryPersist>>> {
+        let config = Config::from_toml(OLD).unwrap();
+        let mut lb = LoadBalancer::new(config, MemoryPersist::new()).unwrap();
+        lb.services
+            .add_preauthed(preauthed(), Arc::downgrade(conn))
+            .unwrap();
+        Arc::new(Mutex::new(lb))
+    }
+
+    fn preauthed() -> Preauthed {
+        Preauthed::new(
+            "example.com",
+            "a.example.com",
+            "/",
+            BalanceStrategy::default(),
+            false,
+            None,
+        )
+    }
+
+    fn routed_id(lb: &Arc<Mutex<LoadBalancer<MemoryPersist>>>) -> Option<u64> {
+        let req = http::Request::get("http://a.example.com/")
+            .body(())
+            .unwrap();
+        let mut lock = lb.lock().unwrap();
+        lock.services.route(&req).ok().map(|r| r.conn.id())
+    }
+
+    #[test]
+    fn test_reload_keeps_connections() {
+        let mut rt = Runtime::new().unwrap();
+        let conns = connections(&mut rt, 1);
+        let lb = load_balancer(&conns[0]);
+        let mut closed = conns[0].closed();
+
+        let file = ConfigFile::new(NEW);
+        reload(&lb, &file.0).unwrap();
+
+        {
+            let lock = lb.lock().unwrap();
+            assert_eq!(lock.config, Config::from_toml(NEW).unwrap());
+            assert!(lock.services.is_valid_secret(&preauthed(), "new"));
+            assert!(!lock.services.is_valid_secret(&preauthed(), "old"));
+            assert!(lock.services.is_authority("a.other.com"));
+        }
+        assert_eq!(routed_id(&lb), Some(conns[0].id()));
+        assert!(closed.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_reload_bad_config() {
+        let mut rt = Runtime::new().unwrap();
+        let conns = connections(&mut rt, 1);
+        let lb = load_balancer(&conns[0]);
+        let account = lb.lock().unwrap().account.clone();
+
+        let file = ConfigFile::new("[[domains]]");
+        assert!(reload(&lb, &file.0).is_err());
+        // valid toml, but not a valid config.
+        file.write(&NEW.replace("admin@example.com", "nope"));
+        assert!(reload(&lb, &file.0).is_err());
+        assert!(reload(&lb, &file.0.with_extension("missing")).is_err());
+
+        let lock = lb.lock().unwrap();
+        assert_eq!(lock.config, Config::from_toml(OLD).unwrap());
+        assert!(lock.services.is_valid_secret(&preauthed(), "old"));
+        assert!(!lock.services.is_authority("a.other.com"));
+        assert!(Arc::ptr_eq(&lock.account, &account));
+        drop(lock);
+        assert_eq!(routed_id(&lb), Some(conns[0].id()));
+    }
+
+    #[test]
+    fn test_watch_config() {
+        let mut rt = Runtime::new().unwrap();
+        let conns = connections(&mut rt, 1);
+        let lb = load_balancer(&conns[0]);
+        let file = ConfigFile::new(OLD);
+
+        let reloaded = rt.block_on(async {
+            let watch = watch_config(lb.clone(), file.0.clone(), Duration::from_millis(10));
+            let check = async {
+                // the watcher starts from the current modification time.
+                tokio_timer::delay_for(Duration::from_millis(50)).await;
+                file.write(NEW);
+                for _ in 0..200 {
+                    if lb.lock().unwrap().config == Config::from_toml(NEW).unwrap() {
+                        return true;
+                    }
+                    tokio_timer::delay_for(Duration::from_millis(10)).await;
+                }
+                false
+            };
+            pin_mut!(watch);
+            pin_mut!(check);
+            match select(watch, check).await {
+                Either::Left((res, _)) => panic!("Watch ended: {:?}", res),
+                Either::Right((reloaded, _)) => reloaded,
+            }
+        });
+        assert!(reloaded);
+        assert_eq!(routed_id(&lb), Some(conns[0].id()));
+    }
+}
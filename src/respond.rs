@@ -1,35 +1,89 @@
 use crate::body::Http11Body;
-use crate::body::PollCapacity;
+use crate::body::{allowed_trailers, PollCapacity};
 use crate::chunked::ChunkedEncoder;
-use crate::error::LolbResult;
-use crate::http11;
+use crate::error::{LolbError, LolbResult};
+use crate::http11::{self, KeepAlive};
 use crate::limit::LimitWrite;
 use crate::peek::Peekable;
+use crate::serv_conn::InFlight;
 use crate::AsyncWriteExt;
 use crate::Socket;
 use bytes::Bytes;
 use h2::server::SendResponse;
 use h2::RecvStream;
-use std::io;
 
 pub(crate) enum Responder<'a, S>
 where
     S: Socket,
 {
     Http2(SendResponse<Bytes>),
-    Http11(&'a mut Peekable<S>),
+    /// The keep-alive is updated if the response forces the connection to close.
+    Http11(&'a mut Peekable<S>, &'a mut KeepAlive),
 }
 
 impl<'a, S: Socket> Responder<'a, S> {
-    pub async fn send_response(self, res_body: http::Response<h2::RecvStream>) -> LolbResult<()> {
-        let (part, body) = res_body.into_parts();
-        let res = http::Response::from_parts(part, ());
+    /// Send the response from the service to the client. The optional cookie is added
+    /// as a `set-cookie` header to pin the client to the service connection.
+    pub async fn send_response(
+        self,
+        res_body: http::Response<h2::RecvStream>,
+        set_cookie: Option<http::HeaderValue>,
+    ) -> LolbResult<()> {
+        let (mut part, body) = res_body.into_parts();
+        // the request is in flight until the entire body is sent.
+        let _in_flight = part.extensions.remove::<InFlight>();
+        let mut res = http::Response::from_parts(part, ());
+        if let Some(cookie) = set_cookie {
+            // append, to not clobber any cookies set by the service itself.
+            res.headers_mut().append("set-cookie", cookie);
+        }
         match self {
             Responder::Http2(send_res) => {
                 send_response_http2(send_res, res, body).await?;
             }
-            Responder::Http11(socket) => {
-                send_response_http1(&mut socket.wrapped, res, body).await?;
+            Responder::Http11(socket, keep_alive) => {
+                send_response_http1(&mut socket.wrapped, res, body, keep_alive).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Respond to the client with the http status of the error. Errors that don't
+    /// correspond to a http status are returned as is.
+    pub async fn send_error(self, err: LolbError) -> LolbResult<()> {
+        let status = match err.status() {
+            Some(s) => s,
+            None => return Err(err),
+        };
+        debug!("Respond with error: {}", err);
+        let body = Bytes::from(format!(
+            "{} {}\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or("Unknown")
+        ));
+        let res = http::Response::builder()
+            .status(status)
+            .header("content-type", "text/plain; charset=utf-8")
+            .body(())?;
+        self.send_body(res, body).await
+    }
+
+    /// Send a response generated by the load balancer itself, with a body that
+    /// is known up front.
+    pub async fn send_body(self, mut res: http::Response<()>, body: Bytes) -> LolbResult<()> {
+        res.headers_mut()
+            .insert("content-length", http::HeaderValue::from(body.len()));
+        match self {
+            Responder::Http2(mut send_res) => {
+                let mut send_body = send_res.send_response(res, false)?;
+                send_body.send_data(body, true)?; // true here is end-of-stream
+            }
+            Responder::Http11(socket, keep_alive) => {
+                keep_alive.set_header(res.headers_mut());
+                let mut header = Vec::with_capacity(4096);
+                http11::write_http11_response(&mut header, res)?;
+                AsyncWriteExt::write_all(&mut socket.wrapped, &header[..]).await?;
+                AsyncWriteExt::write_all(&mut socket.wrapped, &body[..]).await?;
             }
         }
         Ok(())
@@ -66,9 +120,14 @@ async fn send_response_http2(
             body_data = body_data.slice_from(send_len);
         }
     }
-    // no more body data
-    let empty = bytes::Bytes::new();
-    send_body.send_data(empty, true)?; // true here is end-of-stream
+    // no more body data, the trailers end the stream if there are any.
+    match body.trailers().await?.and_then(allowed_trailers) {
+        Some(trailers) => send_body.send_trailers(trailers)?,
+        None => {
+            let empty = bytes::Bytes::new();
+            send_body.send_data(empty, true)?; // true here is end-of-stream
+        }
+    }
     Ok(())
 }
 
@@ -76,7 +135,10 @@ async fn send_response_http1<S: Socket>(
     socket: &mut S,
     mut res: http::Response<()>,
     mut body: RecvStream,
+    keep_alive: &mut KeepAlive,
 ) -> LolbResult<()> {
+    http11::response_to_http11(res.headers_mut());
+
     // figure out if we are to send the response as chunked.
     let content_len = res
         .headers()
@@ -84,7 +146,14 @@ async fn send_response_http1<S: Socket>(
         .and_then(|h| h.to_str().ok())
         .and_then(|s| s.parse::<usize>().ok());
     let is_end = body.is_end_stream();
-    let send_chunked = content_len.is_none() && !is_end;
+    let unknown_len = content_len.is_none() && !is_end;
+    let send_chunked = unknown_len && !keep_alive.http10;
+
+    if unknown_len && keep_alive.http10 {
+        // http/1.0 has no chunked, closing the connection ends the body.
+        keep_alive.close = true;
+    }
+    keep_alive.set_header(res.headers_mut());
 
     // add chunked header
     if send_chunked {
@@ -96,9 +165,8 @@ async fn send_response_http1<S: Socket>(
     }
 
     // write the http1.1 header into a buffer
-    let mut buf = io::Cursor::new(vec![0_u8; 4096]);
-    http11::write_http11_response(&mut buf, res)?;
-    let header = buf.into_inner();
+    let mut header = Vec::with_capacity(4096);
+    http11::write_http11_response(&mut header, res)?;
 
     // async write header to the socket
     AsyncWriteExt::write_all(socket, &header[..]).await?;
@@ -106,23 +174,308 @@ async fn send_response_http1<S: Socket>(
     // wrapper object depending on how we are to send the body
     let mut http11body = if send_chunked {
         Http11Body::Chunked(ChunkedEncoder(socket))
+    } else if unknown_len {
+        Http11Body::Unlimited(socket)
     } else {
         match content_len {
-            None => Http11Body::NoBody,
-            Some(size) => Http11Body::Limited(LimitWrite::new(socket, size)),
+            // a response to HEAD has a content-length, but no body.
+            Some(size) if !is_end => Http11Body::Limited(LimitWrite::new(socket, size)),
+            _ => Http11Body::NoBody,
         }
     };
 
-    // send body.
+    // send body. empty data is skipped by the chunked encoder.
     while let Some(chunk) = body.data().await {
         let body_data = chunk?;
-        if body_data.is_empty() {
-            break;
-        }
         http11body.send_chunk(body_data).await?;
     }
 
-    http11body.send_finish().await?;
+    // trailers can only be sent when chunked.
+    let trailers = body.trailers().await?;
+    http11body.send_finish(trailers.as_ref()).await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::body::RecvBody;
+    use crate::chunked::ChunkedDecoder;
+    use crate::conn::test::{duplex, Duplex};
+    use crate::conn::{Connection, HttpVersion};
+    use crate::limit::LimitRead;
+    use crate::serv_conn::ServiceConnection;
+    use crate::StatusKind;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_net::tcp::{TcpListener, TcpStream};
+
+    /// Service echoing the request body, and responding with the request trailers
+    /// plus a `grpc-status` trailer.
+    async fn serve(tcp: TcpStream) {
+        let mut h2 = h2::server::handshake(tcp).await.unwrap();
+        while let Some(accepted) = h2.accept().await {
+            let (req, mut send_res) = accepted.unwrap();
+            tokio::spawn(async move {
+                let mut body = req.into_body();
+                let mut data = vec![];
+                while let Some(chunk) = body.data().await {
+                    let chunk = chunk.unwrap();
+                    body.release_capacity()
+                        .release_capacity(chunk.len())
+                        .unwrap();
+                    data.extend_from_slice(&chunk[..]);
+                }
+                let mut trailers = body.trailers().await.unwrap().unwrap_or_default();
+                trailers.insert("grpc-status", "0".parse().unwrap());
+
+                let res = http::Response::builder().body(()).unwrap();
+                let mut send_body = send_res.send_response(res, false).unwrap();
+                send_body.send_data(data.into(), false).unwrap();
+                send_body.send_trailers(trailers).unwrap();
+            });
+        }
+    }
+
+    /// Connect to a new service.
+    async fn service() -> ServiceConnection {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            serve(tcp).await;
+        });
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let (send_req, conn) = h2::client::handshake(tcp).await.unwrap();
+        tokio::spawn(async move {
+            conn.await.ok();
+        });
+        ServiceConnection::new(send_req)
+    }
+
+    #[test]
+    fn test_trailers_http11_to_service_and_back() {
+        let mut rt = Runtime::new().unwrap();
+        let output = rt.block_on(async {
+            let service = service().await;
+
+            let input = b"5\r\nhello\r\n0\r\nx-checksum: abc\r\ncontent-length: 5\r\n\r\n";
+            let mut socket = Peekable::new(duplex(input));
+
+            let body = RecvBody::Http11Chunked(ChunkedDecoder::new(&mut socket));
+            let req = http::Request::builder()
+                .uri("http://a.example.com/")
+                .body(body)
+                .unwrap();
+            let res = service.clone().send_request(req).await.unwrap();
+            // the request counts as in flight until the response body is sent.
+            assert_eq!(service.in_flight(), 1);
+
+            Responder::Http11(&mut socket, &mut KeepAlive::default())
+                .send_response(res, None)
+                .await
+                .unwrap();
+            assert_eq!(service.in_flight(), 0);
+            socket.wrapped.output
+        });
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(
+            output.contains("transfer-encoding: chunked\r\n"),
+            "{}",
+            output
+        );
+        // the forbidden content-length trailer is not sent to the service.
+        assert!(
+            output
+                .ends_with("\r\n\r\n5\r\nhello\r\n0\r\nx-checksum: abc\r\ngrpc-status: 0\r\n\r\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_trailers_http2_to_service_and_back() {
+        let mut rt = Runtime::new().unwrap();
+        let (data, trailers) = rt.block_on(async {
+            let service = service().await;
+
+            // the load balancer side of a http2 client connection.
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let mut h2 = h2::server::handshake(tcp).await.unwrap();
+                while let Some(accepted) = h2.accept().await {
+                    let (req, send_res) = accepted.unwrap();
+                    let (parts, body) = req.into_parts();
+                    let req = http::Request::from_parts(parts, RecvBody::<Duplex>::Http2(body));
+                    let service = service.clone();
+                    tokio::spawn(async move {
+                        let res = service.send_request(req).await.unwrap();
+                        Responder::<Duplex>::Http2(send_res)
+                            .send_response(res, None)
+                            .await
+                            .unwrap();
+                    });
+                }
+            });
+
+            let tcp = TcpStream::connect(addr).await.unwrap();
+            let (send_req, conn) = h2::client::handshake(tcp).await.unwrap();
+            tokio::spawn(async move {
+                conn.await.ok();
+            });
+            let mut send_req = send_req.ready().await.unwrap();
+            let req = http::Request::builder()
+                .uri("http://a.example.com/")
+                .body(())
+                .unwrap();
+            let (res, mut send_body) = send_req.send_request(req, false).unwrap();
+            send_body.send_data("hello".into(), false).unwrap();
+            let mut trailers = http::HeaderMap::new();
+            trailers.insert("x-checksum", "abc".parse().unwrap());
+            send_body.send_trailers(trailers).unwrap();
+
+            let mut body = res.await.unwrap().into_body();
+            let mut data = vec![];
+            while let Some(chunk) = body.data().await {
+                data.extend_from_slice(&chunk.unwrap()[..]);
+            }
+            (data, body.trailers().await.unwrap())
+        });
+
+        assert_eq!(&data[..], b"hello");
+        let trailers = trailers.unwrap();
+        assert_eq!(trailers.get("x-checksum").unwrap(), "abc");
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_http10_response_closes() {
+        let mut rt = Runtime::new().unwrap();
+        let (output, keep_alive) = rt.block_on(async {
+            let service = service().await;
+            let mut socket = Peekable::new(duplex(b"hello"));
+
+            let body = RecvBody::Http11Plain(LimitRead::new(&mut socket, 5));
+            let req = http::Request::builder()
+                .uri("http://a.example.com/")
+                .body(body)
+                .unwrap();
+            let res = service.send_request(req).await.unwrap();
+
+            let mut keep_alive = KeepAlive {
+                http10: true,
+                close: false,
+            };
+            Responder::Http11(&mut socket, &mut keep_alive)
+                .send_response(res, None)
+                .await
+                .unwrap();
+            (socket.wrapped.output, keep_alive)
+        });
+
+        // without a length, the body ends when the connection closes.
+        assert!(keep_alive.close);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("connection: close\r\n"), "{}", output);
+        assert!(!output.contains("transfer-encoding"), "{}", output);
+        assert!(output.ends_with("\r\n\r\nhello"), "{}", output);
+    }
+
+    #[test]
+    fn test_keep_alive_header() {
+        let mut rt = Runtime::new().unwrap();
+        let output = rt.block_on(async {
+            let mut socket = Peekable::new(duplex(b""));
+            let mut keep_alive = KeepAlive {
+                http10: true,
+                close: false,
+            };
+            let res = http::Response::builder().body(()).unwrap();
+            Responder::Http11(&mut socket, &mut keep_alive)
+                .send_body(res, "ok".into())
+                .await
+                .unwrap();
+            socket.wrapped.output
+        });
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("connection: keep-alive\r\n"), "{}", output);
+        assert!(output.ends_with("\r\n\r\nok"), "{}", output);
+    }
+
+    #[test]
+    fn test_hop_by_hop_to_service() {
+        let mut rt = Runtime::new().unwrap();
+        let output = rt.block_on(async {
+            let service = service().await;
+            // http2 refuses connection specific headers, they must not reach the service.
+            let input =
+                b"POST / HTTP/1.1\r\nHost: a.example.com\r\nConnection: keep-alive, x-foo\r\n\
+                          X-Foo: 1\r\nKeep-Alive: timeout=5\r\nUpgrade: h2c\r\nTE: trailers\r\n\
+                          Content-Length: 2\r\n\r\nhi";
+            let addr = "127.0.0.1:1".parse().unwrap();
+            let mut conn = Connection::new(duplex(input), addr, HttpVersion::Http11, false);
+            let mut req = http11::parse_http11(&mut conn).await.unwrap().unwrap();
+            let mut keep_alive = req.extensions_mut().remove::<KeepAlive>().unwrap();
+            let res = service.send_request(req).await.unwrap();
+            Responder::Http11(conn.socket(), &mut keep_alive)
+                .send_response(res, None)
+                .await
+                .unwrap();
+            conn.into_socket().wrapped.output
+        });
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(
+            output.ends_with("\r\n\r\n2\r\nhi\r\n0\r\ngrpc-status: 0\r\n\r\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_send_error() {
+        let mut rt = Runtime::new().unwrap();
+        let kinds = [
+            (StatusKind::BadRequest, "HTTP/1.1 400 Bad Request\r\n"),
+            (StatusKind::NotFound, "HTTP/1.1 404 Not Found\r\n"),
+            (StatusKind::BadGateway, "HTTP/1.1 502 Bad Gateway\r\n"),
+            (
+                StatusKind::GatewayTimeout,
+                "HTTP/1.1 504 Gateway Timeout\r\n",
+            ),
+        ];
+        for (kind, status_line) in &kinds {
+            let output = rt.block_on(async {
+                let mut socket = Peekable::new(duplex(b""));
+                Responder::Http11(&mut socket, &mut KeepAlive::default())
+                    .send_error(LolbError::Status(*kind, "x"))
+                    .await
+                    .unwrap();
+                socket.wrapped.output
+            });
+            let output = String::from_utf8(output).unwrap();
+            // the body is the status, like `404 Not Found`.
+            let body = format!("\r\n\r\n{}\n", status_line[9..].trim_end());
+            assert!(output.starts_with(status_line), "{}", output);
+            assert!(output.contains("content-length: "), "{}", output);
+            assert!(output.ends_with(&body), "{}", output);
+        }
+
+        // errors without a status are not answered, the connection is dropped.
+        let (err, output) = rt.block_on(async {
+            let mut socket = Peekable::new(duplex(b""));
+            let err = Responder::Http11(&mut socket, &mut KeepAlive::default())
+                .send_error(LolbError::Message("broken"))
+                .await
+                .unwrap_err();
+            (err, socket.wrapped.output)
+        });
+        assert_eq!(err.to_string(), "broken");
+        assert!(output.is_empty());
+    }
+}
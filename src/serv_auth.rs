@@ -1,3 +1,5 @@
+use crate::balance::BalanceStrategy;
+use crate::hashring::HashKey;
 use crate::service::{ServiceDomain, ServiceHost, ServiceRoute};
 use crate::util::current_time_millis;
 use serde::{Deserialize, Serialize};
@@ -8,22 +10,70 @@ use std::time::Duration;
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub(crate) struct ReconnectKey(pub u64);
 
+/// For how long a reconnect key is valid after the auth.
+pub(crate) const PREAUTH_VALIDITY: Duration = Duration::from_secs(10);
+
+/// Response to the service on a successful auth.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AuthResponse {
+    /// The reconnect key to send after the `lolb` prefix when reconnecting.
+    pub key: u64,
+    /// Unix time millis when the key is no longer valid.
+    pub expires: u64,
+}
+
 /// A preauthed record with information service provided as part of the auth.
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Preauthed {
-    created: u64, // unix time millis
+    /// Unix time millis. Set by the load balancer when the auth succeeds.
+    #[serde(default)]
+    created: u64,
     domain: String,
     host: String,
     prefix: String,
+    /// How to balance requests between connections of the route. The first
+    /// connection to create the route decides.
+    #[serde(default)]
+    balance: BalanceStrategy,
+    /// Whether clients are to be pinned to the same connection using a cookie.
+    #[serde(default)]
+    sticky: bool,
+    /// Route using consistent hashing of this part of the request.
+    #[serde(default)]
+    hash: Option<HashKey>,
 }
 
 impl Preauthed {
+    pub(crate) fn new(
+        domain: &str,
+        host: &str,
+        prefix: &str,
+        balance: BalanceStrategy,
+        sticky: bool,
+        hash: Option<HashKey>,
+    ) -> Self {
+        Preauthed {
+            created: 0,
+            domain: domain.to_string(),
+            host: host.to_string(),
+            prefix: prefix.to_string(),
+            balance,
+            sticky,
+            hash,
+        }
+    }
     pub(crate) fn created(&self) -> u64 {
         self.created
     }
+    pub(crate) fn set_created_now(&mut self) {
+        self.created = current_time_millis();
+    }
+    pub(crate) fn expires(&self) -> u64 {
+        self.created + PREAUTH_VALIDITY.as_millis() as u64
+    }
     pub(crate) fn is_valid(&self) -> bool {
-        let age = Duration::from_millis(current_time_millis() - self.created);
-        age < Duration::from_secs(10)
+        let age = Duration::from_millis(current_time_millis().saturating_sub(self.created));
+        age < PREAUTH_VALIDITY
     }
     pub(crate) fn domain(&self) -> &str {
         &self.domain
@@ -34,13 +84,49 @@ impl Preauthed {
     pub(crate) fn prefix(&self) -> &str {
         &self.prefix
     }
+    pub(crate) fn balance(&self) -> BalanceStrategy {
+        self.balance
+    }
+    pub(crate) fn sticky(&self) -> bool {
+        self.sticky
+    }
+    pub(crate) fn hash(&self) -> Option<&HashKey> {
+        self.hash.as_ref()
+    }
     pub(crate) fn is_same_domain(&self, s: &ServiceDomain) -> bool {
-        self.domain == s.domain()
+        self.domain.eq_ignore_ascii_case(s.domain())
     }
     pub(crate) fn is_same_host(&self, s: &ServiceHost) -> bool {
-        self.host == s.host()
+        self.host.eq_ignore_ascii_case(s.host())
     }
     pub(crate) fn is_same_prefix(&self, s: &ServiceRoute) -> bool {
         self.prefix == s.prefix()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_auth_response_json() {
+        let res = AuthResponse {
+            key: 42,
+            expires: 1_570_000_000_000,
+        };
+        let json = serde_json::to_string(&res).unwrap();
+        assert_eq!(json, r#"{"key":42,"expires":1570000000000}"#);
+    }
+
+    #[test]
+    fn test_preauthed_json() {
+        let json = r#"{"domain":"example.com","host":"a.example.com","prefix":"/"}"#;
+        let p: Preauthed = serde_json::from_str(json).unwrap();
+        assert_eq!(p.domain(), "example.com");
+        assert_eq!(p.host(), "a.example.com");
+        assert_eq!(p.prefix(), "/");
+        assert_eq!(p.created(), 0);
+        assert!(!p.sticky());
+        assert!(p.hash().is_none());
+    }
+}
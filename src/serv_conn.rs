@@ -1,12 +1,79 @@
-use crate::body::PollCapacity;
+use crate::body::{allowed_trailers, PollCapacity};
 use crate::conn::Socket;
 use crate::{LolbResult, RecvBody};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio_sync::oneshot;
 
 /// Holder of the actual connection to the service.
 #[derive(Debug, Clone)]
-pub struct ServiceConnection(pub h2::client::SendRequest<bytes::Bytes>);
+pub struct ServiceConnection {
+    /// Random id identifying this connection, i.e. in affinity cookies.
+    id: u64,
+    send_req: h2::client::SendRequest<bytes::Bytes>,
+    /// Number of requests currently in flight. Shared between all clones.
+    in_flight: Arc<AtomicUsize>,
+    /// Tells the driver of the connection to stop driving it. Shared between all clones.
+    close: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+}
+
+/// Keeps the in flight count up for as long as it is alive. It goes with the response
+/// extensions, so a request is counted until the response body is sent to the client.
+pub(crate) struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
 impl ServiceConnection {
+    pub(crate) fn new(send_req: h2::client::SendRequest<bytes::Bytes>) -> Self {
+        ServiceConnection {
+            id: rand::random(),
+            send_req,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            close: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Resolves when the connection is closed using `close()`. Used by the driver of the
+    /// connection, which drops it when closed.
+    pub(crate) fn closed(&self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        *self.close.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// Close the connection, i.e. when its domain is no longer serviced.
+    pub(crate) fn close(&self) {
+        if let Some(tx) = self.close.lock().unwrap().take() {
+            // fails only if the driver is already gone.
+            tx.send(()).ok();
+        }
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Number of requests currently in flight to this service connection.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Count a request as in flight until the returned value is dropped.
+    pub(crate) fn start_request(&self) -> InFlight {
+        InFlight::new(&self.in_flight)
+    }
+
     /// Send request + request body to service.
     pub(crate) async fn send_request<'a, S>(
         self,
@@ -15,8 +82,10 @@ impl ServiceConnection {
     where
         S: Socket,
     {
+        let in_flight = self.start_request();
+
         // wait for h2 conn to be ready to receive req
-        let mut h2 = self.0.ready().await?;
+        let mut h2 = self.send_req.ready().await?;
 
         // reconstitute req to Request<()>
         let (parts, mut body) = req.into_parts();
@@ -46,13 +115,21 @@ impl ServiceConnection {
                     body_data = body_data.slice_from(send_len);
                 }
             } else {
-                // no more body data
-                let empty = bytes::Bytes::new();
-                send_body.send_data(empty, true)?; // true here is end-of-stream
+                // no more body data, the trailers end the stream if there are any.
+                match body.trailers().await?.and_then(allowed_trailers) {
+                    Some(trailers) => send_body.send_trailers(trailers)?,
+                    None => {
+                        let empty = bytes::Bytes::new();
+                        send_body.send_data(empty, true)?; // true here is end-of-stream
+                    }
+                }
                 break;
             }
         }
 
-        Ok(response.await?)
+        let mut res = response.await?;
+        res.extensions_mut().insert(in_flight);
+
+        Ok(res)
     }
 }
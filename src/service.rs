@@ -1,7 +1,11 @@
+use crate::balance::{BalanceStrategy, Balancer};
+use crate::hashring::{HashKey, HashRing};
 use crate::serv_auth::Preauthed;
 use crate::serv_conn::ServiceConnection;
+use crate::sticky::{affinity_cookie, set_affinity_cookie, StickyKey};
+use crate::tls::TlsCert;
 use crate::util::ArcExt;
-use acme_lib::Certificate;
+use crate::{DomainConfig, LolbError, LolbResult, StatusKind};
 use serde::{Deserialize, Serialize};
 use std::sync::Weak;
 
@@ -16,6 +20,18 @@ pub(crate) const PREAUTH_PREFIX: &[u8] = b"lolb";
 #[derive(Debug, Default)]
 pub(crate) struct Services {
     domains: Vec<ServiceDomain>,
+    /// Secret for signing affinity cookies of sticky routes.
+    sticky_key: StickyKey,
+}
+
+/// The result of routing a request.
+#[derive(Debug)]
+pub(crate) struct Routed {
+    /// The service connection to send the request to.
+    pub conn: ServiceConnection,
+    /// Affinity cookie to set in the response when a client is to be pinned to
+    /// the connection of a sticky route.
+    pub set_cookie: Option<http::HeaderValue>,
 }
 
 /// Domain to be serviced by a load balancer.
@@ -25,13 +41,17 @@ pub(crate) struct ServiceDomain {
     domain: String,
     /// Auth to use when adding service connections to this domain.
     auth: ServiceAuth,
+    /// Whether hosts under the domain use the wildcard certificate in `cert`.
+    wildcard: bool,
+    /// Wildcard TLS certificate `*.example.com`. If known.
+    cert: Option<TlsCert>,
     /// The current set of of hosts serviced under the domain. A host
     /// would be something like `myservice.example.com`.
     hosts: Vec<ServiceHost>,
 }
 
 /// Kinds of authentications for authenticating connections added to the service domain.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ServiceAuth {
     /// Some secret string shared between the load balancer and the service.
     PresharedKey(String),
@@ -43,6 +63,12 @@ impl ServiceAuth {
             ServiceAuth::PresharedKey(x) => secret == x,
         }
     }
+    /// Tells if there is anything to auth against.
+    pub(crate) fn is_configured(&self) -> bool {
+        match self {
+            ServiceAuth::PresharedKey(x) => !x.is_empty(),
+        }
+    }
 }
 
 /// Service host gather a bunch of routes for that host. It is possible to route
@@ -53,7 +79,7 @@ pub(crate) struct ServiceHost {
     /// The service host name. Something like `myservice.example.com`.
     host: String,
     /// TLS certificate needed to service this domain. If known.
-    cert: Option<Certificate>,
+    cert: Option<TlsCert>,
     /// Current routes for the host. When selecting route the longest route prefix
     /// wins.
     routes: Vec<ServiceRoute>,
@@ -68,14 +94,60 @@ pub(crate) struct ServiceRoute {
     /// Current connections servicing this route. The strong reference is held by the
     /// closure driving the connection.
     connections: Vec<Weak<ServiceConnection>>,
+    /// Whether clients are pinned to a connection using an affinity cookie.
+    sticky: bool,
+    /// Part of request to use for consistent hashing onto `ring`.
+    hash_key: Option<HashKey>,
+    /// Ring of the connections when routing with consistent hashing.
+    ring: HashRing,
+    /// Picks which of the connections to use for the next request.
+    balancer: Box<dyn Balancer>,
 }
 
 impl Services {
-    pub fn new() -> Self {
+    pub fn new(domains: &[DomainConfig]) -> Self {
         Services {
+            domains: domains.iter().map(ServiceDomain::new).collect(),
             ..Default::default()
         }
     }
+    /// Update the serviced domains to the given config. Domains that are still configured
+    /// keep their hosts and connections, and only get their auth swapped. Removed domains
+    /// are dropped from routing, and their connections closed.
+    pub fn apply(&mut self, domains: &[DomainConfig]) {
+        let configured: Vec<String> = domains
+            .iter()
+            .map(|d| d.domain.to_ascii_lowercase())
+            .collect();
+
+        self.domains.retain(|d| {
+            let keep = configured.contains(&d.domain);
+            if !keep {
+                info!("Removing domain: {}", d.domain);
+                d.close_connections();
+            }
+            keep
+        });
+
+        for (config, name) in domains.iter().zip(configured.iter()) {
+            match self.domains.iter_mut().find(|d| &d.domain == name) {
+                Some(existing) => {
+                    if existing.auth != config.auth {
+                        info!("Changing auth for domain: {}", name);
+                        existing.auth = config.auth.clone();
+                    }
+                    if existing.wildcard != config.wildcard {
+                        info!("Changing wildcard for domain: {}", name);
+                        existing.wildcard = config.wildcard;
+                    }
+                }
+                None => {
+                    info!("Adding domain: {}", name);
+                    self.domains.push(ServiceDomain::new(config));
+                }
+            }
+        }
+    }
     pub fn is_valid_secret(&self, p: &Preauthed, secret: &str) -> bool {
         let service = self.domains.iter().find(|s| p.is_same_domain(s));
         if let Some(service) = service {
@@ -85,21 +157,159 @@ impl Services {
         }
         false
     }
-    pub fn add_preauthed(&mut self, p: Preauthed, c: Weak<ServiceConnection>) {
+    /// Tells if the host of the preauthed is the domain, or a host under the domain.
+    pub fn is_valid_host(&self, p: &Preauthed) -> bool {
+        self.domains
+            .iter()
+            .find(|s| p.is_same_domain(s))
+            .map(|s| s.is_serving(p.host()))
+            .unwrap_or(false)
+    }
+    pub fn add_preauthed(&mut self, p: Preauthed, c: Weak<ServiceConnection>) -> LolbResult<()> {
         let service = self
             .domains
             .iter_mut()
             .find(|s| p.is_same_domain(s))
-            // we shouldn't be able to have created a preauthed instance for
-            // a domain that doesn't exist, so this is a fault.
-            .expect("Preauthed for not configured domain");
+            // the domain may have been removed by a config reload after the
+            // preauthed instance was created.
+            .ok_or_else(|| {
+                LolbError::Owned(format!("Preauthed for removed domain: {}", p.domain()))
+            })?;
+        if !service.is_serving(p.host()) {
+            return Err(LolbError::Owned(format!(
+                "Preauthed host {} not in domain: {}",
+                p.host(),
+                p.domain()
+            )));
+        }
         service.add_preauthed(p, c);
+        Ok(())
+    }
+
+    /// The serviced domain of the name. The longest domain wins if several match.
+    pub fn domain_of(&self, name: &str) -> Option<&str> {
+        self.domains
+            .iter()
+            .filter(|d| d.is_serving(name))
+            .max_by_key(|d| d.domain.len())
+            .map(|d| d.domain())
+    }
+
+    /// Tells if the name is the domain, or a name under the domain, of any serviced domain.
+    pub fn is_authority(&self, name: &str) -> bool {
+        self.domains.iter().any(|d| d.is_serving(name))
+    }
+
+    /// Tells if the host is serviced and has at least one live connection.
+    pub fn is_live_host(&self, host: &str) -> bool {
+        self.domains
+            .iter()
+            .filter(|d| d.is_serving(host))
+            .max_by_key(|d| d.domain.len())
+            .and_then(|d| d.hosts.iter().find(|h| h.host.eq_ignore_ascii_case(host)))
+            .map(|h| h.routes.iter().any(|r| r.is_live()))
+            .unwrap_or(false)
+    }
+
+    /// Tells if there is a serviced host with at least one live connection under the
+    /// name, which makes the name an empty non-terminal if it isn't a host itself.
+    pub fn has_live_host_under(&self, name: &str) -> bool {
+        let suffix = format!(".{}", name.to_ascii_lowercase());
+        self.domains
+            .iter()
+            .filter(|d| d.is_serving(name))
+            .flat_map(|d| d.hosts.iter())
+            .filter(|h| h.host.ends_with(&suffix))
+            .any(|h| h.routes.iter().any(|r| r.is_live()))
+    }
+
+    /// The TLS certificate for the host, if the host is serviced and has one. Hosts
+    /// of wildcard domains get the wildcard certificate.
+    pub fn cert_for(&self, host: &str) -> Option<&TlsCert> {
+        let domain = self
+            .domains
+            .iter()
+            .filter(|d| d.is_serving(host))
+            .max_by_key(|d| d.domain.len())?;
+        if domain.is_wildcard_for(host) {
+            return domain.cert.as_ref();
+        }
+        domain
+            .hosts
+            .iter()
+            .find(|h| h.host.eq_ignore_ascii_case(host))
+            .and_then(|h| h.cert.as_ref())
+    }
+
+    /// Names without a TLS certificate, or with one expiring before `renew_at` unix millis.
+    /// Wildcard domains need a certificate for `*.example.com`, and hosts under them only
+    /// when the wildcard doesn't cover the host.
+    pub fn hosts_needing_cert(&self, renew_at: u64) -> Vec<String> {
+        let needs = |cert: &Option<TlsCert>| match cert {
+            None => true,
+            // certificates without a known expiry are not ours to renew.
+            Some(c) => c.expires().map(|e| e <= renew_at).unwrap_or(false),
+        };
+        let wildcards = self
+            .domains
+            .iter()
+            .filter(|d| d.wildcard && needs(&d.cert))
+            .map(|d| format!("*.{}", d.domain));
+        let hosts = self
+            .domains
+            .iter()
+            // only hosts under the domain the service authed for.
+            .flat_map(|d| {
+                d.hosts
+                    .iter()
+                    .filter(move |h| d.is_serving(&h.host) && !d.is_wildcard_for(&h.host))
+            })
+            .filter(|h| needs(&h.cert))
+            .map(|h| h.host.clone());
+        wildcards.chain(hosts).collect()
+    }
+
+    /// Set the TLS certificate of a serviced host, or of a wildcard domain when the
+    /// name is `*.example.com`. Returns false if the name isn't known.
+    pub fn set_cert(&mut self, host: &str, cert: TlsCert) -> bool {
+        if let Some(name) = host.strip_prefix("*.") {
+            let name = name.to_ascii_lowercase();
+            return match self.domains.iter_mut().find(|d| d.domain == name) {
+                Some(d) => {
+                    d.cert = Some(cert);
+                    true
+                }
+                None => false,
+            };
+        }
+        let found = self
+            .domains
+            .iter_mut()
+            .filter(|d| d.is_serving(host))
+            .max_by_key(|d| d.domain.len())
+            .and_then(|d| {
+                d.hosts
+                    .iter_mut()
+                    .find(|h| h.host.eq_ignore_ascii_case(host))
+            });
+        match found {
+            Some(h) => {
+                h.cert = Some(cert);
+                true
+            }
+            None => false,
+        }
     }
 
     /// Route the request to a service.
-    pub fn route<X>(&mut self, req: &http::Request<X>) -> Option<ServiceConnection> {
+    pub fn route<X>(&mut self, req: &http::Request<X>) -> LolbResult<Routed> {
         let uri = req.uri();
-        let host = uri.authority_part()?.host();
+        const NOT_FOUND: LolbError =
+            LolbError::Status(StatusKind::NotFound, "No service for request");
+        const UNAVAILABLE: LolbError =
+            LolbError::Status(StatusKind::ServiceUnavailable, "No live service connection");
+
+        let host = uri.authority_part().ok_or(NOT_FOUND)?.host();
         let path = uri.path_and_query().map(|p| p.path()).unwrap_or("/");
 
         // find something that matches domain ending i.e: `a.b.c.com` might match
@@ -107,15 +317,19 @@ impl Services {
         let mut domains: Vec<&mut ServiceDomain> = self
             .domains
             .iter_mut()
-            .filter(|s| s.domain.ends_with(host))
+            .filter(|s| s.is_serving(host))
             .collect();
         domains.as_mut_slice().sort_by_key(|d| d.domain.len());
 
         // the "best" domain is last.
-        let domain = domains.last_mut()?;
+        let domain = domains.last_mut().ok_or(NOT_FOUND)?;
 
         // the host must be an exact match.
-        let host = domain.hosts.iter_mut().find(|h| h.host == host)?;
+        let host = domain
+            .hosts
+            .iter_mut()
+            .find(|h| h.host.eq_ignore_ascii_case(host))
+            .ok_or(NOT_FOUND)?;
 
         // find all routes that has a prefix that matches the incoming request path.
         let mut routes: Vec<&mut ServiceRoute> = host
@@ -126,41 +340,78 @@ impl Services {
         routes.as_mut_slice().sort_by_key(|r| r.prefix.len());
 
         // the "best" is the last.
-        let route = routes.last_mut()?;
-
-        // find a connection that is alive.
-        // TODO sticky logic
-        let conn = loop {
-            // prune dead connections.
-            route.connections.retain(|c| c.upgrade().is_some());
-            if route.connections.is_empty() {
-                break None;
-            }
-            // find first connection that is not dead
-            if let Some(s) = route
-                .connections
-                .iter()
-                .find(|c| c.upgrade().is_some())
-                .and_then(|c| c.upgrade())
-            {
-                // ServiceConnection contains a h2 SendRequest, that we must clone to
-                // get "our own" instance to send requests to.
-                //
-                // At this point we hold a _strong_ reference
-                // to Arc<ServiceConnection> and it will not be gone by connection disconnecting.
-                // Whether it will work to send requests to is a whole other matter.
-                break Some(s.clone_contained());
-            }
-        }?;
+        let route = routes.last_mut().ok_or(NOT_FOUND)?;
+
+        // consistent hashing uses the request key to find a connection in the ring.
+        let hashed = route.hash_key.as_ref().and_then(|k| k.extract(req));
+
+        if !route.sticky {
+            let conn = route.pick_connection(None, hashed).ok_or(UNAVAILABLE)?;
+            return Ok(Routed {
+                conn,
+                set_cookie: None,
+            });
+        }
+
+        // a valid affinity cookie pins the request to a connection, if it is still alive.
+        let sticky_key = &self.sticky_key;
+        let pinned = affinity_cookie(req).and_then(|v| sticky_key.verify(v));
+        let conn = route.pick_connection(pinned, hashed).ok_or(UNAVAILABLE)?;
 
-        Some(conn)
+        // only issue a new cookie when the client isn't already pinned to the picked one.
+        let set_cookie = if pinned == Some(conn.id()) {
+            None
+        } else {
+            let secure = uri.scheme_str() == Some("https");
+            Some(set_affinity_cookie(
+                sticky_key,
+                conn.id(),
+                &route.prefix,
+                secure,
+            ))
+        };
+
+        Ok(Routed { conn, set_cookie })
     }
 }
 
 impl ServiceDomain {
+    fn new(config: &DomainConfig) -> Self {
+        ServiceDomain {
+            domain: config.domain.to_ascii_lowercase(),
+            auth: config.auth.clone(),
+            wildcard: config.wildcard,
+            cert: None,
+            hosts: vec![],
+        }
+    }
     pub fn domain(&self) -> &str {
         &self.domain
     }
+    /// Tells if the host is the domain itself, or a host under the domain.
+    pub fn is_serving(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        host == self.domain
+            || (host.ends_with(&self.domain)
+                && host[..host.len() - self.domain.len()].ends_with('.'))
+    }
+    /// Tells if the host is covered by the wildcard certificate of the domain. A wildcard
+    /// only covers one label, `a.example.com` but not `b.a.example.com`.
+    fn is_wildcard_for(&self, host: &str) -> bool {
+        self.wildcard
+            && self.is_serving(host)
+            && host.len() > self.domain.len()
+            && !host[..host.len() - self.domain.len() - 1].contains('.')
+    }
+    /// Close the live connections of all hosts under the domain.
+    fn close_connections(&self) {
+        let routes = self.hosts.iter().flat_map(|h| h.routes.iter());
+        for conn in routes.flat_map(|r| r.connections.iter()) {
+            if let Some(conn) = conn.upgrade() {
+                conn.close();
+            }
+        }
+    }
     /// add/create a routing entry for a preauthed service connection.
     pub fn add_preauthed(&mut self, p: Preauthed, c: Weak<ServiceConnection>) {
         let mut idx = self.hosts.iter().position(|h| p.is_same_host(h));
@@ -176,7 +427,7 @@ impl ServiceDomain {
 impl ServiceHost {
     fn new(host: &str) -> Self {
         ServiceHost {
-            host: host.to_string(),
+            host: host.to_ascii_lowercase(),
             cert: None,
             routes: vec![],
         }
@@ -189,7 +440,12 @@ impl ServiceHost {
         let mut idx = self.routes.iter().position(|r| p.is_same_prefix(r));
         if idx.is_none() {
             idx = Some(self.routes.len());
-            self.routes.push(ServiceRoute::new(p.prefix()));
+            self.routes.push(ServiceRoute::new(
+                p.prefix(),
+                p.balance(),
+                p.sticky(),
+                p.hash().cloned(),
+            ));
         }
         let route = self.routes.get_mut(idx.unwrap()).unwrap();
         route.add_connection(c);
@@ -197,10 +453,19 @@ impl ServiceHost {
 }
 
 impl ServiceRoute {
-    pub fn new(prefix: &str) -> Self {
+    pub fn new(
+        prefix: &str,
+        balance: BalanceStrategy,
+        sticky: bool,
+        hash_key: Option<HashKey>,
+    ) -> Self {
         ServiceRoute {
             prefix: prefix.to_string(),
             connections: vec![],
+            sticky,
+            hash_key,
+            ring: HashRing::default(),
+            balancer: balance.balancer(),
         }
     }
     pub fn prefix(&self) -> &str {
@@ -209,4 +474,495 @@ impl ServiceRoute {
     pub fn add_connection(&mut self, c: Weak<ServiceConnection>) {
         self.connections.push(c);
     }
+    /// Tells if any of the connections are still alive.
+    fn is_live(&self) -> bool {
+        self.connections.iter().any(|c| c.upgrade().is_some())
+    }
+
+    /// Pick a live connection. The pinned connection id is used if it is still
+    /// alive, then the hashed key is looked up in the ring, otherwise the route's
+    /// balancer picks.
+    fn pick_connection(
+        &mut self,
+        pinned: Option<u64>,
+        hashed: Option<Vec<u8>>,
+    ) -> Option<ServiceConnection> {
+        // upgrade all connections to have them stay alive while picking. dead ones
+        // are pruned.
+        let mut alive = Vec::with_capacity(self.connections.len());
+        self.connections.retain(|c| match c.upgrade() {
+            Some(s) => {
+                alive.push(s);
+                true
+            }
+            None => false,
+        });
+
+        // the Weak of a pinned connection may have died, then we fall back on balancing.
+        let pinned_idx = pinned.and_then(|id| alive.iter().position(|s| s.id() == id));
+
+        let hashed_idx = || {
+            let key = hashed?;
+            let ids: Vec<u64> = alive.iter().map(|s| s.id()).collect();
+            // the ring is rebuilt whenever connections come or go.
+            if !self.ring.is_for(&ids) {
+                self.ring = HashRing::new(&ids);
+            }
+            let id = self.ring.lookup(&key)?;
+            alive.iter().position(|s| s.id() == id)
+        };
+
+        let idx = match pinned_idx.or_else(hashed_idx) {
+            Some(idx) => idx,
+            None => {
+                let in_flight: Vec<usize> = alive.iter().map(|s| s.in_flight()).collect();
+                self.balancer.pick(&in_flight)?
+            }
+        };
+
+        // ServiceConnection contains a h2 SendRequest, that we must clone to
+        // get "our own" instance to send requests to.
+        //
+        // At this point we hold a _strong_ reference
+        // to Arc<ServiceConnection> and it will not be gone by connection disconnecting.
+        // Whether it will work to send requests to is a whole other matter.
+        alive.get(idx).map(|s| s.clone_contained())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_net::tcp::{TcpListener, TcpStream};
+
+    fn domain(name: &str, secret: &str) -> DomainConfig {
+        DomainConfig {
+            domain: name.into(),
+            auth: ServiceAuth::PresharedKey(secret.into()),
+            wildcard: false,
+        }
+    }
+
+    fn preauthed(domain: &str, host: &str) -> Preauthed {
+        Preauthed::new(domain, host, "/", BalanceStrategy::default(), false, None)
+    }
+
+    #[test]
+    fn test_apply_keeps_hosts() {
+        let mut services = Services::new(&[domain("example.com", "old")]);
+        services
+            .add_preauthed(preauthed("example.com", "a.example.com"), Weak::new())
+            .unwrap();
+
+        services.apply(&[domain("example.com", "new"), domain("other.com", "x")]);
+
+        assert_eq!(services.domains.len(), 2);
+        assert_eq!(services.domains[0].hosts.len(), 1);
+        let p = preauthed("example.com", "a.example.com");
+        assert!(!services.is_valid_secret(&p, "old"));
+        assert!(services.is_valid_secret(&p, "new"));
+        assert!(services.is_valid_secret(&preauthed("other.com", "b.other.com"), "x"));
+    }
+
+    #[test]
+    fn test_apply_removes_domain() {
+        let mut services = Services::new(&[domain("example.com", "a"), domain("other.com", "b")]);
+        services
+            .add_preauthed(preauthed("example.com", "a.example.com"), Weak::new())
+            .unwrap();
+
+        services.apply(&[domain("Other.com", "b")]);
+
+        assert_eq!(services.domains.len(), 1);
+        assert_eq!(services.domains[0].domain(), "other.com");
+        let p = preauthed("example.com", "a.example.com");
+        assert!(!services.is_valid_secret(&p, "a"));
+        assert!(services.add_preauthed(p, Weak::new()).is_err());
+    }
+
+    #[test]
+    fn test_preauthed_case_insensitive() {
+        let mut services = Services::new(&[domain("example.com", "a")]);
+        let p = preauthed("Example.COM", "A.example.com");
+        assert!(services.is_valid_secret(&p, "a"));
+        assert!(services.is_valid_host(&p));
+        services.add_preauthed(p, Weak::new()).unwrap();
+        services
+            .add_preauthed(preauthed("example.com", "a.EXAMPLE.com"), Weak::new())
+            .unwrap();
+        // both connections go to the same host.
+        assert_eq!(services.domains[0].hosts.len(), 1);
+        assert_eq!(services.domains[0].hosts[0].host(), "a.example.com");
+        assert_eq!(services.domains[0].hosts[0].routes.len(), 1);
+        assert_eq!(services.hosts_needing_cert(0), vec!["a.example.com"]);
+
+        // the route is found, but has no live connections.
+        for uri in &["http://a.example.com/", "http://A.Example.com/"] {
+            let req = http::Request::get(*uri).body(()).unwrap();
+            let err = services.route(&req).err().unwrap();
+            assert_eq!(err.status(), Some(http::StatusCode::SERVICE_UNAVAILABLE));
+        }
+    }
+
+    #[test]
+    fn test_host_outside_domain() {
+        let mut services = Services::new(&[domain("example.com", "a")]);
+        assert!(services.is_valid_host(&preauthed("example.com", "example.com")));
+        assert!(services.is_valid_host(&preauthed("example.com", "a.example.com")));
+        for host in &["example.org", "aexample.com", "example.com.evil.org"] {
+            let p = preauthed("example.com", host);
+            assert!(!services.is_valid_host(&p));
+            assert!(services.add_preauthed(p, Weak::new()).is_err());
+        }
+        assert!(services.domains[0].hosts.is_empty());
+    }
+
+    #[test]
+    fn test_hosts_needing_cert() {
+        let mut services = Services::new(&[domain("example.com", "a")]);
+        for host in &["a.example.com", "b.example.com"] {
+            services
+                .add_preauthed(preauthed("example.com", host), Weak::new())
+                .unwrap();
+        }
+        assert_eq!(
+            services.hosts_needing_cert(0),
+            vec!["a.example.com", "b.example.com"]
+        );
+
+        // a certificate without expiry is never renewed.
+        assert!(services.set_cert("A.example.com", TlsCert::self_signed("a.example.com", None)));
+        assert_eq!(services.hosts_needing_cert(u64::MAX), vec!["b.example.com"]);
+
+        for host in &["a.example.com", "b.example.com"] {
+            assert!(services.set_cert(host, TlsCert::self_signed(host, Some(1000))));
+        }
+        assert!(services.hosts_needing_cert(999).is_empty());
+        assert_eq!(services.hosts_needing_cert(1000).len(), 2);
+        assert!(!services.set_cert("c.example.com", TlsCert::self_signed("c", None)));
+    }
+
+    #[test]
+    fn test_wildcard_needing_cert() {
+        let mut config = domain("example.com", "a");
+        config.wildcard = true;
+        let mut services = Services::new(&[config]);
+        assert_eq!(services.hosts_needing_cert(0), vec!["*.example.com"]);
+
+        for host in &["a.example.com", "b.a.example.com"] {
+            services
+                .add_preauthed(preauthed("example.com", host), Weak::new())
+                .unwrap();
+        }
+        // the wildcard doesn't cover hosts more than one label down.
+        assert_eq!(
+            services.hosts_needing_cert(0),
+            vec!["*.example.com", "b.a.example.com"]
+        );
+        assert!(services.is_authority("_acme-challenge.example.com"));
+        assert_eq!(services.domain_of("B.a.example.com"), Some("example.com"));
+        assert_eq!(services.domain_of("example.org"), None);
+        // no live connections.
+        assert!(!services.is_live_host("a.example.com"));
+        assert!(!services.is_authority("example.org"));
+    }
+
+    /// Service connections that are never sent any requests.
+    pub(crate) fn connections(rt: &mut Runtime, n: usize) -> Vec<Arc<ServiceConnection>> {
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut conns = vec![];
+            for _ in 0..n {
+                let tcp = TcpStream::connect(addr).await.unwrap();
+                let (send_req, _) = h2::client::handshake(tcp).await.unwrap();
+                conns.push(Arc::new(ServiceConnection::new(send_req)));
+            }
+            conns
+        })
+    }
+
+    #[test]
+    fn test_apply_closes_removed() {
+        let mut rt = Runtime::new().unwrap();
+        let conns = connections(&mut rt, 2);
+        let mut services = Services::new(&[domain("example.com", "a"), domain("other.com", "b")]);
+        services
+            .add_preauthed(
+                preauthed("example.com", "a.example.com"),
+                Arc::downgrade(&conns[0]),
+            )
+            .unwrap();
+        services
+            .add_preauthed(
+                preauthed("other.com", "a.other.com"),
+                Arc::downgrade(&conns[1]),
+            )
+            .unwrap();
+        let mut removed = conns[0].closed();
+        let mut kept = conns[1].closed();
+
+        services.apply(&[domain("other.com", "b")]);
+
+        assert!(removed.try_recv().is_ok());
+        assert!(kept.try_recv().is_err());
+        assert!(services.is_live_host("a.other.com"));
+    }
+
+    #[test]
+    fn test_live_host_under() {
+        let mut rt = Runtime::new().unwrap();
+        let conns = connections(&mut rt, 1);
+        let mut services = Services::new(&[domain("example.com", "a")]);
+        services
+            .add_preauthed(
+                preauthed("example.com", "a.b.example.com"),
+                Arc::downgrade(&conns[0]),
+            )
+            .unwrap();
+        services
+            .add_preauthed(preauthed("example.com", "c.d.example.com"), Weak::new())
+            .unwrap();
+
+        assert!(services.has_live_host_under("B.example.com"));
+        assert!(services.has_live_host_under("example.com"));
+        assert!(!services.has_live_host_under("a.b.example.com"));
+        // no live connections under it.
+        assert!(!services.has_live_host_under("d.example.com"));
+        assert!(!services.has_live_host_under("ab.example.com"));
+    }
+
+    fn services_with(conns: &[Arc<ServiceConnection>], balance: BalanceStrategy) -> Services {
+        let mut services = Services::new(&[domain("example.com", "a")]);
+        for conn in conns {
+            let p = Preauthed::new("example.com", "a.example.com", "/", balance, false, None);
+            services.add_preauthed(p, Arc::downgrade(conn)).unwrap();
+        }
+        services
+    }
+
+    fn routed_idx(services: &mut Services, conns: &[Arc<ServiceConnection>]) -> (usize, Routed) {
+        let req = http::Request::builder()
+            .uri("http://a.example.com/x")
+            .body(())
+            .unwrap();
+        let routed = services.route(&req).unwrap();
+        let idx = conns.iter().position(|c| c.id() == routed.conn.id());
+        (idx.unwrap(), routed)
+    }
+
+    #[test]
+    fn test_route_round_robin() {
+        let mut rt = Runtime::new().unwrap();
+        let conns = connections(&mut rt, 4);
+        let mut services = services_with(&conns, BalanceStrategy::RoundRobin);
+
+        let mut counts = vec![0; conns.len()];
+        for _ in 0..400 {
+            counts[routed_idx(&mut services, &conns).0] += 1;
+        }
+        assert_eq!(counts, vec![100, 100, 100, 100]);
+    }
+
+    #[test]
+    fn test_route_least_in_flight() {
+        let mut rt = Runtime::new().unwrap();
+        let conns = connections(&mut rt, 4);
+        let mut services = services_with(&conns, BalanceStrategy::LeastInFlight);
+
+        // requests that are still in flight steer new ones to the idle connection.
+        let busy: Vec<_> = [0, 0, 1, 2]
+            .iter()
+            .map(|i| conns[*i].start_request())
+            .collect();
+        for _ in 0..10 {
+            assert_eq!(routed_idx(&mut services, &conns).0, 3);
+        }
+        drop(busy);
+
+        // every request kept in flight spreads the next one to another connection.
+        let mut in_flight = vec![];
+        let mut picked = vec![];
+        for _ in 0..4 {
+            let (idx, routed) = routed_idx(&mut services, &conns);
+            in_flight.push(routed.conn.start_request());
+            picked.push(idx);
+        }
+        picked.sort();
+        assert_eq!(picked, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_route_dead_connection() {
+        let mut rt = Runtime::new().unwrap();
+        let mut conns = connections(&mut rt, 2);
+        let mut services = services_with(&conns, BalanceStrategy::RoundRobin);
+
+        conns.remove(0);
+        for _ in 0..10 {
+            assert_eq!(routed_idx(&mut services, &conns).0, 0);
+        }
+        conns.clear();
+        let req = http::Request::builder()
+            .uri("http://a.example.com/x")
+            .body(())
+            .unwrap();
+        let err = services.route(&req).err().unwrap();
+        assert_eq!(err.status(), Some(http::StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    #[test]
+    fn test_route_sticky() {
+        let mut rt = Runtime::new().unwrap();
+        let mut conns = connections(&mut rt, 3);
+        let mut services = Services::new(&[domain("example.com", "a")]);
+        for conn in &conns {
+            let p = Preauthed::new(
+                "example.com",
+                "a.example.com",
+                "/",
+                Default::default(),
+                true,
+                None,
+            );
+            services.add_preauthed(p, Arc::downgrade(conn)).unwrap();
+        }
+        let request = |cookie: Option<&str>| {
+            let mut req = http::Request::builder();
+            req.uri("https://a.example.com/x");
+            if let Some(cookie) = cookie {
+                req.header("cookie", format!("lolb_affinity={}", cookie));
+            }
+            req.body(()).unwrap()
+        };
+
+        // a new client is balanced and gets a cookie.
+        let routed = services.route(&request(None)).unwrap();
+        let set_cookie = routed.set_cookie.unwrap();
+        let set_cookie = set_cookie.to_str().unwrap();
+        assert!(set_cookie.ends_with("; Secure"), "{}", set_cookie);
+        let cookie = &set_cookie["lolb_affinity=".len()..set_cookie.find(';').unwrap()];
+        let pinned = routed.conn.id();
+
+        // the cookie pins the client, and isn't set again.
+        for _ in 0..10 {
+            let routed = services.route(&request(Some(cookie))).unwrap();
+            assert_eq!(routed.conn.id(), pinned);
+            assert!(routed.set_cookie.is_none());
+        }
+
+        // the pinned connection is gone, the client is balanced to another one.
+        conns.retain(|c| c.id() != pinned);
+        let routed = services.route(&request(Some(cookie))).unwrap();
+        assert_ne!(routed.conn.id(), pinned);
+        assert!(conns.iter().any(|c| c.id() == routed.conn.id()));
+        assert!(routed.set_cookie.is_some());
+
+        // a tampered cookie is ignored.
+        let tampered = format!("{:016x}{}", pinned ^ 1, &cookie[16..]);
+        let routed = services.route(&request(Some(&tampered))).unwrap();
+        assert!(routed.set_cookie.is_some());
+    }
+
+    #[test]
+    fn test_route_hash() {
+        let mut rt = Runtime::new().unwrap();
+        let mut conns = connections(&mut rt, 3);
+        let mut services = Services::new(&[domain("example.com", "a")]);
+        for conn in &conns {
+            let p = Preauthed::new(
+                "example.com",
+                "a.example.com",
+                "/",
+                Default::default(),
+                false,
+                Some(HashKey::Header("x-user".into())),
+            );
+            services.add_preauthed(p, Arc::downgrade(conn)).unwrap();
+        }
+        let request = |user: Option<&str>| {
+            let mut req = http::Request::builder();
+            req.uri("https://a.example.com/x");
+            if let Some(user) = user {
+                req.header("x-user", user);
+            }
+            req.body(()).unwrap()
+        };
+        let users: Vec<String> = (0..20).map(|i| format!("user{}", i)).collect();
+        let route_users = |services: &mut Services| -> Vec<u64> {
+            users
+                .iter()
+                .map(|u| services.route(&request(Some(u))).unwrap().conn.id())
+                .collect()
+        };
+
+        // the same key goes to the same connection.
+        let before = route_users(&mut services);
+        assert_eq!(route_users(&mut services), before);
+        let mut used = before.clone();
+        used.sort();
+        used.dedup();
+        assert!(used.len() > 1, "{:?}", before);
+
+        // the ring is rebuilt without the dead connection. keys of the other connections
+        // stay where they are.
+        let dead = before[0];
+        conns.retain(|c| c.id() != dead);
+        let after = route_users(&mut services);
+        let mut ids: Vec<u64> = conns.iter().map(|c| c.id()).collect();
+        assert!(services.domains[0].hosts[0].routes[0].ring.is_for(&ids));
+        ids.sort();
+        for (b, a) in before.iter().zip(after.iter()) {
+            assert!(ids.contains(a));
+            if *b != dead {
+                assert_eq!(a, b);
+            }
+        }
+
+        // without the key the balancer picks.
+        let mut picked: Vec<u64> = (0..4)
+            .map(|_| services.route(&request(None)).unwrap().conn.id())
+            .collect();
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked, ids);
+    }
+
+    #[test]
+    fn test_route_not_found() {
+        let mut rt = Runtime::new().unwrap();
+        let conns = connections(&mut rt, 1);
+        let mut services = Services::new(&[domain("example.com", "a")]);
+        let p = Preauthed::new(
+            "example.com",
+            "a.example.com",
+            "/api",
+            Default::default(),
+            false,
+            None,
+        );
+        services
+            .add_preauthed(p, Arc::downgrade(&conns[0]))
+            .unwrap();
+
+        let status = |services: &mut Services, uri: &str| {
+            let req = http::Request::builder().uri(uri).body(()).unwrap();
+            match services.route(&req) {
+                Ok(_) => 200,
+                Err(e) => e.status().unwrap().as_u16(),
+            }
+        };
+        assert_eq!(status(&mut services, "http://a.example.com/api/x"), 200);
+        // unknown domain, unknown host in a domain, no matching prefix, no host at all.
+        assert_eq!(status(&mut services, "http://a.example.org/api"), 404);
+        assert_eq!(status(&mut services, "http://b.example.com/api"), 404);
+        assert_eq!(status(&mut services, "http://a.example.com/other"), 404);
+        assert_eq!(status(&mut services, "/api"), 404);
+
+        // the route is known, but has no live connections.
+        drop(conns);
+        assert_eq!(status(&mut services, "http://a.example.com/api/x"), 503);
+    }
 }
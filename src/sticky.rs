@@ -0,0 +1,167 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+/// Name of the cookie used to pin a client to a service connection.
+pub(crate) const COOKIE_AFFINITY: &str = "lolb_affinity";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Secret used to sign affinity cookies. It is generated per load balancer
+/// instance, which means a restart invalidates all issued cookies and clients
+/// gracefully fall back to being balanced anew.
+pub(crate) struct StickyKey([u8; 32]);
+
+impl Default for StickyKey {
+    fn default() -> Self {
+        StickyKey(rand::random())
+    }
+}
+
+impl fmt::Debug for StickyKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // do not leak the secret in debug output.
+        write!(f, "StickyKey")
+    }
+}
+
+impl StickyKey {
+    fn mac(&self, id: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(&self.0).expect("HMAC takes any key length");
+        mac.input(&id.to_be_bytes());
+        mac
+    }
+
+    /// Sign a service connection id into a cookie value `<id>.<signature>`.
+    pub fn sign(&self, id: u64) -> String {
+        let sig = self.mac(id).result().code();
+        format!("{:016x}.{}", id, to_hex(&sig[..]))
+    }
+
+    /// Verify a cookie value and return the service connection id it pins to.
+    pub fn verify(&self, value: &str) -> Option<u64> {
+        let mut split = value.splitn(2, '.');
+        let id = u64::from_str_radix(split.next()?, 16).ok()?;
+        let sig = from_hex(split.next()?)?;
+        self.mac(id).verify(&sig[..]).ok()?;
+        Some(id)
+    }
+}
+
+/// Find the value of the affinity cookie in the request, if any.
+pub(crate) fn affinity_cookie<X>(req: &http::Request<X>) -> Option<&str> {
+    cookie_value(req, COOKIE_AFFINITY)
+}
+
+/// Find the value of a named cookie in the request.
+pub(crate) fn cookie_value<'a, X>(req: &'a http::Request<X>, name: &str) -> Option<&'a str> {
+    // http2 allows the cookie header to be split into several headers.
+    req.headers()
+        .get_all("cookie")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|pair| {
+            let mut split = pair.trim().splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(n), Some(value)) if n == name => Some(value),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Create the set-cookie header value for pinning to a service connection. A cookie
+/// issued over TLS is only ever sent back over TLS.
+pub(crate) fn set_affinity_cookie(
+    key: &StickyKey,
+    id: u64,
+    path: &str,
+    secure: bool,
+) -> http::HeaderValue {
+    let cookie = format!(
+        "{}={}; Path={}; HttpOnly; SameSite=Lax{}",
+        COOKIE_AFFINITY,
+        key.sign(id),
+        path,
+        if secure { "; Secure" } else { "" }
+    );
+    http::HeaderValue::from_str(&cookie).expect("Affinity cookie is a valid header value")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let pairs = s.as_bytes().chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    pairs
+        .map(|p| u8::from_str_radix(std::str::from_utf8(p).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let key = StickyKey::default();
+        for id in &[0, 1, 0xdead_beef, 0xffff_ffff_ffff_ffff] {
+            assert_eq!(key.verify(&key.sign(*id)), Some(*id));
+        }
+        // a restarted load balancer has another key.
+        assert_eq!(StickyKey::default().verify(&key.sign(42)), None);
+    }
+
+    #[test]
+    fn test_verify_tampered() {
+        let key = StickyKey::default();
+        let value = key.sign(42);
+        let (id, sig) = value.split_at(16);
+
+        // another id with the signature of 42.
+        assert_eq!(key.verify(&format!("{:016x}{}", 43, sig)), None);
+
+        // flipped last digit of the signature.
+        let mut tampered = value.clone();
+        let last = if value.ends_with('0') { "1" } else { "0" };
+        tampered.replace_range(value.len() - 1.., last);
+        assert_eq!(key.verify(&tampered), None);
+
+        // truncated, odd length, and not hex.
+        assert_eq!(key.verify(&value[..value.len() - 2]), None);
+        assert_eq!(key.verify(&value[..value.len() - 1]), None);
+        assert_eq!(key.verify(&format!("{}.{}", id, "zz".repeat(32))), None);
+        assert_eq!(key.verify(id), None);
+        assert_eq!(key.verify(""), None);
+    }
+
+    #[test]
+    fn test_set_affinity_cookie() {
+        let key = StickyKey::default();
+        let plain = set_affinity_cookie(&key, 42, "/api", false);
+        let plain = plain.to_str().unwrap();
+        assert!(plain.starts_with("lolb_affinity="), "{}", plain);
+        assert!(
+            plain.ends_with("; Path=/api; HttpOnly; SameSite=Lax"),
+            "{}",
+            plain
+        );
+
+        let secure = set_affinity_cookie(&key, 42, "/api", true);
+        assert!(secure.to_str().unwrap().ends_with("; SameSite=Lax; Secure"));
+
+        // the cookie is read back from a request.
+        let value = &plain["lolb_affinity=".len()..plain.find(';').unwrap()];
+        let req = http::Request::builder()
+            .header("cookie", "a=b")
+            .header("cookie", format!("c=d; lolb_affinity={}", value))
+            .body(())
+            .unwrap();
+        assert_eq!(affinity_cookie(&req).and_then(|v| key.verify(v)), Some(42));
+    }
+}
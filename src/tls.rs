@@ -0,0 +1,685 @@
+//! TLS termination of incoming connections.
+//!
+//! `TlsConnectionProvider` wraps another provider of plain connections and does the TLS
+//! handshake before handing the connection on to the load balancer. The certificate is
+//! picked by SNI from the hosts in the services tree, and the ALPN negotiated decides
+//! whether the connection is http2 or http11.
+use crate::conn::{Connection, ConnectionProvider, HttpVersion, Socket};
+use crate::peek::Peekable;
+use crate::persist::Persist;
+use crate::util::current_time_millis;
+use crate::{AsyncRead, AsyncWrite, LoadBalancer, LolbError, LolbResult};
+use futures_util::future::poll_fn;
+use futures_util::ready;
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{NoClientAuth, ResolvesServerCert, ServerConfig, ServerSession, Session};
+use rustls::{SignatureScheme, TLSError};
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// ALPN protocols offered, in order of preference.
+const ALPN_H2: &[u8] = b"h2";
+const ALPN_HTTP11: &[u8] = b"http/1.1";
+
+/// Certificate chain and private key for serving a host.
+#[derive(Clone)]
+pub(crate) struct TlsCert {
+    key: CertifiedKey,
+    /// Unix time millis when the certificate expires, if known.
+    expires: Option<u64>,
+}
+
+impl TlsCert {
+    /// Create from a PEM encoded certificate chain and private key. The key is
+    /// either PKCS8 or RSA.
+    pub fn from_pem(certificate: &str, private_key: &str) -> LolbResult<Self> {
+        let chain = pemfile::certs(&mut certificate.as_bytes())
+            .map_err(|_| LolbError::Message("Bad PEM certificate"))?;
+        if chain.is_empty() {
+            return Err(LolbError::Message("No certificate in PEM"));
+        }
+        let mut keys = pemfile::pkcs8_private_keys(&mut private_key.as_bytes())
+            .map_err(|_| LolbError::Message("Bad PEM private key"))?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut private_key.as_bytes())
+                .map_err(|_| LolbError::Message("Bad PEM private key"))?;
+        }
+        let key = keys
+            .first()
+            .ok_or(LolbError::Message("No private key in PEM"))?;
+        let key = sign::any_supported_type(key)
+            .map_err(|_| LolbError::Message("Unsupported private key type"))?;
+        Ok(TlsCert {
+            key: CertifiedKey::new(chain, Arc::new(key)),
+            expires: None,
+        })
+    }
+
+    /// Create from a certificate issued by ACME.
+    pub fn from_acme(cert: &acme_lib::Certificate) -> LolbResult<Self> {
+        let mut tls = TlsCert::from_pem(cert.certificate(), cert.private_key())?;
+        let days = cert.valid_days_left().max(0) as u64;
+        tls.expires = Some(current_time_millis() + days * 24 * 60 * 60 * 1000);
+        Ok(tls)
+    }
+
+    /// Unix time millis when the certificate expires, if known.
+    pub fn expires(&self) -> Option<u64> {
+        self.expires
+    }
+}
+
+#[cfg(test)]
+impl TlsCert {
+    /// A self signed certificate for the host.
+    pub(crate) fn self_signed(host: &str, expires: Option<u64>) -> Self {
+        let gen = rcgen::generate_simple_self_signed(vec![host.into()]).unwrap();
+        let pem = gen.serialize_pem().unwrap();
+        let mut cert = TlsCert::from_pem(&pem, &gen.serialize_private_key_pem()).unwrap();
+        cert.expires = expires;
+        cert
+    }
+}
+
+impl fmt::Debug for TlsCert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // do not leak the private key in debug output.
+        write!(f, "TlsCert")
+    }
+}
+
+/// Lookup of certificates by the host name sent as SNI.
+pub trait CertLookup: Send + Sync {
+    fn cert_for(&self, host: &str) -> Option<CertifiedKey>;
+}
+
+impl<P: Persist> CertLookup for Mutex<LoadBalancer<P>> {
+    fn cert_for(&self, host: &str) -> Option<CertifiedKey> {
+        let lock = self.lock().unwrap();
+        lock.services.cert_for(host).map(|c| c.key.clone())
+    }
+}
+
+/// Resolves the certificate of a TLS handshake using the SNI.
+struct CertResolver<L>(Arc<L>);
+
+impl<L: CertLookup> ResolvesServerCert for CertResolver<L> {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef<'_>>,
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        // without SNI we have no idea which host the client wants.
+        let host: &str = server_name?.into();
+        let cert = self.0.cert_for(host);
+        if cert.is_none() {
+            debug!("No TLS certificate for: {}", host);
+        }
+        cert
+    }
+}
+
+/// The future of accepting one TLS connection.
+pub type TlsAccept<S> =
+    Pin<Box<dyn Future<Output = LolbResult<Connection<TlsSocket<Peekable<S>>>>> + Send + 'static>>;
+
+/// Provider of TLS terminated connections on top of another provider.
+pub struct TlsConnectionProvider<S, R, F> {
+    inner: R,
+    config: Arc<ServerConfig>,
+    _ph: PhantomData<(S, F)>,
+}
+
+impl<S, R, F> TlsConnectionProvider<S, R, F>
+where
+    S: Socket,
+    R: ConnectionProvider<S, F>,
+    F: Future<Output = LolbResult<Connection<S>>>,
+{
+    /// Create a provider doing the TLS handshake on connections from `inner`. Certificates
+    /// are looked up using `certs`, typically the `Arc<Mutex<LoadBalancer>>`.
+    pub fn new<L: CertLookup + 'static>(certs: Arc<L>, inner: R) -> Self {
+        TlsConnectionProvider {
+            inner,
+            config: server_config(certs),
+            _ph: PhantomData,
+        }
+    }
+}
+
+fn server_config<L: CertLookup + 'static>(certs: Arc<L>) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = Arc::new(CertResolver(certs));
+    config.set_protocols(&[ALPN_H2.to_vec(), ALPN_HTTP11.to_vec()]);
+    Arc::new(config)
+}
+
+impl<S, R, F> ConnectionProvider<TlsSocket<Peekable<S>>, TlsAccept<S>>
+    for TlsConnectionProvider<S, R, F>
+where
+    S: Socket,
+    S: Send + 'static,
+    R: ConnectionProvider<S, F>,
+    F: Future<Output = LolbResult<Connection<S>>>,
+    F: Send + 'static,
+{
+    fn accept(&mut self) -> TlsAccept<S> {
+        let accept = self.inner.accept();
+        let config = self.config.clone();
+        Box::pin(async move {
+            let conn = accept.await?;
+            let peer_addr = conn.peer_addr();
+            let tls = handshake(&config, conn.into_socket()).await?;
+            let http_version = tls.http_version();
+            Ok(Connection::new(tls, peer_addr, http_version, true))
+        })
+    }
+}
+
+/// Do the server side TLS handshake over the socket.
+pub(crate) async fn handshake<T>(config: &Arc<ServerConfig>, io: T) -> io::Result<TlsSocket<T>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut tls = TlsSocket {
+        io,
+        session: ServerSession::new(config),
+        eof: false,
+        closed: false,
+    };
+    poll_fn(|cx| tls.poll_handshake(cx)).await?;
+    Ok(tls)
+}
+
+/// A socket with TLS terminated.
+pub struct TlsSocket<T> {
+    io: T,
+    session: ServerSession,
+    /// Whether the underlying socket reached end of file.
+    eof: bool,
+    /// Whether we sent close notify.
+    closed: bool,
+}
+
+impl<T> fmt::Debug for TlsSocket<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsSocket")
+            .field("sni", &self.session.get_sni_hostname())
+            .field("eof", &self.eof)
+            .finish()
+    }
+}
+
+impl<T> TlsSocket<T> {
+    /// The host name the client sent as SNI.
+    pub fn sni_hostname(&self) -> Option<&str> {
+        self.session.get_sni_hostname()
+    }
+
+    /// The http version as negotiated by ALPN. Unknown when the client didn't use ALPN.
+    pub fn http_version(&self) -> HttpVersion {
+        match self.session.get_alpn_protocol() {
+            Some(ALPN_H2) => HttpVersion::Http2,
+            Some(ALPN_HTTP11) => HttpVersion::Http11,
+            _ => HttpVersion::Unknown,
+        }
+    }
+}
+
+/// Adapter from the async underlying socket to the sync Read/Write that rustls wants.
+/// A pending poll is translated to `WouldBlock`.
+struct SyncIo<'a, 'b, T> {
+    io: &'a mut T,
+    cx: &'a mut Context<'b>,
+}
+
+impl<'a, 'b, T: AsyncRead + Unpin> Read for SyncIo<'a, 'b, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_read(self.cx, buf) {
+            Poll::Ready(r) => r,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<'a, 'b, T: AsyncWrite + Unpin> Write for SyncIo<'a, 'b, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(r) => r,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(r) => r,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+fn would_block<X>(r: io::Result<X>) -> Poll<io::Result<X>> {
+    match r {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+        r => Poll::Ready(r),
+    }
+}
+
+fn tls_error(e: TLSError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> TlsSocket<T> {
+    fn poll_read_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut io = SyncIo {
+            io: &mut self.io,
+            cx,
+        };
+        would_block(self.session.read_tls(&mut io))
+    }
+
+    fn poll_write_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut io = SyncIo {
+            io: &mut self.io,
+            cx,
+        };
+        would_block(self.session.write_tls(&mut io))
+    }
+
+    /// Process read TLS records. On failure we make an attempt to send the alert
+    /// before giving up.
+    fn process_packets(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if let Err(e) = self.session.process_new_packets() {
+            let _ = self.poll_write_tls(cx);
+            return Err(tls_error(e));
+        }
+        Ok(())
+    }
+
+    /// Write all buffered TLS records and flush the underlying socket.
+    fn poll_flush_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.session.wants_write() {
+            ready!(self.poll_write_tls(cx))?;
+        }
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.poll_flush_tls(cx))?;
+            if !self.session.is_handshaking() {
+                return Poll::Ready(Ok(()));
+            }
+            if ready!(self.poll_read_tls(cx))? == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.process_packets(cx)?;
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsSocket<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let mut pending = false;
+        while !this.eof && this.session.wants_read() {
+            match this.poll_read_tls(cx) {
+                Poll::Ready(Ok(0)) => this.eof = true,
+                Poll::Ready(Ok(_)) => this.process_packets(cx)?,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {
+                    pending = true;
+                    break;
+                }
+            }
+        }
+
+        // reading records may produce things to write, such as session tickets.
+        if this.session.wants_write() {
+            if let Poll::Ready(Err(e)) = this.poll_flush_tls(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+
+        match this.session.read(buf) {
+            // no plaintext yet, and we're waiting for more records.
+            Ok(0) if pending && !buf.is_empty() => Poll::Pending,
+            r => Poll::Ready(r),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsSocket<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            // the session buffers plaintext up to a limit.
+            let n = this.session.write(buf)?;
+
+            let mut written = false;
+            while this.session.wants_write() {
+                match this.poll_write_tls(cx) {
+                    Poll::Ready(Ok(_)) => written = true,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    // whatever was buffered is written on the next poll.
+                    Poll::Pending if n > 0 => break,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            if n > 0 {
+                return Poll::Ready(Ok(n));
+            }
+
+            // the buffer was full. try again now that there is room.
+            if this.session.is_handshaking() {
+                // plaintext is held back until the handshake is done.
+                ready!(this.poll_handshake(cx))?;
+            } else if !written {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.session.flush()?;
+        this.poll_flush_tls(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closed {
+            this.session.send_close_notify();
+            this.closed = true;
+        }
+        ready!(this.poll_flush_tls(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+impl<T: Read + Write> Read for TlsSocket<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        rustls::Stream::new(&mut self.session, &mut self.io).read(buf)
+    }
+}
+
+impl<T: Read + Write> Write for TlsSocket<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        rustls::Stream::new(&mut self.session, &mut self.io).write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        rustls::Stream::new(&mut self.session, &mut self.io).flush()
+    }
+}
+
+impl<T> Socket for TlsSocket<T> where T: Read + Write + AsyncRead + AsyncWrite + Unpin {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serv_auth::Preauthed;
+    use crate::service::{ServiceAuth, Services};
+    use crate::{AsyncReadExt, AsyncWriteExt, BalanceStrategy, DomainConfig};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Weak;
+    use std::thread;
+
+    /// Blocking std socket pretending to be async, which is enough to drive the
+    /// handshake with block_on.
+    struct Blocking(TcpStream);
+
+    impl AsyncRead for Blocking {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(self.get_mut().0.read(buf))
+        }
+    }
+
+    impl AsyncWrite for Blocking {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(self.get_mut().0.write(buf))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(self.get_mut().0.flush())
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    struct Certs(Mutex<Services>);
+
+    impl CertLookup for Certs {
+        fn cert_for(&self, host: &str) -> Option<CertifiedKey> {
+            let lock = self.0.lock().unwrap();
+            lock.cert_for(host).map(|c| c.key.clone())
+        }
+    }
+
+    /// Services with the host `a.example.com` that has a generated certificate.
+    fn certs() -> (Arc<Certs>, Vec<u8>) {
+        let mut services = Services::new(&[DomainConfig {
+            domain: "example.com".into(),
+            auth: ServiceAuth::PresharedKey("secret".into()),
+            wildcard: false,
+        }]);
+        let p = Preauthed::new(
+            "example.com",
+            "a.example.com",
+            "/",
+            BalanceStrategy::default(),
+            false,
+            None,
+        );
+        services.add_preauthed(p, Weak::new()).unwrap();
+
+        let gen = rcgen::generate_simple_self_signed(vec!["a.example.com".into()]).unwrap();
+        let cert = TlsCert::from_pem(
+            &gen.serialize_pem().unwrap(),
+            &gen.serialize_private_key_pem(),
+        )
+        .unwrap();
+        assert!(services.set_cert("A.example.com", cert));
+
+        (
+            Arc::new(Certs(Mutex::new(services))),
+            gen.serialize_der().unwrap(),
+        )
+    }
+
+    fn client(
+        root: &[u8],
+        host: &str,
+        alpn: &[&[u8]],
+        tcp: TcpStream,
+    ) -> rustls::StreamOwned<rustls::ClientSession, TcpStream> {
+        let mut config = rustls::ClientConfig::new();
+        config
+            .root_store
+            .add(&rustls::Certificate(root.to_vec()))
+            .unwrap();
+        config.set_protocols(&alpn.iter().map(|a| a.to_vec()).collect::<Vec<_>>());
+        let name = webpki::DNSNameRef::try_from_ascii_str(host).unwrap();
+        let session = rustls::ClientSession::new(&Arc::new(config), name);
+        rustls::StreamOwned::new(session, tcp)
+    }
+
+    /// Accept one connection, echo what is read and tell what was negotiated.
+    fn serve(
+        certs: Arc<Certs>,
+        listener: TcpListener,
+    ) -> thread::JoinHandle<io::Result<(Option<String>, HttpVersion)>> {
+        let config = server_config(certs);
+        thread::spawn(move || {
+            let (tcp, _) = listener.accept()?;
+            futures_executor::block_on(async move {
+                let mut tls = handshake(&config, Blocking(tcp)).await?;
+                let mut buf = [0; 5];
+                tls.read_exact(&mut buf).await?;
+                tls.write_all(&buf).await?;
+                tls.flush().await?;
+                Ok((
+                    tls.sni_hostname().map(|s| s.to_string()),
+                    tls.http_version(),
+                ))
+            })
+        })
+    }
+
+    #[test]
+    fn test_sni_and_alpn() {
+        for (alpn, version) in &[
+            (ALPN_H2, HttpVersion::Http2),
+            (ALPN_HTTP11, HttpVersion::Http11),
+        ] {
+            let (certs, root) = certs();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = serve(certs, listener);
+
+            let tcp = TcpStream::connect(addr).unwrap();
+            let mut tls = client(&root, "a.example.com", &[*alpn], tcp);
+            tls.write_all(b"hello").unwrap();
+            let mut buf = [0; 5];
+            tls.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"hello");
+
+            let (sni, negotiated) = server.join().unwrap().unwrap();
+            assert_eq!(sni.as_deref(), Some("a.example.com"));
+            assert_eq!(negotiated, *version);
+        }
+    }
+
+    #[test]
+    fn test_no_alpn() {
+        let (certs, root) = certs();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve(certs, listener);
+
+        let tcp = TcpStream::connect(addr).unwrap();
+        let mut tls = client(&root, "a.example.com", &[], tcp);
+        tls.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        tls.read_exact(&mut buf).unwrap();
+
+        let (_, negotiated) = server.join().unwrap().unwrap();
+        assert_eq!(negotiated, HttpVersion::Unknown);
+    }
+
+    #[test]
+    fn test_unknown_sni() {
+        let (certs, root) = certs();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve(certs, listener);
+
+        let tcp = TcpStream::connect(addr).unwrap();
+        let mut tls = client(&root, "b.example.com", &[ALPN_H2], tcp);
+        assert!(tls.write_all(b"hello").and_then(|_| tls.flush()).is_err());
+
+        assert!(server.join().unwrap().is_err());
+    }
+
+    /// Socket where every other write isn't ready, like a socket with a full send buffer.
+    struct Congested {
+        tcp: TcpStream,
+        ready: bool,
+    }
+
+    impl AsyncRead for Congested {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(self.get_mut().tcp.read(buf))
+        }
+    }
+
+    impl AsyncWrite for Congested {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            this.ready = !this.ready;
+            if !this.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(this.tcp.write(buf))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(self.get_mut().tcp.flush())
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_write_full_buffer() {
+        let (certs, root) = certs();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = server_config(certs);
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let expected = data.clone();
+
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept()?;
+            futures_executor::block_on(async move {
+                let socket = Congested { tcp, ready: false };
+                let mut tls = handshake(&config, socket).await?;
+                // the session is full of TLS records after every write.
+                tls.session.set_buffer_limit(64);
+                tls.write_all(&data).await?;
+                tls.flush().await
+            })
+        });
+
+        let tcp = TcpStream::connect(addr).unwrap();
+        let mut tls = client(&root, "a.example.com", &[ALPN_H2], tcp);
+        let mut buf = vec![0; expected.len()];
+        tls.read_exact(&mut buf).unwrap();
+        assert!(buf == expected);
+
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_from_pem_bad() {
+        assert!(TlsCert::from_pem("nope", "nope").is_err());
+        let gen = rcgen::generate_simple_self_signed(vec!["a.example.com".into()]).unwrap();
+        assert!(TlsCert::from_pem(&gen.serialize_pem().unwrap(), "nope").is_err());
+    }
+}